@@ -1,3 +1,6 @@
-fn main() {
-    println!("Hello, world!");
+use dnscat::cli::server::App;
+
+#[tokio::main]
+async fn main() {
+    App::new().run().await;
 }
@@ -0,0 +1,159 @@
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use dnscat::client::ClientBuilder;
+use dnscat::encryption::StandardEncryption;
+use dnscat::session::SessionState;
+use dnscat::transport::dns::{BasicDnsEndpoint, DnsClient, Name, RecordType};
+use futures::{AsyncReadExt, AsyncWriteExt};
+
+const SECRET: &str = "dragons";
+
+/// How long to wait for the server to answer after spawning it.
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct ServerProcess(Child);
+
+impl ServerProcess {
+    async fn spawn(addr: SocketAddr, args: &[&str]) -> Self {
+        let child = Command::new(env!("CARGO_BIN_EXE_dnscat-server"))
+            .arg("example.com.")
+            .arg("--listen")
+            .arg(addr.to_string())
+            .args(args)
+            .args(["--exec", "cat"])
+            .stdin(Stdio::null())
+            .spawn()
+            .expect("failed to spawn server");
+        let server = Self(child);
+        wait_ready(addr).await;
+        server
+    }
+}
+
+/// Pings the server until it answers, as it may take a while to bind.
+async fn wait_ready(addr: SocketAddr) {
+    let deadline = Instant::now() + READY_TIMEOUT;
+    loop {
+        let dns_client = DnsClient::connect(addr, dns_endpoint(RecordType::TXT))
+            .await
+            .unwrap();
+        let pong = ClientBuilder::default()
+            .ping_timeout(Duration::from_millis(200))
+            .ping(dns_client, "ready")
+            .await;
+        match pong {
+            Ok(_) => return,
+            Err(err) if Instant::now() >= deadline => panic!("server not ready: {}", err),
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+    }
+}
+
+fn free_addr() -> SocketAddr {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket.local_addr().unwrap()
+}
+
+fn dns_endpoint(query_type: RecordType) -> BasicDnsEndpoint {
+    let constant = Name::from_ascii("example.com.").unwrap();
+    BasicDnsEndpoint::new_with_defaults(vec![query_type], constant).unwrap()
+}
+
+#[tokio::test]
+async fn test_insecure_echo() {
+    let addr = free_addr();
+    let _server = ServerProcess::spawn(addr, &["--insecure"]).await;
+    for query_type in &[
+        RecordType::TXT,
+        RecordType::MX,
+        RecordType::CNAME,
+        RecordType::A,
+        RecordType::AAAA,
+    ] {
+        let dns_client = DnsClient::connect(addr, dns_endpoint(*query_type))
+            .await
+            .unwrap();
+        let mut client = ClientBuilder::default()
+            .max_delay(Duration::from_millis(100))
+            .connect_insecure(dns_client)
+            .await
+            .unwrap();
+        let data = format!("hello {}", query_type);
+        client.write_all(data.as_bytes()).await.unwrap();
+        let mut buf = vec![0; data.len()];
+        client.read_exact(&mut buf[..]).await.unwrap();
+        assert_eq!(buf, data.as_bytes());
+        client.close().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_encrypted_echo() {
+    let addr = free_addr();
+    let _server = ServerProcess::spawn(addr, &["--secret", SECRET]).await;
+    let dns_client = DnsClient::connect(addr, dns_endpoint(RecordType::TXT))
+        .await
+        .unwrap();
+    let encryption = StandardEncryption::new_with_ephemeral(true, Some(SECRET.into())).unwrap();
+    let mut client = ClientBuilder::default()
+        .max_delay(Duration::from_millis(100))
+        .connect(dns_client, encryption)
+        .await
+        .unwrap();
+    let data = b"hello encrypted world";
+    client.write_all(&data[..]).await.unwrap();
+    let mut buf = vec![0; data.len()];
+    client.read_exact(&mut buf[..]).await.unwrap();
+    assert_eq!(&buf[..], &data[..]);
+}
+
+#[tokio::test]
+async fn test_resume_encrypted_echo() {
+    let addr = free_addr();
+    let _server = ServerProcess::spawn(addr, &["--secret", SECRET]).await;
+    let dns_client = DnsClient::connect(addr, dns_endpoint(RecordType::TXT))
+        .await
+        .unwrap();
+    let encryption = StandardEncryption::new_with_ephemeral(true, Some(SECRET.into())).unwrap();
+    let mut client = ClientBuilder::default()
+        .max_delay(Duration::from_millis(100))
+        .connect(dns_client, encryption)
+        .await
+        .unwrap();
+    let data = b"hello before";
+    client.write_all(&data[..]).await.unwrap();
+    let mut buf = vec![0; data.len()];
+    client.read_exact(&mut buf[..]).await.unwrap();
+    assert_eq!(&buf[..], &data[..]);
+
+    // Drop the client without closing, as if the process restarted.
+    let state = client.save_state().unwrap().to_bytes();
+    drop(client);
+
+    let mut state = SessionState::from_bytes(state.unsecure()).unwrap();
+    let encryption = state
+        .restore_encryption::<StandardEncryption>()
+        .unwrap()
+        .unwrap();
+    let dns_client = DnsClient::connect(addr, dns_endpoint(RecordType::TXT))
+        .await
+        .unwrap();
+    let mut client = ClientBuilder::default()
+        .max_delay(Duration::from_millis(100))
+        .resume(dns_client, state, encryption)
+        .await
+        .unwrap();
+    let data = b"hello after";
+    client.write_all(&data[..]).await.unwrap();
+    let mut buf = vec![0; data.len()];
+    client.read_exact(&mut buf[..]).await.unwrap();
+    assert_eq!(&buf[..], &data[..]);
+}
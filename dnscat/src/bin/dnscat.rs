@@ -1,4 +1,4 @@
-use dnscat::cli::client;
+use dnscat::cli::{client, server};
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
@@ -12,6 +12,8 @@ pub(crate) struct Opts {
 enum SubCommand {
     /// DNSCAT client
     Client(client::App),
+    /// DNSCAT server
+    Server(server::App),
 }
 
 #[tokio::main]
@@ -20,5 +22,6 @@ async fn main() {
 
     match opts.app {
         SubCommand::Client(ref app) => app.run().await,
+        SubCommand::Server(ref app) => app.run().await,
     }
 }
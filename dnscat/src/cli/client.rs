@@ -1,27 +1,87 @@
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
 use std::process::Stdio;
+use std::str::FromStr;
+use std::task::{Context, Poll};
 use std::time::Duration;
 
 use futures::{future, pin_mut};
+use itertools::Itertools;
 use log::{error, info, warn};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
 use structopt::StructOpt;
-use tokio::{io, process};
+use tokio::runtime::Handle;
+use tokio::{fs, io, process};
+use trust_dns_client::client::AsyncClient;
 
-use crate::client::{Client, ClientBuilder};
+use crate::client::{Client, ClientBuilder, CommandDriver};
 use crate::encryption::{Encryption, StandardEncryption};
 use crate::packet::LazyPacket;
-use crate::transport::dns::{self, BasicDnsEndpoint, DnsClient, Name, RecordType};
-use crate::transport::Transport;
+use crate::transport::dns::{
+    self, BasicDnsEndpoint, DnsClient, DnsTlsConfig, DnsTransportError, HttpsHandle, HttpsMethod,
+    Name, RecordType, TxtEncoding,
+};
+use crate::transport::{Decode, MuxTransport, ResolverPool, Transport, TransportMux};
 
-#[derive(StructOpt, Debug)]
+use super::Encoding;
+
+/// The default port of DNS-over-TLS servers.
+const DNS_TLS_PORT: u16 = 853;
+
+/// The length of the random data sent in a ping.
+const PING_DATA_LEN: usize = 16;
+
+#[derive(StructOpt, Debug, Clone)]
 #[structopt(version = "0.1", author = "avitex <avitex@wfxlabs.com>")]
 pub struct App {
     /// DNS name constant.
     constant: Name,
 
-    /// Set the DNS server address, which by default is auto-detected.
+    /// Set the DNS server addresses (comma-delimited), which by default
+    /// are auto-detected.
+    ///
+    /// With several servers, the healthiest is used, switching to
+    /// another if it fails.
+    #[structopt(long, multiple = true, use_delimiter = true)]
+    server: Vec<SocketAddr>,
+
+    /// Set the number of DNS servers each query is sent to at once,
+    /// taking the first answer.
+    #[structopt(long, default_value = "1")]
+    hedge: NonZeroUsize,
+
+    /// Set the protocol used to reach the DNS server.
+    ///
+    /// Truncated responses over UDP are retried over TCP.
+    #[structopt(
+        long,
+        default_value = "udp",
+        possible_values = &["udp", "tcp", "tls", "https"]
+    )]
+    protocol: Protocol,
+
+    /// Set the DNS-over-HTTPS server URL.
+    #[structopt(long, required_if("protocol", "https"))]
+    url: Option<reqwest::Url>,
+
+    /// If set, send DNS-over-HTTPS requests with GET rather than POST.
+    #[structopt(long)]
+    https_get: bool,
+
+    /// Set the name the DNS-over-TLS server certificate is verified
+    /// against, which by default is its IP address.
+    #[structopt(long)]
+    tls_name: Option<String>,
+
+    /// Only trust server certificates issued by the CAs in a PEM file.
+    #[structopt(long, conflicts_with = "tls-pin")]
+    tls_ca: Option<PathBuf>,
+
+    /// Only trust the server certificate in a PEM file.
     #[structopt(long)]
-    server: Option<SocketAddr>,
+    tls_pin: Option<PathBuf>,
 
     /// Set the query types for DNS requests (comma-delimited).
     #[structopt(
@@ -29,10 +89,36 @@ pub struct App {
         multiple = true,
         use_delimiter = true,
         default_value = "TXT,MX,A",
-        possible_values = &["TXT", "MX", "CNAME", "A", "AAAA"]
+        possible_values = &["TXT", "NULL", "MX", "CNAME", "SRV", "PTR", "A", "AAAA"]
     )]
     query: Vec<RecordType>,
 
+    /// Set the encoding of data in DNS names, which must match the server.
+    ///
+    /// Encodings other than hex fit more data in each query.
+    #[structopt(
+        long,
+        default_value = "hex",
+        possible_values = &["hex", "base32", "base36"]
+    )]
+    encoding: Encoding,
+
+    /// Set the encoding requested for data in TXT answers.
+    ///
+    /// Encodings other than hex fit more data in each answer, but are
+    /// not supported by upstream dnscat2 servers.
+    #[structopt(
+        long,
+        default_value = "hex",
+        possible_values = &["hex", "base64", "raw"]
+    )]
+    txt_encoding: TxtEncoding,
+
+    /// If set, receive CNAME and MX responses split across several answers,
+    /// which the server must also be set to send.
+    #[structopt(long)]
+    multi_name: bool,
+
     /// Set the minimum delay in milliseconds between packets.
     ///
     /// This can be set to avoid flooding a network or server with
@@ -68,6 +154,27 @@ pub struct App {
     #[structopt(long, conflicts_with = "retransmit_forever")]
     retransmit_backoff: bool,
 
+    /// Set the max number of packets in flight.
+    ///
+    /// Sizes greater than one are only used if the server supports it.
+    #[structopt(long, default_value = "1")]
+    window: usize,
+
+    /// If set, probe the largest DNS request that survives the path to
+    /// the server with a response of the same size before connecting.
+    #[structopt(long)]
+    probe: bool,
+
+    /// The max datagram size probed by the main session, reused by
+    /// sub-sessions over the same transport.
+    #[structopt(skip)]
+    max_datagram_size: Option<usize>,
+
+    /// If set, only ping the server to check it is reachable, reporting
+    /// the data echoed and the round trip time.
+    #[structopt(long, conflicts_with_all = &["command", "exec"])]
+    ping: bool,
+
     /// Set the shared secret used for encryption.
     #[structopt(long)]
     secret: Option<String>,
@@ -96,8 +203,9 @@ pub struct App {
     #[structopt(long)]
     packet_trace: bool,
 
-    /// If set, indicate to the server this is a command session.
-    #[structopt(long)]
+    /// If set, start a command session, answering shell, exec, download
+    /// and upload requests from the server.
+    #[structopt(long, conflicts_with = "exec")]
     command: bool,
 
     /// Execute a process and attach stdin/stdout.
@@ -105,6 +213,28 @@ pub struct App {
     exec: Vec<String>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "udp" => Ok(Self::Udp),
+            "tcp" => Ok(Self::Tcp),
+            "tls" => Ok(Self::Tls),
+            "https" => Ok(Self::Https),
+            other => Err(format!("unknown protocol `{}`", other)),
+        }
+    }
+}
+
 impl App {
     pub fn new() -> Self {
         Self::from_args()
@@ -114,42 +244,179 @@ impl App {
         dotenv::dotenv().ok();
         env_logger::init();
 
-        // Build DNS client
-        let dns_server_addr = if let Some(server) = self.server {
-            server
+        // Build the DNS client
+        let (dns_client, dns_server) = self.dns_client().await.unwrap();
+
+        if self.ping {
+            info!("pinging `{}` using `{}`", dns_server, self.constant);
+            if !self.ping(dns_client).await {
+                std::process::exit(1);
+            }
+            return;
+        }
+
+        // Share the DNS client between the session and any sub-sessions
+        let mux = TransportMux::new(dns_client);
+
+        // Start building the client connection
+        let conn = self
+            .client_builder()
+            .command(self.command)
+            .probe(self.probe);
+
+        info!("connecting to `{}` using `{}`", dns_server, self.constant);
+
+        let result = if self.insecure {
+            match conn.connect_insecure(mux.handle()).await {
+                Ok(client) => {
+                    start_session(client, self, mux).await;
+                    Ok(())
+                }
+                Err(err) => Err(err),
+            }
         } else {
-            let server = match dns::get_system_dns_server() {
-                Ok(Some(server_addr)) => server_addr,
-                Ok(None) => panic!("no valid system DNS servers"),
-                Err(err) => panic!("failed to load system DNS config: {}", err),
-            };
-            if !self.constant.is_fqdn() {
-                // Unless you've changed system configuration to point to a
-                // DNSCAT2 server, this will most certainly not work.
-                warn!("non-FQDN is being used with a system DNS server");
+            if self.secret.is_none() {
+                warn!("no preshared secret! (use `--secret <secret>`)");
+            }
+            match conn.connect(mux.handle(), self.encryption()).await {
+                Ok(client) => {
+                    start_session(client, self, mux).await;
+                    Ok(())
+                }
+                Err(err) => Err(err),
             }
-            server
         };
+        if let Err(err) = result {
+            error!("failed to connect with {}", err)
+        }
+    }
 
-        // Build the DNS endpoint
-        let dns_endpoint =
-            BasicDnsEndpoint::new_with_defaults(self.query.clone(), self.constant.clone()).unwrap();
+    /// Pings the server with random data, without opening a session,
+    /// returning `true` if the data was echoed.
+    ///
+    /// An echo is printed rather than logged, as it is the output asked for
+    /// and should be seen without enabling logs.
+    async fn ping(&self, transport: DnsTransport) -> bool {
+        let data = thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(PING_DATA_LEN)
+            .map(char::from)
+            .collect::<String>();
+        match self.client_builder().ping(transport, data.clone()).await {
+            Ok(pong) if pong.data == data => {
+                println!("ping echoed `{}` in {} ms", pong.data, pong.rtt.as_millis());
+                true
+            }
+            Ok(pong) => {
+                error!("ping echoed `{}`, but `{}` was sent", pong.data, data);
+                false
+            }
+            Err(err) => {
+                error!("failed to ping with {}", err);
+                false
+            }
+        }
+    }
 
-        // Build the DNS client
-        let dns_client = DnsClient::connect(dns_server_addr, dns_endpoint)
-            .await
-            .unwrap();
+    /// Builds the DNS client, returning it with a description of the server.
+    async fn dns_client(&self) -> io::Result<(DnsTransport, String)> {
+        if self.protocol == Protocol::Https {
+            let url = self.url.clone().expect("url required for https");
+            let method = if self.https_get {
+                HttpsMethod::Get
+            } else {
+                HttpsMethod::Post
+            };
+            let http = reqwest::Client::builder()
+                .use_preconfigured_tls(self.tls_config().await?.client_config())
+                .build()
+                .map_err(io::Error::other)?;
+            let handle = HttpsHandle::with_client(http, url.clone(), method);
+            let dns_client = DnsClient::new(handle, self.dns_endpoint(), Handle::current())
+                .multi_name(self.multi_name);
+            return Ok((DnsTransport::Https(Box::new(dns_client)), url.to_string()));
+        }
+        let tls_config = self.tls_config().await?;
+        let mut pool = ResolverPool::new().fan_out(self.hedge.get());
+        let mut last_err = None;
+        for addr in self.dns_server_addrs() {
+            let endpoint = self.dns_endpoint();
+            let result = match self.protocol {
+                Protocol::Udp => DnsClient::connect(addr, endpoint).await,
+                Protocol::Tcp => DnsClient::connect_tcp(addr, endpoint).await,
+                Protocol::Tls => DnsClient::connect_tls(addr, &tls_config, endpoint).await,
+                Protocol::Https => unreachable!(),
+            };
+            match result {
+                Ok(dns_client) => {
+                    let dns_client = dns_client.multi_name(self.multi_name).in_order(true);
+                    pool.add(addr.to_string(), dns_client)
+                }
+                Err(err) => {
+                    warn!("failed to connect to `{}`: {}", addr, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        if let (true, Some(err)) = (pool.is_empty(), last_err) {
+            return Err(err.into());
+        }
+        let servers = pool.stats().map(|stats| stats.name).join(", ");
+        Ok((DnsTransport::Classic(pool), servers))
+    }
 
-        // Start building the client connection
+    fn dns_endpoint(&self) -> BasicDnsEndpoint {
+        self.encoding
+            .dns_endpoint(self.query.clone(), self.constant.clone())
+            .txt_encoding(self.txt_encoding)
+    }
+
+    fn dns_server_addrs(&self) -> Vec<SocketAddr> {
+        if !self.server.is_empty() {
+            return self.server.clone();
+        }
+        let mut servers = match dns::get_system_dns_servers() {
+            Ok(servers) if servers.is_empty() => panic!("no valid system DNS servers"),
+            Ok(servers) => servers,
+            Err(err) => panic!("failed to load system DNS config: {}", err),
+        };
+        if !self.constant.is_fqdn() {
+            // Unless you've changed system configuration to point to a
+            // DNSCAT2 server, this will most certainly not work.
+            warn!("non-FQDN is being used with a system DNS server");
+        }
+        if self.protocol == Protocol::Tls {
+            for server in servers.iter_mut() {
+                server.set_port(DNS_TLS_PORT);
+            }
+        }
+        servers
+    }
+
+    async fn tls_config(&self) -> io::Result<DnsTlsConfig> {
+        let mut config = DnsTlsConfig::default();
+        if let Some(ref name) = self.tls_name {
+            config = config.server_name(name.clone());
+        }
+        if let Some(ref path) = self.tls_ca {
+            config = config.ca_pem(&fs::read(path).await?[..])?;
+        }
+        if let Some(ref path) = self.tls_pin {
+            config = config.pin_pem(&fs::read(path).await?[..])?;
+        }
+        Ok(config)
+    }
+
+    fn client_builder(&self) -> ClientBuilder {
         let mut conn = ClientBuilder::default()
-            .command(self.command)
             .min_delay(Duration::from_millis(self.min_delay))
             .max_delay(Duration::from_millis(self.max_delay))
             .random_delay(self.random_delay)
             .retransmit_backoff(self.retransmit_backoff)
-            .random_delay(self.random_delay)
             .prefer_server_name(self.prefer_server_name)
             .recv_queue_size(self.recv_queue_size)
+            .window_size(self.window)
+            .max_datagram_size(self.max_datagram_size)
             .packet_trace(self.packet_trace);
 
         if let Some(session_id) = self.session_id {
@@ -163,35 +430,81 @@ impl App {
         } else {
             conn = conn.max_retransmits(Some(self.max_retransmits));
         }
+        conn
+    }
 
-        info!(
-            "connecting to `{}` using `{}`",
-            dns_server_addr, self.constant
-        );
+    fn encryption(&self) -> StandardEncryption {
+        let preshared_key = self.secret.clone().map(Into::into);
+        StandardEncryption::new_with_ephemeral(true, preshared_key).unwrap()
+    }
 
-        let result = if self.insecure {
-            match conn.connect_insecure(dns_client).await {
-                Ok(client) => Ok(start_session(client, self).await),
-                Err(err) => Err(err),
-            }
+    /// Connects a sub-session spawned by the command session.
+    async fn connect_sub_session(
+        self,
+        transport: MuxTransport<DnsTransport>,
+        session_id: u16,
+        name: String,
+    ) -> io::Result<Box<dyn SessionStream>> {
+        let conn = self
+            .client_builder()
+            .session_id(session_id)
+            .session_name(name);
+        let stream: Box<dyn SessionStream> = if self.insecure {
+            Box::new(conn.connect_insecure(transport).await?)
         } else {
-            let preshared_key = self.secret.clone().map(Into::into);
-            if preshared_key.is_none() {
-                warn!("no preshared secret! (use `--secret <secret>`)");
-            }
-            let encryption = StandardEncryption::new_with_ephemeral(true, preshared_key).unwrap();
-            match conn.connect(dns_client, encryption).await {
-                Ok(client) => Ok(start_session(client, self).await),
-                Err(err) => Err(err),
-            }
+            Box::new(conn.connect(transport, self.encryption()).await?)
         };
-        if let Err(err) = result {
-            error!("failed to connect with {}", err)
+        Ok(stream)
+    }
+}
+
+/// The DNS client for the protocol used.
+enum DnsTransport {
+    Classic(ResolverPool<DnsClient<AsyncClient, BasicDnsEndpoint, LazyPacket>>),
+    Https(Box<DnsClient<HttpsHandle, BasicDnsEndpoint, LazyPacket>>),
+}
+
+impl Transport<LazyPacket> for DnsTransport {
+    type Error = DnsTransportError<<LazyPacket as Decode>::Error>;
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<LazyPacket, Self::Error>> {
+        match self {
+            Self::Classic(client) => client.poll_recv(cx),
+            Self::Https(client) => client.poll_recv(cx),
+        }
+    }
+
+    fn poll_send(
+        &mut self,
+        cx: &mut Context<'_>,
+        datagram: LazyPacket,
+    ) -> Poll<Result<(), Self::Error>> {
+        match self {
+            Self::Classic(client) => client.poll_send(cx, datagram),
+            Self::Https(client) => client.poll_send(cx, datagram),
+        }
+    }
+
+    fn max_send_size(&self) -> usize {
+        match self {
+            Self::Classic(client) => client.max_send_size(),
+            Self::Https(client) => client.max_send_size(),
+        }
+    }
+
+    fn max_recv_size(&self) -> usize {
+        match self {
+            Self::Classic(client) => client.max_recv_size(),
+            Self::Https(client) => client.max_recv_size(),
         }
     }
 }
 
-async fn start_session<T, E>(client: Client<T, E>, opts: &App)
+trait SessionStream: io::AsyncRead + io::AsyncWrite + Unpin {}
+
+impl<T> SessionStream for T where T: io::AsyncRead + io::AsyncWrite + Unpin {}
+
+async fn start_session<T, E>(client: Client<T, E>, opts: &App, mux: TransportMux<DnsTransport>)
 where
     T: Transport<LazyPacket> + Unpin,
     E: Encryption + Unpin,
@@ -202,9 +515,22 @@ where
         client.session().name().unwrap_or("<none>")
     );
 
+    if opts.command {
+        let mut opts = opts.clone();
+        opts.max_datagram_size = client.session().max_datagram_size();
+        let connector = move |session_id, name| {
+            opts.clone()
+                .connect_sub_session(mux.handle(), session_id, name)
+        };
+        if let Err(err) = CommandDriver::new(client, connector).run().await {
+            error!("command session failed: {}", err);
+        }
+        return;
+    }
+
     let (reader, writer) = io::split(client);
 
-    if let Some(process) = opts.exec.get(0) {
+    if let Some(process) = opts.exec.first() {
         let result = process::Command::new(process)
             .args(&opts.exec[1..])
             .stdin(Stdio::piped())
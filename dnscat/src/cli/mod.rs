@@ -1,2 +1,40 @@
 #[cfg(feature = "client-cli")]
 pub mod client;
+#[cfg(feature = "server-cli")]
+pub mod server;
+
+use std::str::FromStr;
+
+use crate::transport::dns::{Base32, Base36, BasicDnsEndpoint, Hex, Name, RecordType};
+
+/// The encoding of data in DNS names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Hex,
+    Base32,
+    Base36,
+}
+
+impl Encoding {
+    fn dns_endpoint(self, query_types: Vec<RecordType>, constant: Name) -> BasicDnsEndpoint {
+        match self {
+            Self::Hex => BasicDnsEndpoint::new_with_encoding(query_types, constant, Hex),
+            Self::Base32 => BasicDnsEndpoint::new_with_encoding(query_types, constant, Base32),
+            Self::Base36 => BasicDnsEndpoint::new_with_encoding(query_types, constant, Base36),
+        }
+        .unwrap()
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hex" => Ok(Self::Hex),
+            "base32" => Ok(Self::Base32),
+            "base36" => Ok(Self::Base36),
+            other => Err(format!("unknown encoding `{}`", other)),
+        }
+    }
+}
@@ -0,0 +1,394 @@
+use std::cmp;
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc;
+use futures::{future, pin_mut, StreamExt};
+use log::{error, info, warn};
+use rand::Rng;
+use structopt::StructOpt;
+use tokio::io::{self, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::process;
+use tokio::time;
+
+use crate::encryption::{EncryptionAcceptor, StandardEncryptionAcceptor};
+use crate::packet::LazyPacket;
+use crate::server::{
+    socks5_tunnel, CommandController, CommandHandle, Server, ServerBuilder, ServerStream,
+};
+use crate::transport::dns::{BasicDnsEndpoint, DnsEndpoint, DnsServer, Name};
+use crate::transport::Transport;
+
+use super::Encoding;
+
+#[derive(StructOpt, Debug)]
+#[structopt(version = "0.1", author = "avitex <avitex@wfxlabs.com>")]
+pub struct App {
+    /// DNS name constant.
+    constant: Name,
+
+    /// Set the address to listen for DNS requests on.
+    #[structopt(long, default_value = "0.0.0.0:53")]
+    listen: SocketAddr,
+
+    /// If set, will also listen for DNS requests over TCP.
+    #[structopt(long)]
+    tcp: bool,
+
+    /// Set the encoding of data in DNS names, which must match the client.
+    #[structopt(
+        long,
+        default_value = "hex",
+        possible_values = &["hex", "base32", "base36"]
+    )]
+    encoding: Encoding,
+
+    /// If set, send CNAME and MX responses split across several answers,
+    /// fitting more data in each.
+    #[structopt(long)]
+    multi_name: bool,
+
+    /// Set the shared secret used for encryption.
+    #[structopt(long)]
+    secret: Option<String>,
+
+    /// If set, will accept sessions without encryption/authentication.
+    #[structopt(long)]
+    insecure: bool,
+
+    /// If set, display incoming/outgoing DNSCAT2 packets.
+    #[structopt(long)]
+    packet_trace: bool,
+
+    /// Forward connections on a local address to a remote host, through
+    /// the client of the latest command session (eg. `127.0.0.1:8080=intranet:80`).
+    #[structopt(long)]
+    forward: Option<Forward>,
+
+    /// Listen for SOCKS5 connections on a local address, tunnelling each
+    /// through the client of the latest command session.
+    #[structopt(long)]
+    socks: Option<SocketAddr>,
+
+    /// Execute a process and attach stdin/stdout.
+    #[structopt(long, short, multiple = true, allow_hyphen_values = true)]
+    exec: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct Forward {
+    listen: SocketAddr,
+    host: String,
+    port: u16,
+}
+
+impl FromStr for Forward {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected `<listen-addr>=<host>:<port>`, got `{}`", s);
+        let (listen, remote) = s.split_once('=').ok_or_else(invalid)?;
+        let (host, port) = remote.rsplit_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            listen: listen.parse().map_err(|_| invalid())?,
+            host: host.to_string(),
+            port: port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// How long to wait after a server error before accepting again, doubled
+/// for each error in a row up to the max.
+const ERROR_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The command session used for forwarding and SOCKS5.
+type CommandSlot = Arc<Mutex<Option<CommandHandle>>>;
+
+impl App {
+    pub fn new() -> Self {
+        Self::from_args()
+    }
+
+    pub async fn run(&self) {
+        dotenv::dotenv().ok();
+        env_logger::init();
+
+        // Build the DNS endpoint, answering all the query types we support.
+        let query_types = <BasicDnsEndpoint as DnsEndpoint>::supported_queries().to_vec();
+        let dns_endpoint = self
+            .encoding
+            .dns_endpoint(query_types, self.constant.clone());
+
+        // Build the DNS server
+        let socket = match UdpSocket::bind(self.listen).await {
+            Ok(socket) => socket,
+            Err(err) => panic!("failed to bind `{}`: {}", self.listen, err),
+        };
+        let mut dns_server = DnsServer::new(socket, dns_endpoint).multi_name(self.multi_name);
+        if self.tcp {
+            match TcpListener::bind(self.listen).await {
+                Ok(listener) => dns_server = dns_server.with_tcp(listener),
+                Err(err) => panic!("failed to bind `{}`: {}", self.listen, err),
+            }
+        }
+
+        // Build the encryption acceptor.
+        let preshared_key = self.secret.clone().map(Into::into);
+        if preshared_key.is_none() {
+            warn!("no preshared secret! (use `--secret <secret>`)");
+        }
+        let acceptor = StandardEncryptionAcceptor::new(preshared_key);
+
+        let server = ServerBuilder::default()
+            .insecure(self.insecure)
+            .packet_trace(self.packet_trace)
+            .build(dns_server, acceptor);
+
+        info!("listening on `{}` using `{}`", self.listen, self.constant);
+
+        let command_slot = if self.forward.is_some() || self.socks.is_some() {
+            Some(CommandSlot::default())
+        } else {
+            None
+        };
+        if let (Some(forward), Some(command_slot)) = (&self.forward, &command_slot) {
+            let listener = bind_tcp(forward.listen).await;
+            tokio::spawn(forward_connections(
+                listener,
+                forward.clone(),
+                command_slot.clone(),
+            ));
+        }
+        if let (Some(addr), Some(command_slot)) = (self.socks, &command_slot) {
+            let listener = bind_tcp(addr).await;
+            info!("SOCKS5 listening on `{}`", addr);
+            tokio::spawn(socks_connections(listener, command_slot.clone()));
+        }
+
+        if let Some(process) = self.exec.first() {
+            let result = process::Command::new(process)
+                .args(&self.exec[1..])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn();
+            match result {
+                Ok(child) => {
+                    let stdin = child.stdin.unwrap();
+                    let stdout = child.stdout.unwrap();
+                    serve(server, command_slot, stdout, stdin).await
+                }
+                Err(err) => panic!("failed to start `{}`: {}", process, err),
+            }
+        } else {
+            serve(server, command_slot, io::stdin(), io::stdout()).await
+        }
+    }
+}
+
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Drives the server, attaching each accepted session in turn to the
+/// reader and writer.
+///
+/// If tunnelling, command sessions are instead used for tunnels.
+async fn serve<T, A, R, I, O>(
+    mut server: Server<T, A, R>,
+    command_slot: Option<CommandSlot>,
+    mut read: I,
+    mut write: O,
+) where
+    T: Transport<LazyPacket>,
+    A: EncryptionAcceptor,
+    R: Rng + Clone,
+    I: io::AsyncRead + Unpin,
+    O: io::AsyncWrite + Unpin,
+{
+    let (stream_tx, mut stream_rx) = mpsc::unbounded();
+
+    let accept_fut = async {
+        let mut backoff = ERROR_BACKOFF;
+        let mut last_error: Option<Instant> = None;
+        loop {
+            match server.accept().await {
+                Ok(stream) => match command_slot {
+                    Some(ref command_slot) if stream.is_command() => {
+                        start_command_session(stream, command_slot)
+                    }
+                    _ => stream_tx.unbounded_send(stream).expect("attach stopped"),
+                },
+                Err(err) => {
+                    // A persistent error is retried less often.
+                    backoff = match last_error {
+                        Some(at) if at.elapsed() < MAX_ERROR_BACKOFF => {
+                            cmp::min(backoff * 2, MAX_ERROR_BACKOFF)
+                        }
+                        _ => ERROR_BACKOFF,
+                    };
+                    warn!(
+                        "server error: {}, retrying in {} ms",
+                        err,
+                        backoff.as_millis()
+                    );
+                    time::sleep(backoff).await;
+                    last_error = Some(Instant::now());
+                }
+            }
+        }
+    };
+
+    let attach_fut = async {
+        while let Some(stream) = stream_rx.next().await {
+            if !attach(stream, &mut read, &mut write).await {
+                break;
+            }
+        }
+    };
+
+    pin_mut!(accept_fut);
+    pin_mut!(attach_fut);
+
+    future::select(accept_fut, attach_fut).await;
+}
+
+/// Attaches a session to the reader and writer until it is closed.
+///
+/// Returns `false` if the reader has reached the end of input.
+async fn attach<I, O>(stream: ServerStream, read: &mut I, write: &mut O) -> bool
+where
+    I: io::AsyncRead + Unpin,
+    O: io::AsyncWrite + Unpin,
+{
+    info!(
+        "attached session (id: {}, name: {})",
+        stream.id(),
+        stream.name().unwrap_or("<none>")
+    );
+
+    let id = stream.id();
+    let (mut stream_read, mut stream_write) = io::split(stream);
+
+    let to_client_fut = async {
+        if let Err(err) = io::copy(read, &mut stream_write).await {
+            error!("failed to read input: {}", err);
+        }
+        if let Err(err) = stream_write.shutdown().await {
+            warn!("failed to close session {}: {}", id, err);
+        }
+    };
+    let to_output_fut = async {
+        if let Err(err) = io::copy(&mut stream_read, write).await {
+            error!("failed to write output: {}", err);
+        }
+    };
+
+    pin_mut!(to_client_fut);
+    pin_mut!(to_output_fut);
+
+    let input_open = match future::select(to_client_fut, to_output_fut).await {
+        future::Either::Left(((), to_output_fut)) => {
+            to_output_fut.await;
+            false
+        }
+        future::Either::Right(((), _)) => true,
+    };
+
+    info!("detached session {}", id);
+
+    input_open
+}
+
+/// Starts driving a command session, making it the one used for tunnels.
+fn start_command_session(stream: ServerStream, command_slot: &CommandSlot) {
+    let id = stream.id();
+    info!("using command session {} for tunnels", id);
+    let (controller, handle) = CommandController::new(stream);
+    *command_slot.lock().expect("command slot poisoned") = Some(handle);
+    tokio::spawn(async move {
+        if let Err(err) = controller.run().await {
+            warn!("command session {} error: {}", id, err);
+        }
+        info!("command session {} closed", id);
+    });
+}
+
+/// Binds a TCP listener, panicking on failure as for the DNS socket.
+async fn bind_tcp(addr: SocketAddr) -> TcpListener {
+    match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => panic!("failed to bind `{}`: {}", addr, err),
+    }
+}
+
+/// Accepts the next connection while there is a command session to
+/// tunnel it through.
+async fn accept_tunnelled(
+    listener: &TcpListener,
+    command_slot: &CommandSlot,
+) -> (TcpStream, SocketAddr, CommandHandle) {
+    loop {
+        let (stream, peer_addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                error!("failed to accept connection: {}", err);
+                continue;
+            }
+        };
+        let handle = command_slot.lock().expect("command slot poisoned").clone();
+        match handle {
+            Some(handle) => return (stream, peer_addr, handle),
+            None => warn!("no command session to tunnel {} through", peer_addr),
+        }
+    }
+}
+
+/// Accepts SOCKS5 connections to tunnel through the current command session.
+async fn socks_connections(listener: TcpListener, command_slot: CommandSlot) {
+    loop {
+        let (stream, peer_addr, handle) = accept_tunnelled(&listener, &command_slot).await;
+        tokio::spawn(async move {
+            info!("SOCKS5 connection from {}", peer_addr);
+            if let Err(err) = socks5_tunnel(stream, &handle).await {
+                warn!("SOCKS5 connection from {} failed: {}", peer_addr, err);
+            }
+        });
+    }
+}
+
+/// Accepts connections to forward through the current command session.
+async fn forward_connections(listener: TcpListener, forward: Forward, command_slot: CommandSlot) {
+    loop {
+        let (stream, peer_addr, handle) = accept_tunnelled(&listener, &command_slot).await;
+        let forward = forward.clone();
+        tokio::spawn(async move {
+            let tunnel = match handle.tunnel_connect(&forward.host, forward.port).await {
+                Ok(tunnel) => tunnel,
+                Err(err) => {
+                    warn!(
+                        "failed to open tunnel to {}:{}: {}",
+                        forward.host, forward.port, err
+                    );
+                    return;
+                }
+            };
+            info!(
+                "forwarding {} to {}:{} (tunnel: {})",
+                peer_addr,
+                forward.host,
+                forward.port,
+                tunnel.id()
+            );
+            if let Err(err) = tunnel.relay(stream).await {
+                warn!("tunnel error: {}", err);
+            }
+        });
+    }
+}
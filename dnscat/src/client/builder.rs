@@ -1,16 +1,22 @@
 use std::borrow::Cow;
-use std::collections::VecDeque;
+use std::cmp;
+use std::sync::Arc;
 use std::time::Duration;
 
-use bytes::Bytes;
+use log::info;
 use rand::prelude::{Rng, ThreadRng};
 
+use crate::clock::{Clock, SystemClock};
+#[cfg(feature = "persist")]
+use crate::encryption::EncryptionState;
 use crate::encryption::{Encryption, NoEncryption};
-use crate::packet::{LazyPacket, Sequence};
-use crate::session::{Session, SessionRole, SessionStage};
+use crate::packet::{LazyPacket, MsgBody, PingBody, Sequence};
+use crate::session::{Session, SessionRole};
+#[cfg(feature = "persist")]
+use crate::session::{SessionError, SessionStage, SessionState, SessionStateError};
 use crate::transport::Transport;
 
-use super::{Client, ClientError, ClientOpts};
+use super::{ping, probe, Client, ClientError, ClientOpts, Pong};
 
 #[derive(Debug)]
 pub struct ClientBuilder<R = ThreadRng>
@@ -29,7 +35,15 @@ where
     recv_queue_size: usize,
     max_retransmits: Option<usize>,
     retransmit_backoff: bool,
+    window_size: usize,
     packet_trace: bool,
+    probe: bool,
+    probe_timeout: Duration,
+    ping_timeout: Duration,
+    max_datagram_size: Option<usize>,
+    clock: Arc<dyn Clock>,
+    #[cfg(feature = "persist")]
+    max_resume_age: Option<Duration>,
 }
 
 impl<R> ClientBuilder<R>
@@ -48,9 +62,17 @@ where
             random_delay: false,
             recv_queue_size: 16,
             retransmit_backoff: true,
+            window_size: 1,
+            probe: false,
+            probe_timeout: Duration::from_secs(3),
+            ping_timeout: Duration::from_secs(3),
+            max_datagram_size: None,
+            clock: Arc::new(SystemClock),
             max_retransmits: Some(20),
             min_delay: Duration::from_secs(0),
             max_delay: Duration::from_secs(1),
+            #[cfg(feature = "persist")]
+            max_resume_age: Some(Duration::from_secs(5 * 60)),
         }
     }
 
@@ -98,6 +120,57 @@ where
         self
     }
 
+    /// Set the max number of `MSG` requests in flight.
+    ///
+    /// A size greater than one is only used if the server supports it,
+    /// otherwise each request waits for the last to be answered.
+    pub fn window_size(mut self, size: usize) -> Self {
+        assert_ne!(size, 0, "window size must be greater than zero");
+        self.window_size = size;
+        self
+    }
+
+    /// Limit the size of datagrams sent, such as to the size probed
+    /// for an earlier session over the same transport.
+    pub fn max_datagram_size(mut self, max: Option<usize>) -> Self {
+        assert_ne!(max, Some(0), "max datagram size must be greater than zero");
+        self.max_datagram_size = max;
+        self
+    }
+
+    /// Set whether to probe the largest datagram that survives the
+    /// transport before connecting, limiting datagrams to it.
+    ///
+    /// This is done with `PING` packets, which the server echoes.
+    pub fn probe(mut self, value: bool) -> Self {
+        self.probe = value;
+        self
+    }
+
+    /// Set how long to wait for the echo of each probe, which defaults
+    /// to three seconds.
+    pub fn probe_timeout(mut self, duration: Duration) -> Self {
+        self.probe_timeout = duration;
+        self
+    }
+
+    /// Set how long to wait for the echo of a ping, which defaults to
+    /// three seconds.
+    pub fn ping_timeout(mut self, duration: Duration) -> Self {
+        self.ping_timeout = duration;
+        self
+    }
+
+    /// Set the clock the session takes its time from, which defaults
+    /// to the system clock.
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
     pub fn command(mut self, value: bool) -> Self {
         self.is_command = value;
         self
@@ -118,6 +191,15 @@ where
         self
     }
 
+    /// Set the max age of a session state that can be resumed.
+    ///
+    /// Defaults to five minutes, `None` resumes state of any age.
+    #[cfg(feature = "persist")]
+    pub fn max_resume_age(mut self, max: Option<Duration>) -> Self {
+        self.max_resume_age = max;
+        self
+    }
+
     pub async fn connect<T, E>(
         self,
         transport: T,
@@ -140,9 +222,131 @@ where
         self.generic_connect(transport, None).await
     }
 
-    async fn generic_connect<T, E>(
+    /// Send a `PING` with the given data, checking the server is reachable
+    /// without opening a session.
+    ///
+    /// Fails with [`ClientError::PingTimeout`] if no echo is received within
+    /// the ping timeout, or [`ClientError::PingTooLarge`] if the `PING`
+    /// does not fit in a datagram of the transport.
+    pub async fn ping<T, S>(
         mut self,
+        mut transport: T,
+        data: S,
+    ) -> Result<Pong, ClientError<T::Error>>
+    where
+        T: Transport<LazyPacket>,
+        S: Into<String>,
+    {
+        let data = data.into();
+        let max = cmp::min(transport.max_send_size(), transport.max_recv_size());
+        if PingBody::packet_size_no_data() + data.len() > max {
+            return Err(ClientError::PingTooLarge);
+        }
+        let timeout = self.ping_timeout;
+        ping::ping(
+            &mut transport,
+            &mut self.random,
+            &*self.clock,
+            data,
+            timeout,
+        )
+        .await
+    }
+
+    /// Resume an encrypted session from its saved state, with the
+    /// encryption restored from it.
+    ///
+    /// The session ID if set and whether this is a command session must
+    /// match the state, which is refused if it is older than the max
+    /// resume age. See [`SessionState::restore_encryption`] for restoring
+    /// the encryption.
+    #[cfg(feature = "persist")]
+    pub async fn resume<T, E>(
+        self,
+        transport: T,
+        state: SessionState,
+        encryption: E,
+    ) -> Result<Client<T, E, R>, ClientError<T::Error>>
+    where
+        T: Transport<LazyPacket>,
+        E: EncryptionState,
+    {
+        self.generic_resume(transport, state, Some(encryption))
+            .await
+    }
+
+    /// Resume an unencrypted session from its saved state.
+    ///
+    /// See [`ClientBuilder::resume`].
+    #[cfg(feature = "persist")]
+    pub async fn resume_insecure<T>(
+        self,
         transport: T,
+        state: SessionState,
+    ) -> Result<Client<T, NoEncryption, R>, ClientError<T::Error>>
+    where
+        T: Transport<LazyPacket>,
+    {
+        self.generic_resume(transport, state, None).await
+    }
+
+    #[cfg(feature = "persist")]
+    async fn generic_resume<T, E>(
+        mut self,
+        mut transport: T,
+        state: SessionState,
+        encryption: Option<E>,
+    ) -> Result<Client<T, E, R>, ClientError<T::Error>>
+    where
+        T: Transport<LazyPacket>,
+        E: EncryptionState,
+    {
+        let mismatch = if matches!(self.session_id, Some(id) if id != state.id) {
+            Some("session ID")
+        } else if self.is_command != state.is_command {
+            Some("command")
+        } else if state.is_encrypted() != encryption.is_some() {
+            Some("encryption")
+        } else {
+            None
+        };
+        if let Some(mismatch) = mismatch {
+            return Err(SessionError::from(SessionStateError::Mismatch(mismatch)).into());
+        }
+        state
+            .validate(SessionRole::Client, self.max_resume_age)
+            .map_err(SessionError::from)?;
+        let min_datagram_size = msg_packet_min_size(encryption.as_ref());
+        let max_datagram_size = self
+            .probe_transport(&mut transport, min_datagram_size)
+            .await?;
+        let options = self.client_opts();
+        let recv_queue_size = self.recv_queue_size;
+        let mut session = Session::new(
+            SessionRole::Client,
+            state.id,
+            state.self_seq,
+            self.random,
+            self.clock,
+            encryption,
+        );
+        session.name = state.name.map(Into::into);
+        session.peer_seq = state.peer_seq;
+        session.is_command = state.is_command;
+        // Stop-and-wait, which a windowed server also supports.
+        session.windowed = false;
+        session.stage = SessionStage::Send;
+        session.prefer_peer_name = self.prefer_server_name;
+        session.packet_trace = self.packet_trace;
+        session.max_exchange_attempts = self.max_retransmits;
+        session.max_datagram_size = max_datagram_size;
+        let client = Client::new(transport, session, options, recv_queue_size);
+        client.resume().await
+    }
+
+    async fn generic_connect<T, E>(
+        mut self,
+        mut transport: T,
         encryption: Option<E>,
     ) -> Result<Client<T, E, R>, ClientError<T::Error>>
     where
@@ -153,54 +357,253 @@ where
             self.min_delay <= self.max_delay,
             "min delay should be equal to or less than max delay"
         );
+        let min_datagram_size = msg_packet_min_size(encryption.as_ref());
+        let max_datagram_size = self
+            .probe_transport(&mut transport, min_datagram_size)
+            .await?;
         let init_seq = self.initial_sequence.unwrap_or_else(|| self.random.gen());
         let session_id = self.session_id.unwrap_or_else(|| self.random.gen());
+        let options = self.client_opts();
+        let recv_queue_size = self.recv_queue_size;
         let session_name = if self.session_name.is_empty() {
             None
         } else {
             Some(self.session_name)
         };
-        let session = Session {
-            id: session_id,
-            name: session_name,
-            random: self.random,
-            self_seq: Sequence(init_seq),
-            self_seq_pending: Sequence(init_seq),
-            peer_seq: Sequence(0),
-            is_command: self.is_command,
-            role: SessionRole::Client,
+        let mut session = Session::new(
+            SessionRole::Client,
+            session_id,
+            Sequence(init_seq),
+            self.random,
+            self.clock,
             encryption,
-            stage: SessionStage::Uninit,
-            prefer_peer_name: self.prefer_server_name,
-            packet_trace: self.packet_trace,
-            close_reason: None,
-            last_exchange: None,
-            exchange_attempt: None,
-            max_exchange_attempts: self.max_retransmits,
-        };
-        let options = ClientOpts {
+        );
+        session.name = session_name;
+        session.is_command = self.is_command;
+        session.windowed = self.window_size > 1;
+        session.prefer_peer_name = self.prefer_server_name;
+        session.packet_trace = self.packet_trace;
+        session.max_exchange_attempts = self.max_retransmits;
+        session.max_datagram_size = max_datagram_size;
+        let client = Client::new(transport, session, options, recv_queue_size);
+        client.handshake().await
+    }
+
+    /// Returns the max datagram size, probing the transport if set to.
+    ///
+    /// The min size is the smallest datagram the session can send data in.
+    async fn probe_transport<T>(
+        &mut self,
+        transport: &mut T,
+        min: usize,
+    ) -> Result<Option<usize>, ClientError<T::Error>>
+    where
+        T: Transport<LazyPacket>,
+    {
+        if !self.probe {
+            return Ok(self.max_datagram_size);
+        }
+        // Probes are echoed, so must fit both ways.
+        let max = self
+            .max_datagram_size
+            .unwrap_or_else(|| cmp::min(transport.max_send_size(), transport.max_recv_size()));
+        let timeout = self.probe_timeout;
+        let probed =
+            probe::max_datagram_size(transport, &mut self.random, &*self.clock, min, max, timeout)
+                .await
+                .ok_or(ClientError::ProbeFailed)?;
+        info!("probed max datagram size of {}", probed);
+        Ok(Some(probed))
+    }
+
+    fn client_opts(&self) -> ClientOpts {
+        ClientOpts {
             retransmit_backoff: self.retransmit_backoff,
             random_delay: self.random_delay,
             min_delay: self.min_delay,
             max_delay: self.max_delay,
-        };
-        let client = Client {
-            session,
-            options,
-            transport,
-            exchange: None,
-            send_task: None,
-            poll_delay: None,
-            send_buf: Bytes::new(),
-            recv_queue: VecDeque::with_capacity(self.recv_queue_size),
-            recv_buf: Bytes::new(),
-        };
-        client.handshake().await
+            window_size: self.window_size,
+        }
     }
 }
 
+/// Returns the size of a `MSG` packet carrying a single byte of data.
+fn msg_packet_min_size<E: Encryption>(encryption: Option<&E>) -> usize {
+    let args_size = encryption.map_or(0, Encryption::args_size);
+    (MsgBody::packet_size_no_data() + args_size) as usize + 1
+}
+
 impl Default for ClientBuilder {
     fn default() -> Self {
         Self::default_with_random(ThreadRng::default())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::task::{Context, Poll};
+
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::{future, pin_mut};
+    use rand::SeedableRng;
+    use rand_pcg::Pcg32;
+
+    use super::*;
+    use crate::clock::Simulation;
+    use crate::server::ServerBuilder;
+    use crate::transport::channel::{channel_pair, ChannelTransport};
+    use crate::transport::{Encode, MemoryTransport};
+
+    /// Drops datagrams larger than a max size, as resolvers do with
+    /// names too long for them.
+    struct LimitedTransport {
+        inner: ChannelTransport,
+        max: usize,
+    }
+
+    impl Transport<LazyPacket> for LimitedTransport {
+        type Error = <ChannelTransport as Transport<LazyPacket>>::Error;
+
+        fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<LazyPacket, Self::Error>> {
+            self.inner.poll_recv(cx)
+        }
+
+        fn poll_send(
+            &mut self,
+            cx: &mut Context<'_>,
+            datagram: LazyPacket,
+        ) -> Poll<Result<(), Self::Error>> {
+            let mut buf = Vec::new();
+            datagram.encode(&mut buf);
+            if buf.len() > self.max {
+                return Poll::Ready(Ok(()));
+            }
+            self.inner.poll_send(cx, datagram)
+        }
+
+        fn max_send_size(&self) -> usize {
+            self.inner.max_send_size()
+        }
+
+        fn max_recv_size(&self) -> usize {
+            self.inner.max_recv_size()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_probed_echo() {
+        let (client_transport, server_transport) = channel_pair();
+        let client_transport = LimitedTransport {
+            inner: client_transport,
+            max: 60,
+        };
+        let mut server = ServerBuilder::default().build_insecure(server_transport);
+        let client = async {
+            let mut client = ClientBuilder::default()
+                .max_delay(Duration::from_millis(10))
+                .probe(true)
+                .probe_timeout(Duration::from_millis(50))
+                .connect_insecure(client_transport)
+                .await
+                .unwrap();
+            assert_eq!(client.session().max_datagram_size(), Some(60));
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            client.read_exact(&mut buf[..]).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        };
+        let serve = async {
+            let mut stream = server.accept().await.unwrap();
+            let echo = async move {
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf[..]).await.unwrap();
+                stream.write_all(&buf[..]).await.unwrap();
+                stream.flush().await.unwrap();
+                future::pending::<()>().await;
+            };
+            let drive = server.accept();
+            pin_mut!(echo, drive);
+            future::select(echo, drive).await;
+        };
+        pin_mut!(client, serve);
+        if let future::Either::Right(_) = future::select(client, serve).await {
+            panic!("server stopped");
+        }
+    }
+
+    #[test]
+    fn test_simulated_ping() {
+        let sim = Simulation::new();
+        let clock = sim.clock();
+        let (client_transport, server_transport) =
+            MemoryTransport::pair_with_random(Pcg32::seed_from_u64(1), Pcg32::seed_from_u64(2));
+        let latency = Duration::from_millis(50);
+        let client_transport = client_transport.latency(latency).clock(clock.clone());
+        let server_transport = server_transport.latency(latency).clock(clock.clone());
+        let mut server = ServerBuilder::default().build_insecure(server_transport);
+        let ping = ClientBuilder::default()
+            .clock(clock.clone())
+            .ping(client_transport, "hello");
+        let drive = server.accept();
+        pin_mut!(ping, drive);
+        let pong = match sim.run(future::select(ping, drive)) {
+            future::Either::Left((pong, _)) => pong.unwrap(),
+            future::Either::Right(_) => panic!("server stopped"),
+        };
+        assert_eq!(pong.data, "hello");
+        assert_eq!(pong.rtt, latency * 2);
+    }
+
+    #[test]
+    fn test_simulated_ping_late_error() {
+        let sim = Simulation::new();
+        let clock = sim.clock();
+        let (client_transport, server_transport) =
+            MemoryTransport::pair_with_random(Pcg32::seed_from_u64(1), Pcg32::seed_from_u64(2));
+        let latency = Duration::from_secs(2);
+        // Errors before the echo, as an earlier exchange timing out would.
+        let client_transport = client_transport
+            .latency(latency)
+            .recv_timeout(Some(Duration::from_secs(1)))
+            .clock(clock.clone());
+        let server_transport = server_transport.latency(latency).clock(clock.clone());
+        let mut server = ServerBuilder::default().build_insecure(server_transport);
+        let ping = ClientBuilder::default()
+            .clock(clock.clone())
+            .ping_timeout(Duration::from_secs(5))
+            .ping(client_transport, "hello");
+        let drive = server.accept();
+        pin_mut!(ping, drive);
+        let pong = match sim.run(future::select(ping, drive)) {
+            future::Either::Left((pong, _)) => pong.unwrap(),
+            future::Either::Right(_) => panic!("server stopped"),
+        };
+        assert_eq!(pong.rtt, latency * 2);
+    }
+
+    #[test]
+    fn test_simulated_ping_timeout() {
+        let sim = Simulation::new();
+        let clock = sim.clock();
+        let (client_transport, _server_transport) =
+            MemoryTransport::pair_with_random(Pcg32::seed_from_u64(1), Pcg32::seed_from_u64(2));
+        let client_transport = client_transport.loss(1.0).clock(clock.clone());
+        let ping = ClientBuilder::default()
+            .clock(clock.clone())
+            .ping_timeout(Duration::from_secs(3))
+            .ping(client_transport, "hello");
+        match sim.run(ping) {
+            Err(ClientError::PingTimeout) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(clock.elapsed(), Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn test_ping_too_large() {
+        let (client_transport, _server_transport) = MemoryTransport::pair();
+        let data = "a".repeat(LazyPacket::max_size() as usize);
+        let result = ClientBuilder::default().ping(client_transport, data).await;
+        assert!(matches!(result, Err(ClientError::PingTooLarge)));
+    }
+}
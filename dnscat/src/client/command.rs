@@ -0,0 +1,627 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::process::Stdio;
+use std::time::Duration;
+use std::{fmt, io};
+
+use bytes::{Bytes, BytesMut};
+use failure::Fail;
+use futures::channel::mpsc;
+use futures::future::LocalBoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::{FutureExt, SinkExt};
+use log::{debug, info, warn};
+use rand::prelude::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::{fs, process};
+
+use crate::command::{
+    relay_tunnel, CommandBody, CommandPacket, CommandRequest, CommandResponse, CommandStatus,
+    RequestId, TunnelId, RESPONSE_BIT, TUNNEL_QUEUE_SIZE,
+};
+use crate::encryption::Encryption;
+use crate::packet::{LazyPacket, SessionId};
+use crate::transport::Transport;
+use crate::util::Encode;
+
+use super::Client;
+
+/// Connects the sub-sessions spawned by a [`CommandDriver`].
+///
+/// Implemented for any `FnMut(SessionId, String) -> Future` returning a
+/// connected session stream.
+pub trait SessionConnector {
+    /// The connected session stream.
+    type Stream: AsyncRead + AsyncWrite + Unpin + 'static;
+
+    /// The future returned when connecting a session.
+    type Future: Future<Output = Result<Self::Stream, io::Error>> + 'static;
+
+    /// Connect a new session with the given ID and name.
+    fn connect(&mut self, session_id: SessionId, name: String) -> Self::Future;
+}
+
+impl<F, Fut, S> SessionConnector for F
+where
+    F: FnMut(SessionId, String) -> Fut,
+    Fut: Future<Output = Result<S, io::Error>> + 'static,
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    type Stream = S;
+    type Future = Fut;
+
+    fn connect(&mut self, session_id: SessionId, name: String) -> Self::Future {
+        self(session_id, name)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Drives a command session, answering the requests sent by the server.
+///
+/// Shells and execs are attached to sub-sessions connected with the
+/// [`SessionConnector`], which are driven alongside the command session,
+/// as are tunnel connections and file transfers. Tunnels are connected over
+/// TCP and relayed within the command session by spawned tasks.
+pub struct CommandDriver<T, E, R, C>
+where
+    T: Transport<LazyPacket>,
+    C: SessionConnector,
+{
+    client: Client<T, E, R>,
+    handler: CommandHandler<C>,
+    recv_buf: BytesMut,
+}
+
+impl<T, E, R, C> CommandDriver<T, E, R, C>
+where
+    T: Transport<LazyPacket> + Unpin,
+    E: Encryption + Unpin,
+    R: Rng + Unpin,
+    C: SessionConnector,
+{
+    pub fn new(client: Client<T, E, R>, connector: C) -> Self {
+        Self {
+            client,
+            handler: CommandHandler::new(connector),
+            recv_buf: BytesMut::new(),
+        }
+    }
+
+    /// Answers requests until the session is closed or the server
+    /// requests a shutdown.
+    pub async fn run(mut self) -> Result<(), io::Error> {
+        let mut buf = [0u8; 1024];
+        loop {
+            while let Some(packet) = CommandPacket::decode_buf(&mut self.recv_buf)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.compat()))?
+            {
+                if let Some(response) = self.handler.handle_packet(packet).await {
+                    self.send(response).await?;
+                }
+                if let Some(delay) = self.handler.delay.take() {
+                    self.client.set_max_delay(delay);
+                }
+                if self.handler.shutdown {
+                    info!("shutdown requested");
+                    return self.client.shutdown().await;
+                }
+            }
+            let tasks = &mut self.handler.tasks;
+            let request_rx = &mut self.handler.request_rx;
+            let packet = tokio::select! {
+                result = self.client.read(&mut buf[..]) => match result? {
+                    0 => return Ok(()),
+                    len => {
+                        self.recv_buf.extend_from_slice(&buf[..len]);
+                        continue;
+                    }
+                },
+                Some(output) = tasks.next(), if !tasks.is_empty() => match output {
+                    Some(output) => self.handler.handle_task_output(output),
+                    None => continue,
+                },
+                // Send a request from a tunnel.
+                Some(request) = request_rx.next() => {
+                    CommandPacket::request(self.handler.next_request_id(), request)
+                }
+            };
+            self.send(packet).await?;
+        }
+    }
+
+    async fn send(&mut self, packet: CommandPacket) -> Result<(), io::Error> {
+        let mut bytes = BytesMut::new();
+        packet.encode(&mut bytes);
+        self.client.write_all(&bytes[..]).await?;
+        self.client.flush().await
+    }
+}
+
+impl<T, E, R, C> fmt::Debug for CommandDriver<T, E, R, C>
+where
+    T: Transport<LazyPacket> + fmt::Debug,
+    E: fmt::Debug,
+    R: fmt::Debug,
+    C: SessionConnector,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommandDriver")
+            .field("client", &self.client)
+            .field("tasks", &self.handler.tasks.len())
+            .field("tunnels", &self.handler.tunnels.len())
+            .finish()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// The result of a task answering a request once complete.
+enum TaskOutput {
+    /// The response to a request.
+    Response(RequestId, CommandResponse),
+    /// A TCP connection made for a `TUNNEL_CONNECT` request.
+    Connected(RequestId, String, TcpStream),
+}
+
+struct CommandHandler<C> {
+    connector: C,
+    /// Sub-sessions, tunnel connections and file transfers being driven.
+    tasks: FuturesUnordered<LocalBoxFuture<'static, Option<TaskOutput>>>,
+    /// Senders for data received on each open tunnel.
+    tunnels: HashMap<TunnelId, mpsc::Sender<Bytes>>,
+    next_tunnel_id: TunnelId,
+    /// Requests made by tunnels to be sent to the server.
+    request_tx: mpsc::Sender<CommandRequest>,
+    request_rx: mpsc::Receiver<CommandRequest>,
+    next_request_id: RequestId,
+    delay: Option<Duration>,
+    shutdown: bool,
+}
+
+impl<C> CommandHandler<C>
+where
+    C: SessionConnector,
+{
+    fn new(connector: C) -> Self {
+        let (request_tx, request_rx) = mpsc::channel(TUNNEL_QUEUE_SIZE);
+        Self {
+            connector,
+            tasks: FuturesUnordered::new(),
+            tunnels: HashMap::new(),
+            next_tunnel_id: 0,
+            request_tx,
+            request_rx,
+            next_request_id: 0,
+            delay: None,
+            shutdown: false,
+        }
+    }
+
+    fn next_request_id(&mut self) -> RequestId {
+        let request_id = self.next_request_id;
+        self.next_request_id = (request_id + 1) & !RESPONSE_BIT;
+        request_id
+    }
+
+    async fn handle_packet(&mut self, packet: CommandPacket) -> Option<CommandPacket> {
+        let request_id = packet.request_id;
+        match packet.body {
+            CommandBody::Request(request) => self
+                .handle_request(request_id, request)
+                .await
+                .map(|response| CommandPacket::response(request_id, response)),
+            CommandBody::Response(response) => {
+                debug!("ignoring response {:?}", response.kind());
+                None
+            }
+        }
+    }
+
+    async fn handle_request(
+        &mut self,
+        request_id: RequestId,
+        request: CommandRequest,
+    ) -> Option<CommandResponse> {
+        debug!("handling request {} ({:?})", request_id, request.kind());
+        let response = match request {
+            CommandRequest::Ping { data } => CommandResponse::Ping { data },
+            CommandRequest::Shell { name } => {
+                let command = shell_command(None);
+                match self.spawn_session(name.to_string(), command) {
+                    Ok(session_id) => CommandResponse::Shell { session_id },
+                    Err(err) => error_response(format!("failed to start shell: {}", err)),
+                }
+            }
+            CommandRequest::Exec { name, command } => {
+                let command = shell_command(Some(&command));
+                match self.spawn_session(name.to_string(), command) {
+                    Ok(session_id) => CommandResponse::Exec { session_id },
+                    Err(err) => error_response(format!("failed to execute: {}", err)),
+                }
+            }
+            CommandRequest::Download { filename } => {
+                let download = async move {
+                    let response = match fs::read(&*filename).await {
+                        Ok(data) => CommandResponse::Download { data: data.into() },
+                        Err(err) => {
+                            error_response(format!("failed to read `{}`: {}", &*filename, err))
+                        }
+                    };
+                    Some(TaskOutput::Response(request_id, response))
+                };
+                self.tasks.push(download.boxed_local());
+                return None;
+            }
+            CommandRequest::Upload { filename, data } => {
+                let upload = async move {
+                    let response = match fs::write(&*filename, &data[..]).await {
+                        Ok(()) => CommandResponse::Upload,
+                        Err(err) => {
+                            error_response(format!("failed to write `{}`: {}", &*filename, err))
+                        }
+                    };
+                    Some(TaskOutput::Response(request_id, response))
+                };
+                self.tasks.push(upload.boxed_local());
+                return None;
+            }
+            CommandRequest::Shutdown => {
+                self.shutdown = true;
+                CommandResponse::Shutdown
+            }
+            CommandRequest::Delay { delay } => {
+                self.delay = Some(Duration::from_millis(delay.into()));
+                CommandResponse::Delay
+            }
+            CommandRequest::TunnelConnect { host, port, .. } => {
+                let connect = async move {
+                    let target = format!("{}:{}", &*host, port);
+                    match TcpStream::connect((&*host, port)).await {
+                        Ok(stream) => Some(TaskOutput::Connected(request_id, target, stream)),
+                        Err(err) => {
+                            warn!("failed to connect to {}: {}", target, err);
+                            let response = CommandResponse::Error {
+                                status: CommandStatus::FAILURE,
+                                reason: err.to_string().into(),
+                            };
+                            Some(TaskOutput::Response(request_id, response))
+                        }
+                    }
+                };
+                self.tasks.push(connect.boxed_local());
+                return None;
+            }
+            CommandRequest::TunnelData { tunnel_id, data } => {
+                match self.tunnels.get_mut(&tunnel_id) {
+                    // Waits for a full tunnel, holding back the session.
+                    Some(data_tx) => {
+                        if data_tx.send(data).await.is_err() {
+                            // The tunnel has since closed.
+                            self.tunnels.remove(&tunnel_id);
+                        }
+                    }
+                    None => warn!("ignoring data for unknown tunnel {}", tunnel_id),
+                }
+                return None;
+            }
+            CommandRequest::TunnelClose { tunnel_id, reason } => {
+                if self.tunnels.remove(&tunnel_id).is_some() {
+                    info!("tunnel {} closed by server: {}", tunnel_id, &*reason);
+                }
+                return None;
+            }
+            CommandRequest::Error { status, reason } => {
+                warn!("server error (status: {:#06x}): {}", status.0, &*reason);
+                return None;
+            }
+        };
+        Some(response)
+    }
+
+    /// Returns the response to a request answered by a completed task.
+    fn handle_task_output(&mut self, output: TaskOutput) -> CommandPacket {
+        match output {
+            TaskOutput::Response(request_id, response) => {
+                CommandPacket::response(request_id, response)
+            }
+            TaskOutput::Connected(request_id, target, stream) => {
+                let tunnel_id = self.open_tunnel(stream);
+                info!("opened tunnel {} to {}", tunnel_id, target);
+                CommandPacket::response(request_id, CommandResponse::TunnelConnect { tunnel_id })
+            }
+        }
+    }
+
+    /// Spawns a process, attaching it to a new sub-session.
+    fn spawn_session(
+        &mut self,
+        name: String,
+        mut command: process::Command,
+    ) -> Result<SessionId, io::Error> {
+        let child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let session_id = rand::random();
+        let connect = self.connector.connect(session_id, name);
+        let attach = attach_process(session_id, connect, child).map(|()| None);
+        self.tasks.push(attach.boxed_local());
+        Ok(session_id)
+    }
+
+    /// Opens a tunnel relaying a connected stream.
+    ///
+    /// The relay is spawned, so it keeps taking data for the tunnel while
+    /// the command session waits for it to make room.
+    fn open_tunnel(&mut self, stream: TcpStream) -> TunnelId {
+        let tunnel_id = self.next_tunnel_id;
+        self.next_tunnel_id = self.next_tunnel_id.wrapping_add(1);
+        let (data_tx, data_rx) = mpsc::channel(TUNNEL_QUEUE_SIZE);
+        self.tunnels.insert(tunnel_id, data_tx);
+        let relay = relay_tunnel(tunnel_id, stream, data_rx, self.request_tx.clone());
+        tokio::spawn(async move {
+            if let Err(err) = relay.await {
+                warn!("tunnel {} error: {}", tunnel_id, err);
+            }
+            debug!("tunnel {} closed", tunnel_id);
+        });
+        tunnel_id
+    }
+}
+
+fn error_response(reason: String) -> CommandResponse {
+    warn!("{}", reason);
+    CommandResponse::Error {
+        status: CommandStatus::ERROR,
+        reason: reason.into(),
+    }
+}
+
+/// Builds a command running the system shell, or a command with it.
+fn shell_command(command: Option<&str>) -> process::Command {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd.exe", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut shell = process::Command::new(shell);
+    if let Some(command) = command {
+        shell.arg(flag).arg(command);
+    }
+    shell
+}
+
+/// Connects a sub-session and attaches it to the stdin and merged
+/// stdout/stderr of a process until either closes.
+async fn attach_process<F, S>(session_id: SessionId, connect: F, mut child: process::Child)
+where
+    F: Future<Output = Result<S, io::Error>>,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let session = match connect.await {
+        Ok(session) => session,
+        Err(err) => {
+            warn!("failed to connect session {}: {}", session_id, err);
+            return;
+        }
+    };
+
+    info!("attached process to session {}", session_id);
+
+    let mut stdin = child.stdin.take().expect("stdin piped");
+    let mut stdout = child.stdout.take().expect("stdout piped");
+    let mut stderr = child.stderr.take().expect("stderr piped");
+    let (mut session_read, mut session_write) = tokio::io::split(session);
+
+    let input_fut = tokio::io::copy(&mut session_read, &mut stdin);
+    let output_fut = async {
+        let mut out_buf = [0u8; 1024];
+        let mut err_buf = [0u8; 1024];
+        let (mut out_open, mut err_open) = (true, true);
+        loop {
+            let chunk = tokio::select! {
+                result = stdout.read(&mut out_buf[..]), if out_open => match result? {
+                    0 => { out_open = false; continue }
+                    len => &out_buf[..len],
+                },
+                result = stderr.read(&mut err_buf[..]), if err_open => match result? {
+                    0 => { err_open = false; continue }
+                    len => &err_buf[..len],
+                },
+                else => break,
+            };
+            session_write.write_all(chunk).await?;
+        }
+        session_write.shutdown().await
+    };
+
+    // If the session closes first, the process is killed when dropped.
+    let result = tokio::select! {
+        result = input_fut => result.map(drop),
+        result = output_fut => result,
+    };
+    if let Err(err) = result {
+        warn!("session {} error: {}", session_id, err);
+    }
+
+    info!("detached process from session {}", session_id);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use futures::future;
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    fn failing_connector() -> impl SessionConnector {
+        |_, _| future::ready(Err::<DuplexStream, _>(io::ErrorKind::Other.into()))
+    }
+
+    /// Handles a request, waiting for any task answering it.
+    async fn respond(
+        handler: &mut CommandHandler<impl SessionConnector>,
+        request: CommandRequest,
+    ) -> CommandPacket {
+        let packet = CommandPacket::request(1, request);
+        if let Some(response) = handler.handle_packet(packet).await {
+            return response;
+        }
+        let output = handler.tasks.next().await.flatten().expect("task output");
+        handler.handle_task_output(output)
+    }
+
+    async fn assert_response(
+        handler: &mut CommandHandler<impl SessionConnector>,
+        request: CommandRequest,
+        response: CommandResponse,
+    ) {
+        assert_eq!(
+            respond(handler, request).await,
+            CommandPacket::response(1, response)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_command_ping() {
+        let mut handler = CommandHandler::new(failing_connector());
+        assert_response(
+            &mut handler,
+            CommandRequest::Ping {
+                data: "hello".into(),
+            },
+            CommandResponse::Ping {
+                data: "hello".into(),
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_command_upload_download() {
+        let mut handler = CommandHandler::new(failing_connector());
+        let path = env::temp_dir().join(format!("dnscat-upload-{}", rand::random::<u32>()));
+        let filename = path.to_str().unwrap().to_string();
+        assert_response(
+            &mut handler,
+            CommandRequest::Upload {
+                filename: filename.clone().into(),
+                data: "hello!".into(),
+            },
+            CommandResponse::Upload,
+        )
+        .await;
+        assert_response(
+            &mut handler,
+            CommandRequest::Download {
+                filename: filename.into(),
+            },
+            CommandResponse::Download {
+                data: "hello!".into(),
+            },
+        )
+        .await;
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_command_download_missing() {
+        let mut handler = CommandHandler::new(failing_connector());
+        let request = CommandRequest::Download {
+            filename: "/dnscat/does/not/exist".into(),
+        };
+        let response = respond(&mut handler, request).await;
+        assert!(matches!(
+            response.body,
+            CommandBody::Response(CommandResponse::Error {
+                status: CommandStatus::ERROR,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_command_tunnel_connect_deferred() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut handler = CommandHandler::new(failing_connector());
+        let connect = CommandRequest::TunnelConnect {
+            options: 0,
+            host: "127.0.0.1".into(),
+            port,
+        };
+        // Answered once connected, without holding up other requests.
+        let packet = CommandPacket::request(2, connect);
+        assert_eq!(handler.handle_packet(packet).await, None);
+        assert_response(
+            &mut handler,
+            CommandRequest::Ping {
+                data: "hello".into(),
+            },
+            CommandResponse::Ping {
+                data: "hello".into(),
+            },
+        )
+        .await;
+        let output = handler.tasks.next().await.flatten().unwrap();
+        assert_eq!(
+            handler.handle_task_output(output),
+            CommandPacket::response(2, CommandResponse::TunnelConnect { tunnel_id: 0 })
+        );
+        assert!(handler.tunnels.contains_key(&0));
+    }
+
+    #[tokio::test]
+    async fn test_command_delay_and_shutdown() {
+        let mut handler = CommandHandler::new(failing_connector());
+        assert_response(
+            &mut handler,
+            CommandRequest::Delay { delay: 500 },
+            CommandResponse::Delay,
+        )
+        .await;
+        assert_eq!(handler.delay, Some(Duration::from_millis(500)));
+        assert_response(
+            &mut handler,
+            CommandRequest::Shutdown,
+            CommandResponse::Shutdown,
+        )
+        .await;
+        assert!(handler.shutdown);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_exec() {
+        let (mut local, remote) = tokio::io::duplex(1024);
+        let mut remote = Some(remote);
+        let mut handler = CommandHandler::new(move |_, name: String| {
+            assert_eq!(name, "test");
+            future::ready(Ok(remote.take().unwrap()))
+        });
+        let response = handler
+            .handle_request(
+                1,
+                CommandRequest::Exec {
+                    name: "test".into(),
+                    command: "echo hello; echo world >&2".into(),
+                },
+            )
+            .await;
+        assert!(matches!(response, Some(CommandResponse::Exec { .. })));
+        while handler.tasks.next().await.is_some() {}
+        let mut output = String::new();
+        local.read_to_string(&mut output).await.unwrap();
+        let mut lines: Vec<_> = output.lines().collect();
+        lines.sort_unstable();
+        assert_eq!(lines, ["hello", "world"]);
+    }
+}
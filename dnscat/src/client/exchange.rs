@@ -1,14 +1,14 @@
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
 use bytes::Bytes;
 use futures::ready;
-use futures_timer::Delay;
 use log::{trace, warn};
 use rand::Rng;
 
+use crate::clock::Delay;
 use crate::encryption::Encryption;
 use crate::packet::{LazyPacket, Packet, SessionBodyBytes};
 use crate::session::Session;
@@ -21,6 +21,8 @@ pub(super) struct Exchange {
     delay: Option<Delay>,
     packet: Packet<SessionBodyBytes>,
     transmit: bool,
+    /// Whether the packet is retransmitted once the delay has passed.
+    retransmit: bool,
 }
 
 impl Exchange {
@@ -36,6 +38,7 @@ impl Exchange {
         Self {
             packet,
             transmit: true,
+            retransmit: false,
             delay: transmit_delay(options, session),
         }
     }
@@ -52,13 +55,12 @@ impl Exchange {
         E: Encryption,
         R: Rng,
     {
-        let exchange_attempt = session.exchange_attempt().expect("should be exchanging");
-
         if let Some(ref mut delay_fut) = self.delay {
             ready!(Pin::new(delay_fut).poll(cx));
             self.delay = None;
-            if exchange_attempt > 1 {
+            if self.retransmit {
                 trace!("preparing retransmit");
+                self.retransmit = false;
                 session.prepare_retransmit(&mut self.packet)?;
             }
         }
@@ -91,21 +93,23 @@ impl Exchange {
                 return Poll::Ready(Err(err));
             }
             Err(err) => {
+                let exchange_attempt = session.exchange_attempt().expect("should be exchanging");
                 let delay_dur = retransmit_delay(options, session, exchange_attempt);
                 warn!(
                     "retrying exchange after {} secs after {}",
                     delay_dur.as_secs(),
                     err
                 );
-                self.delay = Some(Delay::new(delay_dur));
+                self.delay = Some(session.clock().delay(delay_dur));
                 self.transmit = true;
+                self.retransmit = true;
                 return self.poll(cx, session, transport, options);
             }
         }
     }
 }
 
-fn retransmit_delay<E, R>(
+pub(super) fn retransmit_delay<E, R>(
     opts: &ClientOpts,
     session: &mut Session<E, R>,
     attempt: usize,
@@ -130,7 +134,7 @@ where
 {
     let dur_since_last = session
         .last_exchange()
-        .map(|last| Instant::now().duration_since(last))
+        .map(|last| session.clock().now().duration_since(last))
         .unwrap_or(Duration::from_secs(0));
 
     let dur = if opts.random_delay {
@@ -144,5 +148,5 @@ where
         None
     };
 
-    dur.map(Delay::new)
+    dur.map(|dur| session.clock().delay(dur))
 }
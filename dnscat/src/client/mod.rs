@@ -1,5 +1,10 @@
 mod builder;
+#[cfg(feature = "client-command")]
+mod command;
 mod exchange;
+mod ping;
+mod probe;
+mod window;
 
 use std::collections::VecDeque;
 use std::future::Future;
@@ -12,19 +17,27 @@ use bytes::Bytes;
 use failure::Fail;
 use futures::io::{AsyncRead, AsyncWrite};
 use futures::{future, ready};
-use futures_timer::Delay;
 use log::{debug, warn};
 use rand::prelude::{Rng, ThreadRng};
 use tokio::io::{AsyncRead as TokioAsyncRead, AsyncWrite as TokioAsyncWrite, ReadBuf};
 
+use crate::clock::Delay;
 use crate::encryption::Encryption;
+#[cfg(feature = "persist")]
+use crate::encryption::EncryptionState;
 use crate::packet::{LazyPacket, Packet, PacketKind, SessionBodyBytes};
+#[cfg(feature = "persist")]
+use crate::session::SessionState;
 use crate::session::{Session, SessionError};
 use crate::transport::Transport;
 
 use self::exchange::Exchange;
+use self::window::Window;
 
 pub use self::builder::ClientBuilder;
+#[cfg(feature = "client-command")]
+pub use self::command::{CommandDriver, SessionConnector};
+pub use self::ping::Pong;
 
 #[derive(Debug, Fail)]
 pub enum ClientError<T: Fail> {
@@ -34,6 +47,12 @@ pub enum ClientError<T: Fail> {
     Session(SessionError),
     #[fail(display = "Unexpected packet kind `{:?}`", _0)]
     UnexpectedKind(PacketKind),
+    #[fail(display = "No datagram probed survived the transport")]
+    ProbeFailed,
+    #[fail(display = "No echo of the ping received")]
+    PingTimeout,
+    #[fail(display = "Ping data too large for the transport")]
+    PingTooLarge,
 }
 
 impl<T: Fail> From<SessionError> for ClientError<T> {
@@ -57,6 +76,7 @@ struct ClientOpts {
     max_delay: Duration,
     random_delay: bool,
     retransmit_backoff: bool,
+    window_size: usize,
 }
 
 #[derive(Debug)]
@@ -68,6 +88,7 @@ where
     session: Session<E, R>,
     options: ClientOpts,
     exchange: Option<Exchange>,
+    window: Option<Window>,
     poll_delay: Option<Delay>,
     send_buf: Bytes,
     recv_buf: Bytes,
@@ -81,10 +102,39 @@ where
     E: Encryption,
     R: Rng,
 {
+    fn new(
+        transport: T,
+        session: Session<E, R>,
+        options: ClientOpts,
+        recv_queue_size: usize,
+    ) -> Self {
+        Self {
+            session,
+            options,
+            transport,
+            exchange: None,
+            window: None,
+            send_task: None,
+            poll_delay: None,
+            send_buf: Bytes::new(),
+            recv_queue: VecDeque::with_capacity(recv_queue_size),
+            recv_buf: Bytes::new(),
+        }
+    }
+
     pub fn session(&self) -> &Session<E, R> {
         &self.session
     }
 
+    /// Set the maximum delay before polling the server for data.
+    ///
+    /// The minimum delay is lowered if it is greater than the new maximum.
+    pub fn set_max_delay(&mut self, duration: Duration) {
+        self.options.max_delay = duration;
+        self.options.min_delay = cmp::min(self.options.min_delay, duration);
+        self.poll_delay = None;
+    }
+
     async fn handshake(mut self) -> Result<Self, ClientError<T::Error>> {
         debug!("starting client handshake");
 
@@ -97,6 +147,23 @@ where
         let packet = self.session.build_syn()?;
         self.basic_exchange(packet).await?;
 
+        if self.session.is_windowed() {
+            debug!("using window of {} requests", self.options.window_size);
+            self.window = Some(Window::new(self.options.window_size));
+        }
+
+        Ok(self)
+    }
+
+    /// Checks the server still knows a resumed session by exchanging an
+    /// empty chunk.
+    #[cfg(feature = "persist")]
+    async fn resume(mut self) -> Result<Self, ClientError<T::Error>> {
+        debug!("resuming session {}", self.session.id());
+
+        let packet = self.session.build_msg(Bytes::new())?;
+        self.basic_exchange(packet).await?;
+
         Ok(self)
     }
 
@@ -161,7 +228,7 @@ where
             debug!("sending empty chunk");
             Bytes::new()
         } else {
-            let budget = self.transport.max_datagram_size();
+            let budget = self.transport.max_send_size();
             let chunk_len = self.session.calc_chunk_len(self.send_buf.len(), budget);
             self.send_buf.split_to(chunk_len as usize)
         };
@@ -198,12 +265,15 @@ where
         if self.recv_buf.is_empty() {
             self.recv_buf = ready!(self.do_poll_recv(cx))?;
         }
-        let len = cmp::min(buf.capacity(), self.recv_buf.len());
-        buf.put_slice(&self.recv_buf[..len]);
-        Poll::Ready(Ok(buf.filled().len()))
+        let len = cmp::min(buf.remaining(), self.recv_buf.len());
+        buf.put_slice(&self.recv_buf.split_to(len)[..]);
+        Poll::Ready(Ok(len))
     }
 
     fn do_poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<Bytes, ClientError<T::Error>>> {
+        if self.window.is_some() {
+            return self.poll_window_recv(cx);
+        }
         // First see if we have anything in the recv queue.
         if let Some(chunk) = self.recv_queue_pop() {
             return Poll::Ready(Ok(chunk));
@@ -219,7 +289,7 @@ where
         // There is no exchange currently running so we set a delay
         // to send an empty chunk to poke the server.
         if self.poll_delay.is_none() {
-            self.poll_delay = Some(Delay::new(self.options.max_delay));
+            self.poll_delay = Some(self.session.clock().delay(self.options.max_delay));
         }
         // We poll the delay to see if we should send an empty chunk.
         let poll_delay = self.poll_delay.as_mut().expect("expected delay");
@@ -242,6 +312,9 @@ where
             self.send_task = Some(cx.waker().clone());
             return Poll::Pending;
         }
+        if self.window.is_some() {
+            return self.poll_window_write(cx, buf);
+        }
         // Flush the current send buffer out.
         ready!(self.do_poll_flush(cx))?;
         // Push the data into the send buffer.
@@ -253,6 +326,9 @@ where
     }
 
     fn do_poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ClientError<T::Error>>> {
+        if self.window.is_some() {
+            return self.poll_window_flush(cx);
+        }
         // If there is an exchange already happening we poll
         // it to completion.
         if self.exchange.is_some() {
@@ -269,17 +345,31 @@ where
     }
 
     fn do_poll_close(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ClientError<T::Error>>> {
-        if self.exchange.is_none() && self.session.is_closed() {
+        if self.session.is_closed() {
+            // If we are closed with an exchange, it is our `FIN` to which the
+            // peer should respond with its own, closing the session.
+            if self.exchange.is_some() {
+                match ready!(self.poll_exchange(cx)) {
+                    Ok(_) | Err(ClientError::Session(SessionError::Closed)) => (),
+                    Err(err) => warn!("ignored error while closing {}", err),
+                }
+            }
             return Poll::Ready(Ok(()));
         }
         // If we get any errors while closing, just ignore them.
-        if let Err(err) = ready!(self.do_poll_flush(cx)) {
+        if self.window.is_some() {
+            // The `FIN` is exchanged alone once all requests are answered.
+            if let Err(err) = ready!(self.poll_window_drain(cx)) {
+                warn!("ignored error while closing {}", err);
+            }
+            self.window = None;
+        } else if let Err(err) = ready!(self.do_poll_flush(cx)) {
             warn!("ignored error while closing {}", err);
         }
         match self.session.build_fin("") {
             Ok(packet) => {
                 self.start_exchange(packet);
-                self.poll_exchange(cx).map_ok(drop)
+                self.do_poll_close(cx)
             }
             Err(err) => {
                 warn!("ignored error while closing {}", err);
@@ -289,6 +379,21 @@ where
     }
 }
 
+#[cfg(feature = "persist")]
+impl<T, E, R> Client<T, E, R>
+where
+    T: Transport<LazyPacket>,
+    E: EncryptionState,
+    R: Rng,
+{
+    /// Saves the state of the session so it can be resumed later.
+    ///
+    /// See [`Session::save_state`].
+    pub fn save_state(&mut self) -> Result<SessionState, SessionError> {
+        self.session.save_state()
+    }
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 
 impl<T, E, R> AsyncRead for Client<T, E, R>
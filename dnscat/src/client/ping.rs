@@ -0,0 +1,79 @@
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::{future, ready, FutureExt};
+use log::debug;
+use rand::Rng;
+
+use crate::clock::Clock;
+use crate::packet::{LazyPacket, Packet, PingBody, PingHeader, SupportedBody, SupportedHeader};
+use crate::transport::Transport;
+
+use super::ClientError;
+
+/// The echo of a `PING` sent to a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pong {
+    /// The data echoed, which should match the data sent.
+    pub data: String,
+    /// The time between sending the `PING` and receiving its echo.
+    pub rtt: Duration,
+}
+
+/// Sends a `PING` with the given data, waiting up to a timeout for its echo.
+///
+/// Echoes of earlier pings received in the meantime are skipped, as are
+/// transport errors, which may be from the exchange of an earlier request
+/// still in flight rather than this one.
+pub(super) async fn ping<T, R>(
+    transport: &mut T,
+    random: &mut R,
+    clock: &dyn Clock,
+    data: String,
+    timeout: Duration,
+) -> Result<Pong, ClientError<T::Error>>
+where
+    T: Transport<LazyPacket>,
+    R: Rng,
+{
+    let ping_id = random.gen();
+    let packet = ping_packet(random.gen(), ping_id, data);
+    let sent = clock.now();
+    future::poll_fn(|cx| transport.poll_send(cx, packet.clone()))
+        .await
+        .map_err(ClientError::Transport)?;
+    let mut delay = clock.delay(timeout);
+    future::poll_fn(|cx| loop {
+        if delay.poll_unpin(cx).is_ready() {
+            return Poll::Ready(Err(ClientError::PingTimeout));
+        }
+        let echo = match ready!(transport.poll_recv(cx)) {
+            Ok(echo) => echo,
+            Err(err) => {
+                debug!("ignoring transport error awaiting echo: {}", err);
+                continue;
+            }
+        };
+        let kind = echo.kind();
+        match echo.into_ping() {
+            Some(echo) if echo.head.ping_id == ping_id => {
+                return Poll::Ready(Ok(Pong {
+                    data: echo.body.data().to_owned(),
+                    rtt: clock.now().duration_since(sent),
+                }));
+            }
+            Some(_) => continue,
+            None => return Poll::Ready(Err(ClientError::UnexpectedKind(kind))),
+        }
+    })
+    .await
+}
+
+fn ping_packet(packet_id: u16, ping_id: u16, data: String) -> LazyPacket {
+    let mut body = PingBody::new();
+    body.set_data(data);
+    Packet::new(
+        SupportedHeader::Ping(PingHeader::new(packet_id, ping_id)),
+        SupportedBody::Ping(body),
+    )
+}
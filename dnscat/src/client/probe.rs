@@ -0,0 +1,89 @@
+use std::cmp;
+use std::time::Duration;
+
+use log::debug;
+use rand::Rng;
+
+use crate::clock::Clock;
+use crate::packet::{LazyPacket, PingBody};
+use crate::transport::Transport;
+
+use super::ping;
+
+/// The number of times a size is probed before it is assumed to not survive.
+const PROBE_ATTEMPTS: usize = 2;
+
+/// Probes the largest datagram between a min and max size that survives
+/// the transport, and whose echo survives the way back.
+///
+/// As a `PING` is echoed at the same size, this is the smaller of the
+/// largest request and the largest response that survive, the two not
+/// being measured apart.
+///
+/// Returns `None` if not even the min size survives.
+pub(crate) async fn max_datagram_size<T, R>(
+    transport: &mut T,
+    random: &mut R,
+    clock: &dyn Clock,
+    min: usize,
+    max: usize,
+    timeout: Duration,
+) -> Option<usize>
+where
+    T: Transport<LazyPacket>,
+    R: Rng,
+{
+    let min = cmp::max(min, PingBody::packet_size_no_data());
+    let max = cmp::min(max, LazyPacket::max_size() as usize);
+    if min > max {
+        return None;
+    }
+    if probe_size(transport, random, clock, max, timeout).await {
+        return Some(max);
+    }
+    if !probe_size(transport, random, clock, min, timeout).await {
+        return None;
+    }
+    // Binary search between the largest size known to survive and the
+    // smallest known not to.
+    let (mut good, mut bad) = (min, max);
+    while bad - good > 1 {
+        let size = good + (bad - good) / 2;
+        if probe_size(transport, random, clock, size, timeout).await {
+            good = size;
+        } else {
+            bad = size;
+        }
+    }
+    Some(good)
+}
+
+async fn probe_size<T, R>(
+    transport: &mut T,
+    random: &mut R,
+    clock: &dyn Clock,
+    size: usize,
+    timeout: Duration,
+) -> bool
+where
+    T: Transport<LazyPacket>,
+    R: Rng,
+{
+    for attempt in 1..=PROBE_ATTEMPTS {
+        let data = ping_data(size);
+        match ping::ping(transport, random, clock, data.clone(), timeout).await {
+            Ok(pong) if pong.data == data => return true,
+            Ok(_) => debug!("probe of {} echoed corrupted", size),
+            Err(err) => debug!("probe of {} failed: {}", size, err),
+        }
+        debug!("probe of {} not echoed (attempt {})", size, attempt);
+    }
+    false
+}
+
+/// Builds the data of a `PING` packet encoding to the given size.
+fn ping_data(size: usize) -> String {
+    let data_len = size - PingBody::packet_size_no_data();
+    let data = (b'a'..=b'z').cycle().take(data_len).map(char::from);
+    data.collect()
+}
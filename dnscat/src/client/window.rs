@@ -0,0 +1,316 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::ready;
+use log::{debug, warn};
+use rand::Rng;
+
+use crate::clock::Delay;
+use crate::encryption::Encryption;
+use crate::packet::{LazyPacket, Packet, Sequence, SessionBodyBytes};
+use crate::session::SessionError;
+use crate::transport::Transport;
+
+use super::exchange::retransmit_delay;
+use super::{Client, ClientError};
+
+/// State for a windowed session, with several `MSG` packets in flight.
+///
+/// Data is sent in new requests while the window allows, with the peer
+/// acknowledging it cumulatively in its responses. Responses only carry
+/// the oldest data the peer has yet to have acknowledged, so data from
+/// the peer still arrives one chunk per round trip.
+///
+/// If a request is lost, or all requests are answered while data is still
+/// unacknowledged, all unacknowledged data is sent again.
+#[derive(Debug)]
+pub(super) struct Window {
+    /// The max number of requests in flight.
+    size: usize,
+    /// The number of requests sent without a response yet.
+    in_flight: usize,
+    /// Data sent and not yet acknowledged by the peer, oldest first.
+    unacked: VecDeque<(Sequence, Bytes)>,
+    /// Unacknowledged data to send again.
+    resend: VecDeque<(Sequence, Bytes)>,
+    /// A packet built that the transport was not ready to send.
+    queued: Option<Packet<SessionBodyBytes>>,
+    /// Set when the peer should be polled with an empty chunk.
+    poll_peer: bool,
+    /// The delay before sending data again.
+    delay: Option<Delay>,
+    /// The number of times data was sent again without progress.
+    attempt: usize,
+}
+
+impl Window {
+    pub(super) fn new(size: usize) -> Self {
+        Self {
+            size,
+            in_flight: 0,
+            unacked: VecDeque::with_capacity(size),
+            resend: VecDeque::new(),
+            queued: None,
+            poll_peer: false,
+            delay: None,
+            attempt: 0,
+        }
+    }
+
+    /// Returns `true` if there are no requests in flight or to be sent.
+    pub(super) fn is_idle(&self) -> bool {
+        self.is_acknowledged() && self.in_flight == 0 && !self.poll_peer
+    }
+
+    /// Returns `true` if all data sent has been acknowledged.
+    pub(super) fn is_acknowledged(&self) -> bool {
+        self.unacked.is_empty() && self.queued.is_none()
+    }
+
+    /// Polls the peer with an empty chunk once no requests are in flight.
+    pub(super) fn poll_peer(&mut self) {
+        self.poll_peer = true;
+    }
+
+    /// Drops data acknowledged by the peer, returning `true` if any was.
+    fn acknowledge(&mut self, ack: Sequence) -> bool {
+        let mut progress = false;
+        while let Some((seq, chunk)) = self.unacked.front() {
+            if (seq.steps_to(ack) as usize) < chunk.len() {
+                break;
+            }
+            self.unacked.pop_front();
+            progress = true;
+        }
+        if progress {
+            let unacked = &self.unacked;
+            self.resend
+                .retain(|(seq, _)| unacked.iter().any(|(unacked, _)| unacked == seq));
+        }
+        progress
+    }
+}
+
+impl<T, E, R> Client<T, E, R>
+where
+    T: Transport<LazyPacket>,
+    E: Encryption,
+    R: Rng,
+{
+    /// Receives responses and sends requests as the window allows.
+    ///
+    /// Returns `Poll::Ready(Ok(()))` if any progress was made.
+    pub(super) fn poll_window(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), ClientError<T::Error>>> {
+        let mut progress = false;
+        // Receive the responses to requests in flight.
+        while self.window().in_flight > 0 {
+            let result = match self.transport.poll_recv(cx) {
+                Poll::Pending => break,
+                Poll::Ready(result) => result,
+            };
+            self.window().in_flight -= 1;
+            progress = true;
+            let result = result.map_err(ClientError::Transport).and_then(|packet| {
+                match (packet.kind(), packet.into_session()) {
+                    (_, Some(packet)) => self.session.handle_inbound(packet).map_err(Into::into),
+                    (kind, None) => Err(ClientError::UnexpectedKind(kind)),
+                }
+            });
+            match result {
+                Ok(chunk_opt) => {
+                    let ack = self.session.self_seq;
+                    if self.window().acknowledge(ack) || chunk_opt.is_some() {
+                        self.window().attempt = 0;
+                    }
+                    if let Some(chunk) = chunk_opt {
+                        self.recv_queue_push(chunk);
+                    }
+                }
+                Err(err) if self.session.is_closed() => return Poll::Ready(Err(err)),
+                Err(err) => {
+                    warn!("windowed request failed: {}", err);
+                    self.window_go_back()?;
+                }
+            }
+        }
+        // If every request was answered and data is still unacknowledged,
+        // the peer must have missed some of it.
+        let window = self.window();
+        if window.in_flight == 0
+            && window.queued.is_none()
+            && window.resend.is_empty()
+            && !window.unacked.is_empty()
+        {
+            self.window_go_back()?;
+        }
+        // Wait before sending data again.
+        if let Some(ref mut delay) = self.window().delay {
+            if Pin::new(delay).poll(cx).is_pending() {
+                return if progress {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Pending
+                };
+            }
+            let window = self.window();
+            window.delay = None;
+            window.resend = window.unacked.clone();
+            if window.resend.is_empty() {
+                window.poll_peer = true;
+            }
+        }
+        // Send requests while the window and the receive queue allow.
+        loop {
+            let window = self.window.as_ref().expect("window");
+            if window.in_flight >= window.size
+                || self.recv_queue.len() + window.in_flight >= self.recv_queue.capacity()
+            {
+                break;
+            }
+            let packet = match self.window().queued.take() {
+                Some(packet) => packet,
+                None => match self.next_window_packet()? {
+                    Some(packet) => packet,
+                    None => break,
+                },
+            };
+            match self.transport.poll_send(cx, packet.clone().translate()) {
+                Poll::Pending => {
+                    self.window().queued = Some(packet);
+                    break;
+                }
+                Poll::Ready(Ok(())) => {
+                    self.window().in_flight += 1;
+                    progress = true;
+                }
+                Poll::Ready(Err(err)) => {
+                    warn!("windowed request failed: {}", err);
+                    self.window_go_back()?;
+                    progress = true;
+                    break;
+                }
+            }
+        }
+        if progress {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    pub(super) fn poll_window_recv(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Bytes, ClientError<T::Error>>> {
+        loop {
+            if let Some(chunk) = self.recv_queue_pop() {
+                return Poll::Ready(Ok(chunk));
+            }
+            // Nothing is being exchanged, so we set a delay to send an
+            // empty chunk to poke the server.
+            if self.send_buf.is_empty() && self.window().is_idle() {
+                if self.poll_delay.is_none() {
+                    self.poll_delay = Some(self.session.clock().delay(self.options.max_delay));
+                }
+                let poll_delay = self.poll_delay.as_mut().expect("expected delay");
+                ready!(Pin::new(poll_delay).poll(cx));
+                self.poll_delay = None;
+                self.window().poll_peer();
+            }
+            ready!(self.poll_window(cx))?;
+        }
+    }
+
+    pub(super) fn poll_window_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, ClientError<T::Error>>> {
+        // Wait for the last write to be sent.
+        while !self.send_buf.is_empty() {
+            ready!(self.poll_window(cx))?;
+        }
+        self.send_buf = buf.to_vec().into();
+        // Start sending, which continues as the client is polled.
+        if let Poll::Ready(Err(err)) = self.poll_window(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    pub(super) fn poll_window_flush(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), ClientError<T::Error>>> {
+        while !self.send_buf.is_empty() || !self.window().is_acknowledged() {
+            ready!(self.poll_window(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Flushes and waits for all requests in flight to be answered.
+    pub(super) fn poll_window_drain(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), ClientError<T::Error>>> {
+        ready!(self.poll_window_flush(cx))?;
+        while self.window().in_flight > 0 {
+            ready!(self.poll_window(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn next_window_packet(
+        &mut self,
+    ) -> Result<Option<Packet<SessionBodyBytes>>, ClientError<T::Error>> {
+        let window = self.window.as_mut().expect("window");
+        if let Some((seq, chunk)) = window.resend.pop_front() {
+            debug!("sending data again from {}", seq);
+            return Ok(Some(self.session.build_msg_at(seq, chunk)?));
+        }
+        if !self.send_buf.is_empty() && window.unacked.len() < window.size {
+            let budget = self.transport.max_send_size();
+            let chunk_len = self.session.calc_chunk_len(self.send_buf.len(), budget);
+            let chunk = self.send_buf.split_to(chunk_len as usize);
+            let seq = self.session.self_seq_pending;
+            window.unacked.push_back((seq, chunk.clone()));
+            return Ok(Some(self.session.build_msg(chunk)?));
+        }
+        if window.poll_peer && window.in_flight == 0 {
+            debug!("sending empty chunk");
+            window.poll_peer = false;
+            return Ok(Some(self.session.build_msg(Bytes::new())?));
+        }
+        Ok(None)
+    }
+
+    /// Sends all unacknowledged data again after a delay.
+    fn window_go_back(&mut self) -> Result<(), ClientError<T::Error>> {
+        if self.window().delay.is_some() {
+            return Ok(());
+        }
+        let attempt = self.window().attempt + 1;
+        if let Some(max_exchange_attempts) = self.session.max_exchange_attempts {
+            if attempt > max_exchange_attempts {
+                return Err(SessionError::MaxTransmitAttempts.into());
+            }
+        }
+        let delay_dur = retransmit_delay(&self.options, &mut self.session, attempt);
+        let delay = self.session.clock().delay(delay_dur);
+        let window = self.window();
+        window.attempt = attempt;
+        window.resend.clear();
+        window.delay = Some(delay);
+        Ok(())
+    }
+
+    fn window(&mut self) -> &mut Window {
+        self.window.as_mut().expect("session not windowed")
+    }
+}
@@ -0,0 +1,61 @@
+mod sim;
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+pub use self::sim::{SimClock, Simulation};
+
+/// A source of the current time, and of delays measured against it.
+///
+/// Sessions and transports take their time from a clock, so that they can
+/// be driven under virtual time by a [`Simulation`].
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current instant.
+    fn now(&self) -> Instant;
+
+    /// Returns a future completing once a duration has passed.
+    fn delay(&self, duration: Duration) -> Delay;
+}
+
+/// The system clock, with delays driven by a timer thread.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn delay(&self, duration: Duration) -> Delay {
+        Delay::new(futures_timer::Delay::new(duration))
+    }
+}
+
+/// A future completing once a delay has passed on a clock.
+pub struct Delay(Pin<Box<dyn Future<Output = ()> + Send>>);
+
+impl Delay {
+    pub fn new<F>(future: F) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Self(Box::pin(future))
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+impl fmt::Debug for Delay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Delay").finish()
+    }
+}
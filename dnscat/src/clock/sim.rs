@@ -0,0 +1,194 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
+
+use futures::pin_mut;
+
+use super::{Clock, Delay};
+
+/// A virtual clock, which only moves forward when advanced.
+///
+/// Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct SimClock {
+    state: Arc<Mutex<SimState>>,
+}
+
+#[derive(Debug)]
+struct SimState {
+    start: Instant,
+    elapsed: Duration,
+    timers: Vec<(Duration, Waker)>,
+}
+
+impl SimClock {
+    pub fn new() -> Self {
+        let state = SimState {
+            start: Instant::now(),
+            elapsed: Duration::from_secs(0),
+            timers: Vec::new(),
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Returns the virtual time passed since the clock was constructed.
+    pub fn elapsed(&self) -> Duration {
+        self.state().elapsed
+    }
+
+    /// Advance the clock to the earliest deadline of the delays pending,
+    /// waking them.
+    ///
+    /// Returns `false` if no delays are pending.
+    fn advance_to_next(&self) -> bool {
+        let mut state = self.state();
+        let next = match state.timers.iter().map(|(deadline, _)| *deadline).min() {
+            Some(next) => next,
+            None => return false,
+        };
+        if next > state.elapsed {
+            state.elapsed = next;
+        }
+        let elapsed = state.elapsed;
+        let (expired, pending) = state
+            .timers
+            .drain(..)
+            .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= elapsed);
+        state.timers = pending;
+        drop(state);
+        for (_, waker) in expired {
+            waker.wake();
+        }
+        true
+    }
+
+    fn state(&self) -> MutexGuard<'_, SimState> {
+        self.state.lock().expect("sim clock poisoned")
+    }
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Instant {
+        let state = self.state();
+        state.start + state.elapsed
+    }
+
+    fn delay(&self, duration: Duration) -> Delay {
+        let deadline = self.elapsed() + duration;
+        Delay::new(SimDelay {
+            clock: self.clone(),
+            deadline,
+        })
+    }
+}
+
+struct SimDelay {
+    clock: SimClock,
+    deadline: Duration,
+}
+
+impl Future for SimDelay {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.clock.state();
+        if state.elapsed >= self.deadline {
+            return Poll::Ready(());
+        }
+        state.timers.push((self.deadline, cx.waker().clone()));
+        Poll::Pending
+    }
+}
+
+/// Drives futures to completion under the virtual time of a [`SimClock`].
+///
+/// Whenever the future is blocked, the clock jumps to the next deadline of
+/// the delays pending, so timeouts and backoffs pass instantly. Given the
+/// same seeded random number generators, a run is reproducible.
+#[derive(Debug, Default)]
+pub struct Simulation {
+    clock: SimClock,
+}
+
+impl Simulation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the clock the simulation is driven by, to be given to the
+    /// sessions and transports simulated.
+    pub fn clock(&self) -> SimClock {
+        self.clock.clone()
+    }
+
+    /// Run a future to completion, returning its output.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the future is blocked without any delays pending, as it
+    /// would never complete.
+    pub fn run<F: Future>(&self, future: F) -> F::Output {
+        let woken = Arc::new(Woken(AtomicBool::new(true)));
+        let waker = Waker::from(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        pin_mut!(future);
+        loop {
+            if woken.0.swap(false, Ordering::SeqCst) {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+            } else if !self.clock.advance_to_next() {
+                panic!(
+                    "simulation stalled at {:?} without delays pending",
+                    self.clock.elapsed()
+                );
+            }
+        }
+    }
+}
+
+struct Woken(AtomicBool);
+
+impl Wake for Woken {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+
+    use super::*;
+
+    #[test]
+    fn test_simulation_delays() {
+        let sim = Simulation::new();
+        let clock = sim.clock();
+        let start = clock.now();
+        let delays = future::join(
+            clock.delay(Duration::from_secs(5)),
+            clock.delay(Duration::from_secs(60)),
+        );
+        sim.run(delays);
+        assert_eq!(clock.elapsed(), Duration::from_secs(60));
+        assert_eq!(clock.now() - start, Duration::from_secs(60));
+    }
+
+    #[test]
+    #[should_panic(expected = "simulation stalled")]
+    fn test_simulation_stalled() {
+        Simulation::new().run(future::pending::<()>());
+    }
+}
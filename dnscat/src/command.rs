@@ -0,0 +1,802 @@
+//! Command protocol support, carried within the data of a command session.
+
+use std::str::Utf8Error;
+use std::{fmt, mem};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use failure::Fail;
+
+use crate::packet::SessionId;
+use crate::util::parse::{self, Needed, NoNullTermError};
+use crate::util::{Decode, Encode, StringBytes};
+
+/// Command request ID (`u15`).
+pub type RequestId = u16;
+
+/// Tunnel ID (`u32`).
+pub type TunnelId = u32;
+
+/// The bit set in a packed ID if the packet is a response.
+pub(crate) const RESPONSE_BIT: u16 = 0x8000;
+
+/// The max number of chunks queued for or from a tunnel before the
+/// relay waits for them to be taken.
+#[cfg(any(feature = "server", feature = "client-command"))]
+pub(crate) const TUNNEL_QUEUE_SIZE: usize = 16;
+
+/// The max length of a packet after its length prefix, above which it is
+/// rejected rather than buffered.
+pub const MAX_PACKET_LEN: usize = 16 * 1024 * 1024;
+
+/// A command packet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandPacket {
+    pub request_id: RequestId,
+    pub body: CommandBody,
+}
+
+impl CommandPacket {
+    /// Constructs a new command request packet.
+    pub fn request(request_id: RequestId, request: CommandRequest) -> Self {
+        Self::new(request_id, CommandBody::Request(request))
+    }
+
+    /// Constructs a new command response packet.
+    pub fn response(request_id: RequestId, response: CommandResponse) -> Self {
+        Self::new(request_id, CommandBody::Response(response))
+    }
+
+    fn new(request_id: RequestId, body: CommandBody) -> Self {
+        assert_eq!(request_id & RESPONSE_BIT, 0, "request ID out of range");
+        Self { request_id, body }
+    }
+
+    /// Retrives the command kind.
+    pub fn kind(&self) -> CommandKind {
+        self.body.kind()
+    }
+
+    /// Returns `true` if the packet is a response.
+    pub fn is_response(&self) -> bool {
+        matches!(self.body, CommandBody::Response(_))
+    }
+
+    /// Decodes a packet from the front of a stream buffer.
+    ///
+    /// Returns `None` if the buffer does not yet contain a complete packet.
+    pub fn decode_buf(buf: &mut BytesMut) -> Result<Option<Self>, CommandDecodeError> {
+        if buf.len() < mem::size_of::<u32>() {
+            return Ok(None);
+        }
+        let len = (&buf[..]).get_u32() as usize;
+        if len > MAX_PACKET_LEN {
+            return Err(CommandDecodeError::TooLarge(len));
+        }
+        let len = len + mem::size_of::<u32>();
+        if buf.len() < len {
+            return Ok(None);
+        }
+        let mut bytes = buf.split_to(len).freeze();
+        Self::decode(&mut bytes).map(Some)
+    }
+}
+
+impl Encode for CommandPacket {
+    fn encode<B: BufMut + ?Sized>(&self, b: &mut B) {
+        let mut packed_id = self.request_id;
+        if self.is_response() {
+            packed_id |= RESPONSE_BIT;
+        }
+        let mut inner = BytesMut::new();
+        inner.put_u16(packed_id);
+        inner.put_u16(self.kind().into());
+        self.body.encode(&mut inner);
+        b.put_u32(inner.len() as u32);
+        b.put_slice(&inner[..]);
+    }
+}
+
+impl Decode for CommandPacket {
+    type Error = CommandDecodeError;
+
+    fn decode(b: &mut Bytes) -> Result<Self, Self::Error> {
+        let len = parse::be_u32(b)? as usize;
+        if len > MAX_PACKET_LEN {
+            return Err(CommandDecodeError::TooLarge(len));
+        }
+        let mut b = parse::split_to(b, len)?;
+        let packed_id = parse::be_u16(&mut b)?;
+        let kind = CommandKind::from_code(parse::be_u16(&mut b)?)?;
+        let body = if packed_id & RESPONSE_BIT == 0 {
+            CommandRequest::decode_kind(kind, &mut b).map(CommandBody::Request)?
+        } else {
+            CommandResponse::decode_kind(kind, &mut b).map(CommandBody::Response)?
+        };
+        if !b.is_empty() {
+            return Err(CommandDecodeError::Overflow(b.len()));
+        }
+        Ok(Self {
+            request_id: packed_id & !RESPONSE_BIT,
+            body,
+        })
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Command Body
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandBody {
+    Request(CommandRequest),
+    Response(CommandResponse),
+}
+
+impl CommandBody {
+    /// Retrives the command kind.
+    pub fn kind(&self) -> CommandKind {
+        match self {
+            Self::Request(request) => request.kind(),
+            Self::Response(response) => response.kind(),
+        }
+    }
+}
+
+impl Encode for CommandBody {
+    fn encode<B: BufMut + ?Sized>(&self, b: &mut B) {
+        match self {
+            Self::Request(request) => request.encode(b),
+            Self::Response(response) => response.encode(b),
+        }
+    }
+}
+
+/// A command request.
+///
+/// `TUNNEL_DATA`, `TUNNEL_CLOSE` and `ERROR` may be sent by either peer,
+/// without expecting a response.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandRequest {
+    Ping {
+        data: StringBytes,
+    },
+    Shell {
+        name: StringBytes,
+    },
+    Exec {
+        name: StringBytes,
+        command: StringBytes,
+    },
+    Download {
+        filename: StringBytes,
+    },
+    Upload {
+        filename: StringBytes,
+        data: Bytes,
+    },
+    Shutdown,
+    Delay {
+        delay: u32,
+    },
+    TunnelConnect {
+        options: u32,
+        host: StringBytes,
+        port: u16,
+    },
+    TunnelData {
+        tunnel_id: TunnelId,
+        data: Bytes,
+    },
+    TunnelClose {
+        tunnel_id: TunnelId,
+        reason: StringBytes,
+    },
+    Error {
+        status: CommandStatus,
+        reason: StringBytes,
+    },
+}
+
+impl CommandRequest {
+    /// Retrives the command kind.
+    pub fn kind(&self) -> CommandKind {
+        match self {
+            Self::Ping { .. } => CommandKind::PING,
+            Self::Shell { .. } => CommandKind::SHELL,
+            Self::Exec { .. } => CommandKind::EXEC,
+            Self::Download { .. } => CommandKind::DOWNLOAD,
+            Self::Upload { .. } => CommandKind::UPLOAD,
+            Self::Shutdown => CommandKind::SHUTDOWN,
+            Self::Delay { .. } => CommandKind::DELAY,
+            Self::TunnelConnect { .. } => CommandKind::TUNNEL_CONNECT,
+            Self::TunnelData { .. } => CommandKind::TUNNEL_DATA,
+            Self::TunnelClose { .. } => CommandKind::TUNNEL_CLOSE,
+            Self::Error { .. } => CommandKind::ERROR,
+        }
+    }
+
+    fn decode_kind(kind: CommandKind, b: &mut Bytes) -> Result<Self, CommandDecodeError> {
+        let request = match kind {
+            CommandKind::PING => Self::Ping {
+                data: parse::nt_string::<CommandDecodeError>(b)?,
+            },
+            CommandKind::SHELL => Self::Shell {
+                name: parse::nt_string::<CommandDecodeError>(b)?,
+            },
+            CommandKind::EXEC => Self::Exec {
+                name: parse::nt_string::<CommandDecodeError>(b)?,
+                command: parse::nt_string::<CommandDecodeError>(b)?,
+            },
+            CommandKind::DOWNLOAD => Self::Download {
+                filename: parse::nt_string::<CommandDecodeError>(b)?,
+            },
+            CommandKind::UPLOAD => Self::Upload {
+                filename: parse::nt_string::<CommandDecodeError>(b)?,
+                data: b.split_to(b.len()),
+            },
+            CommandKind::SHUTDOWN => Self::Shutdown,
+            CommandKind::DELAY => Self::Delay {
+                delay: parse::be_u32(b)?,
+            },
+            CommandKind::TUNNEL_CONNECT => Self::TunnelConnect {
+                options: parse::be_u32(b)?,
+                host: parse::nt_string::<CommandDecodeError>(b)?,
+                port: parse::be_u16(b)?,
+            },
+            CommandKind::TUNNEL_DATA => Self::TunnelData {
+                tunnel_id: parse::be_u32(b)?,
+                data: b.split_to(b.len()),
+            },
+            CommandKind::TUNNEL_CLOSE => Self::TunnelClose {
+                tunnel_id: parse::be_u32(b)?,
+                reason: parse::nt_string::<CommandDecodeError>(b)?,
+            },
+            CommandKind::ERROR => Self::Error {
+                status: CommandStatus(parse::be_u16(b)?),
+                reason: parse::nt_string::<CommandDecodeError>(b)?,
+            },
+        };
+        Ok(request)
+    }
+}
+
+impl Encode for CommandRequest {
+    fn encode<B: BufMut + ?Sized>(&self, b: &mut B) {
+        match self {
+            Self::Ping { data } => put_nt_string(b, data),
+            Self::Shell { name } => put_nt_string(b, name),
+            Self::Exec { name, command } => {
+                put_nt_string(b, name);
+                put_nt_string(b, command);
+            }
+            Self::Download { filename } => put_nt_string(b, filename),
+            Self::Upload { filename, data } => {
+                put_nt_string(b, filename);
+                b.put_slice(&data[..]);
+            }
+            Self::Shutdown => (),
+            Self::Delay { delay } => b.put_u32(*delay),
+            Self::TunnelConnect {
+                options,
+                host,
+                port,
+            } => {
+                b.put_u32(*options);
+                put_nt_string(b, host);
+                b.put_u16(*port);
+            }
+            Self::TunnelData { tunnel_id, data } => {
+                b.put_u32(*tunnel_id);
+                b.put_slice(&data[..]);
+            }
+            Self::TunnelClose { tunnel_id, reason } => {
+                b.put_u32(*tunnel_id);
+                put_nt_string(b, reason);
+            }
+            Self::Error { status, reason } => {
+                b.put_u16(status.0);
+                put_nt_string(b, reason);
+            }
+        }
+    }
+}
+
+/// A command response.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandResponse {
+    Ping {
+        data: StringBytes,
+    },
+    Shell {
+        session_id: SessionId,
+    },
+    Exec {
+        session_id: SessionId,
+    },
+    Download {
+        data: Bytes,
+    },
+    Upload,
+    Shutdown,
+    Delay,
+    TunnelConnect {
+        tunnel_id: TunnelId,
+    },
+    Error {
+        status: CommandStatus,
+        reason: StringBytes,
+    },
+}
+
+impl CommandResponse {
+    /// Retrives the command kind.
+    pub fn kind(&self) -> CommandKind {
+        match self {
+            Self::Ping { .. } => CommandKind::PING,
+            Self::Shell { .. } => CommandKind::SHELL,
+            Self::Exec { .. } => CommandKind::EXEC,
+            Self::Download { .. } => CommandKind::DOWNLOAD,
+            Self::Upload => CommandKind::UPLOAD,
+            Self::Shutdown => CommandKind::SHUTDOWN,
+            Self::Delay => CommandKind::DELAY,
+            Self::TunnelConnect { .. } => CommandKind::TUNNEL_CONNECT,
+            Self::Error { .. } => CommandKind::ERROR,
+        }
+    }
+
+    fn decode_kind(kind: CommandKind, b: &mut Bytes) -> Result<Self, CommandDecodeError> {
+        let response = match kind {
+            CommandKind::PING => Self::Ping {
+                data: parse::nt_string::<CommandDecodeError>(b)?,
+            },
+            CommandKind::SHELL => Self::Shell {
+                session_id: parse::be_u16(b)?,
+            },
+            CommandKind::EXEC => Self::Exec {
+                session_id: parse::be_u16(b)?,
+            },
+            CommandKind::DOWNLOAD => Self::Download {
+                data: b.split_to(b.len()),
+            },
+            CommandKind::UPLOAD => Self::Upload,
+            CommandKind::SHUTDOWN => Self::Shutdown,
+            CommandKind::DELAY => Self::Delay,
+            CommandKind::TUNNEL_CONNECT => Self::TunnelConnect {
+                tunnel_id: parse::be_u32(b)?,
+            },
+            CommandKind::ERROR => Self::Error {
+                status: CommandStatus(parse::be_u16(b)?),
+                reason: parse::nt_string::<CommandDecodeError>(b)?,
+            },
+            kind @ CommandKind::TUNNEL_DATA | kind @ CommandKind::TUNNEL_CLOSE => {
+                return Err(CommandDecodeError::UnexpectedResponse(kind))
+            }
+        };
+        Ok(response)
+    }
+}
+
+impl Encode for CommandResponse {
+    fn encode<B: BufMut + ?Sized>(&self, b: &mut B) {
+        match self {
+            Self::Ping { data } => put_nt_string(b, data),
+            Self::Shell { session_id } | Self::Exec { session_id } => b.put_u16(*session_id),
+            Self::Download { data } => b.put_slice(&data[..]),
+            Self::Upload | Self::Shutdown | Self::Delay => (),
+            Self::TunnelConnect { tunnel_id } => b.put_u32(*tunnel_id),
+            Self::Error { status, reason } => {
+                b.put_u16(status.0);
+                put_nt_string(b, reason);
+            }
+        }
+    }
+}
+
+fn put_nt_string<B: BufMut + ?Sized>(b: &mut B, s: &StringBytes) {
+    b.put_slice(s.as_bytes());
+    b.put_u8(0);
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Command Kind
+
+/// Enum of all possible command kinds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u16)]
+#[allow(non_camel_case_types)]
+pub enum CommandKind {
+    /// `COMMAND_PING` command kind.
+    PING = 0x0000,
+    /// `COMMAND_SHELL` command kind.
+    SHELL = 0x0001,
+    /// `COMMAND_EXEC` command kind.
+    EXEC = 0x0002,
+    /// `COMMAND_DOWNLOAD` command kind.
+    DOWNLOAD = 0x0003,
+    /// `COMMAND_UPLOAD` command kind.
+    UPLOAD = 0x0004,
+    /// `COMMAND_SHUTDOWN` command kind.
+    SHUTDOWN = 0x0005,
+    /// `COMMAND_DELAY` command kind.
+    DELAY = 0x0006,
+    /// `TUNNEL_CONNECT` command kind.
+    TUNNEL_CONNECT = 0x1000,
+    /// `TUNNEL_DATA` command kind.
+    TUNNEL_DATA = 0x1001,
+    /// `TUNNEL_CLOSE` command kind.
+    TUNNEL_CLOSE = 0x1002,
+    /// `COMMAND_ERROR` command kind.
+    ERROR = 0xFFFF,
+}
+
+impl CommandKind {
+    pub fn from_code(code: u16) -> Result<Self, CommandDecodeError> {
+        match code {
+            0x0000 => Ok(Self::PING),
+            0x0001 => Ok(Self::SHELL),
+            0x0002 => Ok(Self::EXEC),
+            0x0003 => Ok(Self::DOWNLOAD),
+            0x0004 => Ok(Self::UPLOAD),
+            0x0005 => Ok(Self::SHUTDOWN),
+            0x0006 => Ok(Self::DELAY),
+            0x1000 => Ok(Self::TUNNEL_CONNECT),
+            0x1001 => Ok(Self::TUNNEL_DATA),
+            0x1002 => Ok(Self::TUNNEL_CLOSE),
+            0xFFFF => Ok(Self::ERROR),
+            code => Err(CommandDecodeError::UnexpectedKind(code)),
+        }
+    }
+}
+
+impl From<CommandKind> for u16 {
+    fn from(kind: CommandKind) -> u16 {
+        kind as u16
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Command Status
+
+/// Status code carried in an `ERROR` packet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommandStatus(pub u16);
+
+impl CommandStatus {
+    /// The request succeeded.
+    pub const OK: Self = Self(0x0000);
+    /// A tunnel could not be established.
+    pub const FAILURE: Self = Self(0x8000);
+    /// The request could not be completed (eg. a file could not be read
+    /// or the request is not supported).
+    pub const ERROR: Self = Self(0xFFFF);
+}
+
+impl fmt::Display for CommandStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#06x}", self.0)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Command Error
+
+/// Enum of all possible errors when decoding command packets.
+#[derive(Debug, Clone, PartialEq, Fail)]
+pub enum CommandDecodeError {
+    /// No null term error.
+    #[fail(display = "Expected a null terminator")]
+    NoNullTerm,
+    /// UTF8 decode error.
+    #[fail(display = "UTF-8 decode error: {}", _0)]
+    Utf8(Utf8Error),
+    /// Unexpected command kind.
+    #[fail(display = "Unexpected command kind: {:#06x}", _0)]
+    UnexpectedKind(u16),
+    /// A response was received for a command without one.
+    #[fail(display = "Unexpected response for command: {:?}", _0)]
+    UnexpectedResponse(CommandKind),
+    /// Data remaining after decoding the packet.
+    #[fail(display = "Overflow of {} bytes", _0)]
+    Overflow(usize),
+    /// The packet length exceeds the max.
+    #[fail(display = "Packet length of {} bytes exceeds the max", _0)]
+    TooLarge(usize),
+    /// Incomplete input error.
+    #[fail(display = "Incomplete ({})", _0)]
+    Incomplete(Needed),
+}
+
+impl From<NoNullTermError> for CommandDecodeError {
+    fn from(_: NoNullTermError) -> Self {
+        Self::NoNullTerm
+    }
+}
+
+impl From<Utf8Error> for CommandDecodeError {
+    fn from(err: Utf8Error) -> Self {
+        Self::Utf8(err)
+    }
+}
+
+impl From<Needed> for CommandDecodeError {
+    fn from(needed: Needed) -> Self {
+        Self::Incomplete(needed)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// Tunnel Relay
+
+/// Relays a stream through a tunnel until either end closes.
+///
+/// Data read from the stream is sent as `TUNNEL_DATA` requests, and data
+/// received for the tunnel is written to the stream. A `TUNNEL_CLOSE` is
+/// sent unless the peer closed the tunnel first (by dropping `data_tx`).
+#[cfg(any(feature = "server", feature = "client-command"))]
+pub(crate) async fn relay_tunnel<S>(
+    tunnel_id: TunnelId,
+    stream: S,
+    mut data_rx: futures::channel::mpsc::Receiver<Bytes>,
+    mut request_tx: futures::channel::mpsc::Sender<CommandRequest>,
+) -> Result<(), std::io::Error>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+{
+    use futures::{future, pin_mut, SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut read, mut write) = tokio::io::split(stream);
+
+    let mut close_tx = request_tx.clone();
+    let outbound_fut = async {
+        let mut buf = [0u8; 1024];
+        loop {
+            let len = read.read(&mut buf[..]).await?;
+            if len == 0 {
+                return Ok(());
+            }
+            let request = CommandRequest::TunnelData {
+                tunnel_id,
+                data: Bytes::copy_from_slice(&buf[..len]),
+            };
+            if request_tx.send(request).await.is_err() {
+                // The command session is gone.
+                return Ok(());
+            }
+        }
+    };
+    let inbound_fut = async {
+        while let Some(data) = data_rx.next().await {
+            write.write_all(&data[..]).await?;
+        }
+        write.shutdown().await
+    };
+
+    pin_mut!(outbound_fut, inbound_fut);
+
+    let result = match future::select(outbound_fut, inbound_fut).await {
+        future::Either::Left((result, _)) => result,
+        future::Either::Right((Ok(()), _)) => return Ok(()),
+        future::Either::Right((Err(err), _)) => Err(err),
+    };
+    let close = CommandRequest::TunnelClose {
+        tunnel_id,
+        reason: "closed".into(),
+    };
+    let _ = close_tx.send(close).await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_cmd_encdec_works(packet_in: &'static [u8], valid: CommandPacket) {
+        let mut bytes = Bytes::from_static(packet_in);
+        let decoded = match CommandPacket::decode(&mut bytes) {
+            Ok(decoded) if bytes.is_empty() => decoded,
+            Ok(_) => panic!("bytes remaining after decode: {:?}", bytes),
+            Err(err) => panic!("error decoding packet: {:?}", err),
+        };
+        let mut packet_out = Vec::new();
+        assert_eq!(valid, decoded, "valid = decoded");
+        valid.encode(&mut packet_out);
+        assert_eq!(
+            packet_in,
+            &packet_out[..],
+            "packet = encoded (len {} vs {} )",
+            packet_in.len(),
+            packet_out.len()
+        )
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_parse_cmd_ping() {
+        assert_cmd_encdec_works(
+            &[
+                0x00, 0x00, 0x00, 0x0C, // Length
+                0x00, 0x01, // Packed ID
+                0x00, 0x00, // Command kind
+                b'd', b'r', b'a', b'g', b'o', b'n', b's', 0x00, // Data
+            ],
+            CommandPacket::request(1, CommandRequest::Ping {
+                data: "dragons".into(),
+            }),
+        );
+        assert_cmd_encdec_works(
+            &[
+                0x00, 0x00, 0x00, 0x0C, // Length
+                0x80, 0x01, // Packed ID
+                0x00, 0x00, // Command kind
+                b'd', b'r', b'a', b'g', b'o', b'n', b's', 0x00, // Data
+            ],
+            CommandPacket::response(1, CommandResponse::Ping {
+                data: "dragons".into(),
+            }),
+        );
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_parse_cmd_exec() {
+        assert_cmd_encdec_works(
+            &[
+                0x00, 0x00, 0x00, 0x0A, // Length
+                0x00, 0x02, // Packed ID
+                0x00, 0x02, // Command kind
+                b's', b'h', 0x00, // Name
+                b'l', b's', 0x00, // Command
+            ],
+            CommandPacket::request(2, CommandRequest::Exec {
+                name: "sh".into(),
+                command: "ls".into(),
+            }),
+        );
+        assert_cmd_encdec_works(
+            &[
+                0x00, 0x00, 0x00, 0x06, // Length
+                0x80, 0x02, // Packed ID
+                0x00, 0x02, // Command kind
+                0x12, 0x34, // Session ID
+            ],
+            CommandPacket::response(2, CommandResponse::Exec {
+                session_id: 0x1234,
+            }),
+        );
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_parse_cmd_upload() {
+        assert_cmd_encdec_works(
+            &[
+                0x00, 0x00, 0x00, 0x08, // Length
+                0x00, 0x03, // Packed ID
+                0x00, 0x04, // Command kind
+                b'a', 0x00, // Filename
+                0x01, 0x02, // Data
+            ],
+            CommandPacket::request(3, CommandRequest::Upload {
+                filename: "a".into(),
+                data: Bytes::from_static(&[0x01, 0x02]),
+            }),
+        );
+        assert_cmd_encdec_works(
+            &[
+                0x00, 0x00, 0x00, 0x04, // Length
+                0x80, 0x03, // Packed ID
+                0x00, 0x04, // Command kind
+            ],
+            CommandPacket::response(3, CommandResponse::Upload),
+        );
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_parse_cmd_tunnel_connect() {
+        assert_cmd_encdec_works(
+            &[
+                0x00, 0x00, 0x00, 0x10, // Length
+                0x00, 0x04, // Packed ID
+                0x10, 0x00, // Command kind
+                0x00, 0x00, 0x00, 0x00, // Options
+                b'l', b'o', b'c', b'a', b'l', 0x00, // Host
+                0x00, 0x50, // Port
+            ],
+            CommandPacket::request(4, CommandRequest::TunnelConnect {
+                options: 0,
+                host: "local".into(),
+                port: 80,
+            }),
+        );
+        assert_cmd_encdec_works(
+            &[
+                0x00, 0x00, 0x00, 0x08, // Length
+                0x80, 0x04, // Packed ID
+                0x10, 0x00, // Command kind
+                0x00, 0x00, 0x00, 0x07, // Tunnel ID
+            ],
+            CommandPacket::response(4, CommandResponse::TunnelConnect {
+                tunnel_id: 7,
+            }),
+        );
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_parse_cmd_tunnel_data() {
+        assert_cmd_encdec_works(
+            &[
+                0x00, 0x00, 0x00, 0x0B, // Length
+                0x00, 0x05, // Packed ID
+                0x10, 0x01, // Command kind
+                0x00, 0x00, 0x00, 0x07, // Tunnel ID
+                b'h', b'i', b'!', // Data
+            ],
+            CommandPacket::request(5, CommandRequest::TunnelData {
+                tunnel_id: 7,
+                data: Bytes::from_static(b"hi!"),
+            }),
+        );
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_parse_cmd_error() {
+        assert_cmd_encdec_works(
+            &[
+                0x00, 0x00, 0x00, 0x0A, // Length
+                0x80, 0x06, // Packed ID
+                0xFF, 0xFF, // Command kind
+                0x80, 0x00, // Status
+                b'n', b'o', b'!', 0x00, // Reason
+            ],
+            CommandPacket::response(6, CommandResponse::Error {
+                status: CommandStatus::FAILURE,
+                reason: "no!".into(),
+            }),
+        );
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_parse_cmd_unexpected_response() {
+        let mut bytes = Bytes::from_static(&[
+            0x00, 0x00, 0x00, 0x04, // Length
+            0x80, 0x05, // Packed ID
+            0x10, 0x02, // Command kind
+        ]);
+        assert_eq!(
+            CommandPacket::decode(&mut bytes),
+            Err(CommandDecodeError::UnexpectedResponse(CommandKind::TUNNEL_CLOSE))
+        );
+    }
+
+    #[test]
+    fn test_decode_buf_partial() {
+        let packet = CommandPacket::request(1, CommandRequest::Shutdown);
+        let mut encoded = BytesMut::new();
+        packet.encode(&mut encoded);
+        packet.encode(&mut encoded);
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&encoded[..5]);
+        assert_eq!(CommandPacket::decode_buf(&mut buf), Ok(None));
+        buf.extend_from_slice(&encoded[5..]);
+        assert_eq!(
+            CommandPacket::decode_buf(&mut buf),
+            Ok(Some(packet.clone()))
+        );
+        assert_eq!(CommandPacket::decode_buf(&mut buf), Ok(Some(packet)));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_buf_too_large() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(MAX_PACKET_LEN as u32 + 1);
+        assert_eq!(
+            CommandPacket::decode_buf(&mut buf),
+            Err(CommandDecodeError::TooLarge(MAX_PACKET_LEN + 1))
+        );
+    }
+}
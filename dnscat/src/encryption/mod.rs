@@ -4,6 +4,8 @@ mod standard;
 use failure::Fail;
 use generic_array::typenum::{U32, U64};
 use generic_array::GenericArray;
+#[cfg(feature = "persist")]
+use secstr::SecStr;
 
 use crate::packet::SessionHeader;
 
@@ -25,6 +27,8 @@ pub enum EncryptionError {
     Keygen,
     #[fail(display = "Invalid signature")]
     Signature,
+    #[fail(display = "Invalid encryption state")]
+    State,
     #[fail(display = "{}", _0)]
     Custom(&'static str),
 }
@@ -55,6 +59,26 @@ pub trait Encryption {
     ) -> Result<(), EncryptionError>;
 }
 
+/// Encryption whose established state can be saved and later restored.
+#[cfg(feature = "persist")]
+pub trait EncryptionState: Encryption + Sized {
+    /// Saves the state required to continue encrypting and decrypting.
+    ///
+    /// Implementations must ensure the restored encryption never reuses a
+    /// nonce this encryption may still use, returning an error if too few
+    /// are left to do so.
+    ///
+    /// Returns `None` if a shared secret has not yet been agreed on.
+    fn save_state(&mut self) -> Result<Option<SecStr>, EncryptionError>;
+
+    /// Restores encryption from a state previously saved, updating the state
+    /// to be restored next in its place.
+    ///
+    /// The updated state must be persisted before the restored encryption
+    /// is used, as restoring a state again would reuse nonces.
+    fn restore_state(state: &mut SecStr) -> Result<Self, EncryptionError>;
+}
+
 pub trait EncryptionAcceptor {
     type Encryption: Encryption;
 
@@ -104,6 +128,17 @@ impl Encryption for NoEncryption {
     }
 }
 
+#[cfg(feature = "persist")]
+impl EncryptionState for NoEncryption {
+    fn save_state(&mut self) -> Result<Option<SecStr>, EncryptionError> {
+        unreachable!()
+    }
+
+    fn restore_state(_state: &mut SecStr) -> Result<Self, EncryptionError> {
+        Err(EncryptionError::Custom("encryption not supported"))
+    }
+}
+
 impl EncryptionAcceptor for NoEncryption {
     type Encryption = NoEncryption;
 
@@ -111,3 +146,15 @@ impl EncryptionAcceptor for NoEncryption {
         unimplemented!()
     }
 }
+
+/// An acceptor that rejects all encrypted sessions.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoEncryptionAcceptor;
+
+impl EncryptionAcceptor for NoEncryptionAcceptor {
+    type Encryption = NoEncryption;
+
+    fn accept(&mut self, _client: PublicKey) -> Result<Self::Encryption, EncryptionError> {
+        Err(EncryptionError::Custom("encryption not supported"))
+    }
+}
@@ -11,6 +11,8 @@ use salsa20::Salsa20;
 use secstr::SecStr;
 use sha3::{Digest, Sha3_256};
 
+#[cfg(feature = "persist")]
+use super::EncryptionState;
 use super::{Authenticator, Encryption, EncryptionAcceptor, EncryptionError, PublicKey};
 
 use crate::packet::SessionHeader;
@@ -21,6 +23,15 @@ const PUBLIC_KEY_OCTET_TAG: u8 = 0x04;
 // signature + nonce
 const STANDARD_ARGS_SIZE: usize = 6 + 2;
 
+// is client + nonce + public key + stream keys
+#[cfg(feature = "persist")]
+const STANDARD_STATE_SIZE: usize = 1 + 2 + 64 + 4 * 32;
+
+// Nonces reserved for the encryption a state is saved from or restored to,
+// which the next restore resumes past.
+#[cfg(feature = "persist")]
+const NONCE_RESERVE: u16 = 1024;
+
 type EncryptionKey = GenericArray<u8, <Salsa20 as NewStreamCipher>::KeySize>;
 type EncryptionNonce = GenericArray<u8, <Salsa20 as NewStreamCipher>::NonceSize>;
 type EncryptionMac = GenericArray<u8, U32>;
@@ -30,8 +41,9 @@ type PublicKeyWithTag = GenericArray<u8, U65>;
 pub struct StandardEncryption {
     is_client: bool,
     nonce: u16,
+    nonce_limit: u16,
     preshared_key: Option<SecStr>,
-    self_pub_key: agreement::PublicKey,
+    self_pub_key: PublicKey,
     self_authenticator: Option<Authenticator>,
     peer_authenticator: Option<Authenticator>,
     self_priv_key: Option<agreement::EphemeralPrivateKey>,
@@ -54,8 +66,12 @@ impl StandardEncryption {
                 })
                 .or(Err(EncryptionError::Keygen))?;
 
+        // Remove: PUBLIC_KEY_OCTET_TAG
+        let self_pub_key = GenericArray::clone_from_slice(&self_pub_key.as_ref()[1..]);
+
         Ok(Self {
             nonce: 0,
+            nonce_limit: u16::MAX,
             is_client,
             preshared_key,
             self_pub_key,
@@ -68,7 +84,7 @@ impl StandardEncryption {
     }
 
     fn next_nouce(&mut self) -> Result<u16, EncryptionError> {
-        if self.nonce == u16::max_value() {
+        if self.nonce == self.nonce_limit {
             Err(EncryptionError::Renegotiate)
         } else {
             let current = self.nonce;
@@ -82,8 +98,7 @@ impl StandardEncryption {
     }
 
     fn raw_public_key(&self) -> &[u8] {
-        // Remove: PUBLIC_KEY_OCTET_TAG
-        &self.self_pub_key.as_ref()[1..]
+        &self.self_pub_key[..]
     }
 }
 
@@ -93,7 +108,7 @@ impl Encryption for StandardEncryption {
     }
 
     fn public_key(&self) -> PublicKey {
-        GenericArray::clone_from_slice(self.raw_public_key())
+        self.self_pub_key
     }
 
     fn handshake(&mut self, peer: PublicKey) -> Result<(), EncryptionError> {
@@ -190,6 +205,73 @@ impl Encryption for StandardEncryption {
     }
 }
 
+#[cfg(feature = "persist")]
+impl EncryptionState for StandardEncryption {
+    fn save_state(&mut self) -> Result<Option<SecStr>, EncryptionError> {
+        let keys = match self.stream_keys {
+            Some(ref keys) => keys,
+            None => return Ok(None),
+        };
+        // Reserve the nonces up to the point a restore resumes from, leaving
+        // this encryption to renegotiate once it runs out of them. The state
+        // is refused if a restore couldn't reserve as many in turn.
+        let resume_nonce = match self.nonce.checked_add(NONCE_RESERVE) {
+            Some(nonce) if nonce.checked_add(NONCE_RESERVE).is_some() => nonce,
+            _ => return Err(EncryptionError::Renegotiate),
+        };
+        self.nonce_limit = resume_nonce;
+        // Allocated up front so no copies of the keys are left behind.
+        let mut state = Vec::with_capacity(STANDARD_STATE_SIZE);
+        state.put_u8(self.is_client as u8);
+        state.put_u16(resume_nonce);
+        state.put_slice(&self.self_pub_key[..]);
+        state.put_slice(&keys.client_write[..]);
+        state.put_slice(&keys.client_mac[..]);
+        state.put_slice(&keys.server_write[..]);
+        state.put_slice(&keys.server_mac[..]);
+        Ok(Some(SecStr::new(state)))
+    }
+
+    fn restore_state(state: &mut SecStr) -> Result<Self, EncryptionError> {
+        let state = state.unsecure_mut();
+        if state.len() != STANDARD_STATE_SIZE {
+            return Err(EncryptionError::State);
+        }
+        let (head, keys) = state.split_at_mut(1 + 2 + 64);
+        let is_client = match head[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(EncryptionError::State),
+        };
+        let mut keys = keys.chunks_exact(32).map(GenericArray::clone_from_slice);
+        let mut next_key = || keys.next().expect("stream key");
+        let stream_keys = StreamKeys {
+            client_write: next_key(),
+            client_mac: next_key(),
+            server_write: next_key(),
+            server_mac: next_key(),
+        };
+        // Reserve the nonces up to the point the next restore resumes from.
+        let nonce = u16::from_be_bytes([head[1], head[2]]);
+        let resume_nonce = nonce
+            .checked_add(NONCE_RESERVE)
+            .ok_or(EncryptionError::Renegotiate)?;
+        head[1..3].copy_from_slice(&resume_nonce.to_be_bytes());
+        Ok(Self {
+            is_client,
+            nonce,
+            nonce_limit: resume_nonce,
+            preshared_key: None,
+            self_pub_key: GenericArray::clone_from_slice(&head[3..]),
+            self_authenticator: None,
+            peer_authenticator: None,
+            self_priv_key: None,
+            peer_pub_key: None,
+            stream_keys: Some(stream_keys),
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct StandardEncryptionAcceptor {
     preshared_key: Option<SecStr>,
@@ -359,4 +441,123 @@ mod tests {
             .expect("decrypt");
         assert_eq!(data, [1, 2, 3, 5]);
     }
+
+    #[test]
+    #[cfg(feature = "persist")]
+    fn test_restore_state() {
+        let mut client = StandardEncryption::new_with_ephemeral(true, None).expect("client enc");
+        let mut server = StandardEncryption::new_with_ephemeral(false, None).expect("server enc");
+
+        assert!(client.save_state().expect("save").is_none());
+
+        server
+            .handshake(client.public_key())
+            .expect("client to server handshake");
+        client
+            .handshake(server.public_key())
+            .expect("server to client handshake");
+
+        let header = SessionHeader::new(1, PacketKind::MSG, 2);
+        let mut args = [0u8; 8];
+        let mut data = [1, 2, 3, 5];
+
+        client
+            .encrypt(&header, &mut args[..], &mut data[..])
+            .expect("encrypt");
+        server
+            .decrypt(&header, &args[..], &mut data[..])
+            .expect("decrypt");
+
+        let mut state = client.save_state().expect("save").expect("client state");
+        let public_key = client.public_key();
+        let mut client = StandardEncryption::restore_state(&mut state).expect("restore");
+        assert_eq!(client.public_key(), public_key);
+        // The state now resumes past the nonces reserved for the restore.
+        assert_eq!(
+            state.unsecure()[1..3],
+            (1 + 2 * NONCE_RESERVE).to_be_bytes()
+        );
+
+        client
+            .encrypt(&header, &mut args[..], &mut data[..])
+            .expect("encrypt");
+        assert_eq!(args[6..], (1 + NONCE_RESERVE).to_be_bytes());
+        server
+            .decrypt(&header, &args[..], &mut data[..])
+            .expect("decrypt");
+        assert_eq!(data, [1, 2, 3, 5]);
+
+        let mut truncated = SecStr::from(&state.unsecure()[1..]);
+        assert!(matches!(
+            StandardEncryption::restore_state(&mut truncated),
+            Err(EncryptionError::State)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "persist")]
+    fn test_restore_state_nonce_reuse() {
+        let mut client = StandardEncryption::new_with_ephemeral(true, None).expect("client enc");
+        let mut server = StandardEncryption::new_with_ephemeral(false, None).expect("server enc");
+        server.handshake(client.public_key()).expect("handshake");
+        client.handshake(server.public_key()).expect("handshake");
+
+        let header = SessionHeader::new(1, PacketKind::MSG, 2);
+        let encrypt = |client: &mut StandardEncryption| {
+            let mut args = [0u8; 8];
+            let mut data = [0u8; 16];
+            client
+                .encrypt(&header, &mut args[..], &mut data[..])
+                .map(|()| data)
+        };
+
+        // Restored twice, as if after a crash, from the state as updated.
+        let mut state = client.save_state().expect("save").expect("client state");
+        let mut resumed = StandardEncryption::restore_state(&mut state).expect("restore");
+        let mut resumed_again = StandardEncryption::restore_state(&mut state).expect("restore");
+
+        // Each keystream is distinct, so no nonce is used twice.
+        let ciphertexts = [
+            encrypt(&mut client).expect("encrypt"),
+            encrypt(&mut resumed).expect("encrypt"),
+            encrypt(&mut resumed_again).expect("encrypt"),
+        ];
+        assert_ne!(ciphertexts[0], ciphertexts[1]);
+        assert_ne!(ciphertexts[0], ciphertexts[2]);
+        assert_ne!(ciphertexts[1], ciphertexts[2]);
+
+        // Each encryption stops at the nonces reserved for it.
+        for encryption in [&mut client, &mut resumed] {
+            for _ in 1..NONCE_RESERVE {
+                encrypt(encryption).expect("encrypt");
+            }
+            assert!(matches!(
+                encrypt(encryption),
+                Err(EncryptionError::Renegotiate)
+            ));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "persist")]
+    fn test_save_state_nonces_exhausted() {
+        let mut client = StandardEncryption::new_with_ephemeral(true, None).expect("client enc");
+        let server = StandardEncryption::new_with_ephemeral(false, None).expect("server enc");
+        client.handshake(server.public_key()).expect("handshake");
+
+        // The last nonce a state can be saved at leaves enough for a restore.
+        client.nonce = u16::MAX - 2 * NONCE_RESERVE + 1;
+        assert!(matches!(
+            client.save_state(),
+            Err(EncryptionError::Renegotiate)
+        ));
+        client.nonce -= 1;
+        let mut state = client.save_state().expect("save").expect("client state");
+        let restored = StandardEncryption::restore_state(&mut state).expect("restore");
+        assert_eq!(restored.nonce_limit, u16::MAX);
+        assert!(matches!(
+            StandardEncryption::restore_state(&mut state),
+            Err(EncryptionError::Renegotiate)
+        ));
+    }
 }
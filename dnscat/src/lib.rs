@@ -9,11 +9,14 @@
     unreachable_pub
 )]
 
-#[cfg(feature = "client-cli")]
+#[cfg(any(feature = "client-cli", feature = "server-cli"))]
 pub mod cli;
 pub mod client;
+pub mod clock;
+pub mod command;
 pub mod encryption;
 pub mod packet;
+pub mod server;
 pub mod session;
 pub mod transport;
 
@@ -105,6 +105,13 @@ where
         self.split_session()
             .map(|(head, body)| Packet::new(head, body))
     }
+
+    pub fn into_ping(self) -> Option<Packet<PingBody>> {
+        match self.split() {
+            (SupportedHeader::Ping(h), SupportedBody::Ping(b)) => Some(Packet::new(h, b)),
+            _ => None,
+        }
+    }
 }
 
 impl<T> Encode for Packet<T>
@@ -272,6 +279,12 @@ bitflags! {
         /// We're negotiating encryption.
         #[deprecated]
         const ENCRYPTED = 0b0100_0000;
+        /// `OPT_WINDOW`
+        ///
+        /// Non-standard, the session supports several `MSG` packets in
+        /// flight with cumulative acknowledgements. A peer that doesn't
+        /// support it won't set it in its `SYN`.
+        const WINDOW = 0b1000_0000;
     }
 }
 
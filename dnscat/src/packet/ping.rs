@@ -1,3 +1,5 @@
+use std::mem;
+
 use crate::util::{Encode, StringBytes};
 
 use super::*;
@@ -12,6 +14,22 @@ pub struct PingHeader {
     pub ping_id: PingId,
 }
 
+impl PingHeader {
+    pub const fn new(packet_id: PacketId, ping_id: PingId) -> Self {
+        Self {
+            packet: PacketHeader {
+                id: packet_id,
+                kind: PacketKind::PING,
+            },
+            ping_id,
+        }
+    }
+
+    pub const fn len() -> usize {
+        PacketHeader::len() + mem::size_of::<PingId>()
+    }
+}
+
 impl AsRef<PacketHeader> for PingHeader {
     fn as_ref(&self) -> &PacketHeader {
         &self.packet
@@ -54,6 +72,12 @@ impl PingBody {
         self.data.as_ref()
     }
 
+    /// Returns the size of a `PING` packet without data.
+    pub const fn packet_size_no_data() -> usize {
+        // The data is null terminated.
+        PingHeader::len() + 1
+    }
+
     pub fn set_data<S>(&mut self, data: S) -> u8
     where
         S: Into<StringBytes>,
@@ -203,6 +203,16 @@ impl SynBody {
         self.flags().contains(PacketFlags::COMMAND)
     }
 
+    /// Returns `true` if the `WINDOW` flag is set.
+    pub fn is_windowed(&self) -> bool {
+        self.flags().contains(PacketFlags::WINDOW)
+    }
+
+    /// Sets the `WINDOW` flag.
+    pub fn set_windowed(&mut self) {
+        self.flags.insert(PacketFlags::WINDOW);
+    }
+
     /// Retrives the session name.
     pub fn session_name(&self) -> Option<&str> {
         if self.has_session_name() {
@@ -0,0 +1,145 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::prelude::{Rng, ThreadRng};
+
+use crate::clock::{Clock, SystemClock};
+use crate::encryption::{EncryptionAcceptor, NoEncryptionAcceptor};
+use crate::packet::LazyPacket;
+use crate::transport::Transport;
+
+use super::{Server, ServerOpts};
+
+#[derive(Debug)]
+pub struct ServerBuilder<R = ThreadRng>
+where
+    R: Rng + Clone,
+{
+    random: R,
+    insecure: bool,
+    prefer_client_name: bool,
+    send_buf_size: usize,
+    packet_trace: bool,
+    window: bool,
+    session_timeout: Duration,
+    max_handshakes: usize,
+    clock: Arc<dyn Clock>,
+}
+
+impl<R> ServerBuilder<R>
+where
+    R: Rng + Clone,
+{
+    pub fn default_with_random(random: R) -> Self {
+        Self {
+            random,
+            insecure: false,
+            prefer_client_name: true,
+            send_buf_size: 64 * 1024,
+            packet_trace: false,
+            window: true,
+            session_timeout: Duration::from_secs(5 * 60),
+            max_handshakes: 256,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// If set, sessions without encryption/authentication are accepted.
+    pub fn insecure(mut self, value: bool) -> Self {
+        self.insecure = value;
+        self
+    }
+
+    pub fn prefer_client_name(mut self, value: bool) -> Self {
+        self.prefer_client_name = value;
+        self
+    }
+
+    /// Set the max length of data buffered per session before writes block.
+    pub fn send_buf_size(mut self, size: usize) -> Self {
+        assert_ne!(size, 0, "send buffer size must be greater than zero");
+        self.send_buf_size = size;
+        self
+    }
+
+    pub fn packet_trace(mut self, value: bool) -> Self {
+        self.packet_trace = value;
+        self
+    }
+
+    /// If set, clients requesting it may have several `MSG` packets in flight.
+    pub fn window(mut self, value: bool) -> Self {
+        self.window = value;
+        self
+    }
+
+    /// Set how long a session may go without a request before it is
+    /// removed, which defaults to five minutes.
+    pub fn session_timeout(mut self, timeout: Duration) -> Self {
+        assert_ne!(
+            timeout,
+            Duration::from_secs(0),
+            "session timeout must be non-zero"
+        );
+        self.session_timeout = timeout;
+        self
+    }
+
+    /// Set the max number of sessions yet to be established, beyond which
+    /// new sessions are rejected, which defaults to 256.
+    pub fn max_handshakes(mut self, max: usize) -> Self {
+        assert_ne!(max, 0, "max handshakes must be greater than zero");
+        self.max_handshakes = max;
+        self
+    }
+
+    /// Set the clock sessions take their time from, which defaults to the
+    /// system clock.
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn build<T, A>(self, transport: T, acceptor: A) -> Server<T, A, R>
+    where
+        T: Transport<LazyPacket>,
+        A: EncryptionAcceptor,
+    {
+        let options = ServerOpts {
+            insecure: self.insecure,
+            prefer_client_name: self.prefer_client_name,
+            send_buf_size: self.send_buf_size,
+            packet_trace: self.packet_trace,
+            window: self.window,
+            session_timeout: self.session_timeout,
+            max_handshakes: self.max_handshakes,
+            clock: self.clock,
+        };
+        Server {
+            transport,
+            acceptor,
+            options,
+            random: self.random,
+            sessions: HashMap::new(),
+            accept_queue: VecDeque::new(),
+            response: None,
+        }
+    }
+
+    pub fn build_insecure<T>(self, transport: T) -> Server<T, NoEncryptionAcceptor, R>
+    where
+        T: Transport<LazyPacket>,
+    {
+        self.insecure(true).build(transport, NoEncryptionAcceptor)
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::default_with_random(ThreadRng::default())
+    }
+}
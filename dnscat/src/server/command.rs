@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+use std::io;
+
+use bytes::{Bytes, BytesMut};
+use failure::Fail;
+use futures::channel::{mpsc, oneshot};
+use futures::{SinkExt, StreamExt};
+use log::{debug, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::command::{
+    relay_tunnel, CommandBody, CommandKind, CommandPacket, CommandRequest, CommandResponse,
+    CommandStatus, RequestId, TunnelId, RESPONSE_BIT, TUNNEL_QUEUE_SIZE,
+};
+use crate::util::Encode;
+
+use super::ServerStream;
+
+#[derive(Debug, Fail)]
+pub enum CommandError {
+    #[fail(display = "Command session closed")]
+    Closed,
+    #[fail(display = "Command failed with status {}: {}", _0, _1)]
+    Status(CommandStatus, String),
+    #[fail(display = "Unexpected response `{:?}`", _0)]
+    UnexpectedResponse(CommandKind),
+}
+
+impl From<oneshot::Canceled> for CommandError {
+    fn from(_: oneshot::Canceled) -> Self {
+        Self::Closed
+    }
+}
+
+impl From<CommandError> for io::Error {
+    fn from(err: CommandError) -> Self {
+        io::Error::other(err.compat())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+enum Control {
+    Request(CommandRequest, oneshot::Sender<CommandResponse>),
+    TunnelConnect(String, u16, oneshot::Sender<Result<Tunnel, CommandError>>),
+}
+
+#[derive(Debug)]
+enum Pending {
+    Request(oneshot::Sender<CommandResponse>),
+    TunnelConnect(oneshot::Sender<Result<Tunnel, CommandError>>),
+}
+
+/// Drives a command session accepted by a [`Server`](super::Server),
+/// sending the requests made with its [`CommandHandle`]s.
+///
+/// Data is only exchanged with the client while the server is being polled.
+#[derive(Debug)]
+pub struct CommandController {
+    stream: ServerStream,
+    control_rx: mpsc::UnboundedReceiver<Control>,
+    request_tx: mpsc::Sender<CommandRequest>,
+    request_rx: mpsc::Receiver<CommandRequest>,
+    pending: HashMap<RequestId, Pending>,
+    tunnels: HashMap<TunnelId, mpsc::Sender<Bytes>>,
+    next_request_id: RequestId,
+    recv_buf: BytesMut,
+}
+
+impl CommandController {
+    /// Constructs a controller for a command session, along with a handle
+    /// to make requests with.
+    pub fn new(stream: ServerStream) -> (Self, CommandHandle) {
+        let (control_tx, control_rx) = mpsc::unbounded();
+        let (request_tx, request_rx) = mpsc::channel(TUNNEL_QUEUE_SIZE);
+        let controller = Self {
+            stream,
+            control_rx,
+            request_tx,
+            request_rx,
+            pending: HashMap::new(),
+            tunnels: HashMap::new(),
+            next_request_id: 0,
+            recv_buf: BytesMut::new(),
+        };
+        (controller, CommandHandle { control_tx })
+    }
+
+    /// Sends requests and handles the client's responses until the
+    /// session is closed.
+    pub async fn run(mut self) -> Result<(), io::Error> {
+        let mut buf = [0u8; 1024];
+        loop {
+            while let Some(packet) = CommandPacket::decode_buf(&mut self.recv_buf)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.compat()))?
+            {
+                self.handle_packet(packet).await;
+            }
+            let control_rx = &mut self.control_rx;
+            let request_rx = &mut self.request_rx;
+            let request = tokio::select! {
+                result = self.stream.read(&mut buf[..]) => match result? {
+                    0 => return Ok(()),
+                    len => {
+                        self.recv_buf.extend_from_slice(&buf[..len]);
+                        continue;
+                    }
+                },
+                Some(request) = request_rx.next() => request,
+                Some(control) = control_rx.next() => {
+                    let (request, pending) = match control {
+                        Control::Request(request, reply) => (request, Pending::Request(reply)),
+                        Control::TunnelConnect(host, port, reply) => {
+                            let request = CommandRequest::TunnelConnect {
+                                options: 0,
+                                host: host.into(),
+                                port,
+                            };
+                            (request, Pending::TunnelConnect(reply))
+                        }
+                    };
+                    self.pending.insert(self.next_request_id, pending);
+                    request
+                }
+            };
+            self.send(request).await?;
+        }
+    }
+
+    async fn send(&mut self, request: CommandRequest) -> Result<(), io::Error> {
+        let request_id = self.next_request_id;
+        self.next_request_id = (request_id + 1) & !RESPONSE_BIT;
+        let mut bytes = BytesMut::new();
+        CommandPacket::request(request_id, request).encode(&mut bytes);
+        self.stream.write_all(&bytes[..]).await?;
+        self.stream.flush().await
+    }
+
+    async fn handle_packet(&mut self, packet: CommandPacket) {
+        match packet.body {
+            CommandBody::Request(request) => self.handle_request(request).await,
+            CommandBody::Response(response) => match self.pending.remove(&packet.request_id) {
+                Some(pending) => self.handle_response(pending, response),
+                None => warn!("ignoring response to unknown request {}", packet.request_id),
+            },
+        }
+    }
+
+    async fn handle_request(&mut self, request: CommandRequest) {
+        match request {
+            CommandRequest::TunnelData { tunnel_id, data } => {
+                match self.tunnels.get_mut(&tunnel_id) {
+                    // Waits for a full tunnel, holding back the session.
+                    Some(data_tx) => {
+                        if data_tx.send(data).await.is_err() {
+                            // The tunnel has since closed.
+                            self.tunnels.remove(&tunnel_id);
+                        }
+                    }
+                    None => warn!("ignoring data for unknown tunnel {}", tunnel_id),
+                }
+            }
+            CommandRequest::TunnelClose { tunnel_id, reason } => {
+                if self.tunnels.remove(&tunnel_id).is_some() {
+                    debug!("tunnel {} closed by client: {}", tunnel_id, &*reason);
+                }
+            }
+            CommandRequest::Error { status, reason } => {
+                warn!("client error (status: {}): {}", status, &*reason);
+            }
+            other => warn!("ignoring request {:?}", other.kind()),
+        }
+    }
+
+    fn handle_response(&mut self, pending: Pending, response: CommandResponse) {
+        match pending {
+            Pending::Request(reply) => {
+                let _ = reply.send(response);
+            }
+            Pending::TunnelConnect(reply) => {
+                let result = match response {
+                    CommandResponse::TunnelConnect { tunnel_id } => {
+                        let (data_tx, data_rx) = mpsc::channel(TUNNEL_QUEUE_SIZE);
+                        self.tunnels.insert(tunnel_id, data_tx);
+                        Ok(Tunnel {
+                            id: tunnel_id,
+                            data_rx,
+                            request_tx: self.request_tx.clone(),
+                        })
+                    }
+                    other => Err(response_error(other)),
+                };
+                let _ = reply.send(result);
+            }
+        }
+    }
+}
+
+fn response_error(response: CommandResponse) -> CommandError {
+    match response {
+        CommandResponse::Error { status, reason } => {
+            CommandError::Status(status, reason.to_string())
+        }
+        other => CommandError::UnexpectedResponse(other.kind()),
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// A handle to make requests with a [`CommandController`].
+#[derive(Debug, Clone)]
+pub struct CommandHandle {
+    control_tx: mpsc::UnboundedSender<Control>,
+}
+
+impl CommandHandle {
+    /// Sends a request, returning the client's response.
+    ///
+    /// Error responses are returned as [`CommandError::Status`]. Use
+    /// [`CommandHandle::tunnel_connect`] to open tunnels.
+    pub async fn request(&self, request: CommandRequest) -> Result<CommandResponse, CommandError> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.control(Control::Request(request, reply_tx))?;
+        match reply_rx.await? {
+            response @ CommandResponse::Error { .. } => Err(response_error(response)),
+            response => Ok(response),
+        }
+    }
+
+    /// Requests the client open a TCP connection to `host:port`.
+    pub async fn tunnel_connect(&self, host: &str, port: u16) -> Result<Tunnel, CommandError> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.control(Control::TunnelConnect(host.to_string(), port, reply_tx))?;
+        reply_rx.await?
+    }
+
+    fn control(&self, control: Control) -> Result<(), CommandError> {
+        self.control_tx
+            .unbounded_send(control)
+            .map_err(|_| CommandError::Closed)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// A TCP connection opened by the client, relayed through a command session.
+#[derive(Debug)]
+pub struct Tunnel {
+    id: TunnelId,
+    data_rx: mpsc::Receiver<Bytes>,
+    request_tx: mpsc::Sender<CommandRequest>,
+}
+
+impl Tunnel {
+    /// Returns the tunnel ID.
+    pub fn id(&self) -> TunnelId {
+        self.id
+    }
+
+    /// Relays a stream through the tunnel until either end closes.
+    pub async fn relay<S>(self, stream: S) -> Result<(), io::Error>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        relay_tunnel(self.id, stream, self.data_rx, self.request_tx).await
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{future, pin_mut};
+    use tokio::io::DuplexStream;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::client::{ClientBuilder, CommandDriver};
+    use crate::server::ServerBuilder;
+    use crate::transport::channel::channel_pair;
+
+    #[cfg(feature = "client-command")]
+    #[tokio::test]
+    async fn test_command_tunnel() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let echo = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut read, mut write) = stream.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+        };
+
+        let (client_transport, server_transport) = channel_pair();
+        let mut server = ServerBuilder::default()
+            .insecure(true)
+            .build_insecure(server_transport);
+
+        let client = async {
+            let client = ClientBuilder::default()
+                .command(true)
+                .max_delay(Duration::from_millis(10))
+                .connect_insecure(client_transport)
+                .await
+                .unwrap();
+            let connector =
+                |_, _| future::ready(Err::<DuplexStream, _>(io::ErrorKind::Other.into()));
+            CommandDriver::new(client, connector).run().await.unwrap();
+        };
+
+        let controller = async {
+            let stream = server.accept().await.unwrap();
+            assert!(stream.is_command());
+            let (controller, handle) = CommandController::new(stream);
+            let requests = async {
+                let response = handle
+                    .request(CommandRequest::Ping {
+                        data: "hello".into(),
+                    })
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    response,
+                    CommandResponse::Ping {
+                        data: "hello".into()
+                    }
+                );
+                let tunnel = handle.tunnel_connect("127.0.0.1", port).await.unwrap();
+                let (mut local, remote) = tokio::io::duplex(64);
+                let relay = tunnel.relay(remote);
+                let exchange = async {
+                    local.write_all(b"hello tunnel").await.unwrap();
+                    let mut buf = [0u8; 12];
+                    local.read_exact(&mut buf[..]).await.unwrap();
+                    assert_eq!(&buf, b"hello tunnel");
+                };
+                pin_mut!(relay, exchange);
+                future::select(relay, exchange).await;
+                assert!(matches!(
+                    handle.tunnel_connect("127.0.0.1", 0).await,
+                    Err(CommandError::Status(CommandStatus::FAILURE, _))
+                ));
+            };
+            let drive = async {
+                let run = controller.run();
+                let accept = server.accept();
+                pin_mut!(run, accept);
+                future::select(run, accept).await;
+            };
+            pin_mut!(requests, drive);
+            future::select(requests, drive).await;
+        };
+
+        pin_mut!(echo, client, controller);
+        let client_and_echo = future::select(client, echo);
+        future::select(client_and_echo, controller).await;
+    }
+}
@@ -0,0 +1,736 @@
+mod builder;
+#[cfg(feature = "server")]
+mod command;
+#[cfg(feature = "server")]
+mod socks;
+mod stream;
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes};
+use failure::Fail;
+use futures::{future, ready};
+use log::{debug, info, warn};
+use rand::Rng;
+
+use crate::clock::Clock;
+use crate::encryption::{Encryption, EncryptionAcceptor};
+use crate::packet::*;
+use crate::session::{Session, SessionError, SessionRole, SessionStage};
+use crate::transport::Transport;
+
+use self::stream::StreamState;
+
+pub use self::builder::ServerBuilder;
+#[cfg(feature = "server")]
+pub use self::command::{CommandController, CommandError, CommandHandle, Tunnel};
+#[cfg(feature = "server")]
+pub use self::socks::{socks5_tunnel, SocksError};
+pub use self::stream::ServerStream;
+
+#[derive(Debug, Fail)]
+pub enum ServerError<T: Fail> {
+    #[fail(display = "Transport error: {}", _0)]
+    Transport(T),
+}
+
+impl<T: Fail> From<ServerError<T>> for io::Error {
+    fn from(err: ServerError<T>) -> Self {
+        io::Error::other(err.compat())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// How long a closed session is kept to answer re-transmits of its
+/// last request.
+const CLOSED_SESSION_LINGER: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+struct ServerOpts {
+    insecure: bool,
+    prefer_client_name: bool,
+    send_buf_size: usize,
+    packet_trace: bool,
+    window: bool,
+    session_timeout: Duration,
+    max_handshakes: usize,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Debug)]
+struct ServerSession<E, R> {
+    session: Session<E, R>,
+    /// The state shared with the session stream once accepted.
+    stream: Arc<Mutex<StreamState>>,
+    /// Whether or not the session stream has been accepted.
+    accepted: bool,
+    /// The last request body received, used to detect re-transmits.
+    last_request: Option<Bytes>,
+    /// The last response sent, which is sent again on a re-transmit.
+    last_response: Option<Packet<SessionBodyBytes>>,
+    /// If windowed, data sent that the client has yet to acknowledge.
+    unacked: Bytes,
+    /// The instant the last request was received.
+    last_seen: Instant,
+}
+
+/// A DNSCAT2 server, accepting sessions from clients over a transport.
+///
+/// The transport is expected to receive client requests, each of which is
+/// answered by the next datagram sent. Sessions only make progress while
+/// the server is polled, so [`Server::accept`] should be called in a loop
+/// for as long as any of the accepted streams are in use.
+#[derive(Debug)]
+pub struct Server<T, A, R>
+where
+    T: Transport<LazyPacket>,
+    A: EncryptionAcceptor,
+{
+    transport: T,
+    acceptor: A,
+    options: ServerOpts,
+    random: R,
+    sessions: HashMap<SessionId, ServerSession<A::Encryption, R>>,
+    accept_queue: VecDeque<ServerStream>,
+    response: Option<LazyPacket>,
+}
+
+impl<T, A, R> Server<T, A, R>
+where
+    T: Transport<LazyPacket>,
+    A: EncryptionAcceptor,
+    R: Rng + Clone,
+{
+    /// Returns a reference to the underlying transport.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Accept the next established session, driving all sessions
+    /// while waiting.
+    pub async fn accept(&mut self) -> Result<ServerStream, ServerError<T::Error>> {
+        future::poll_fn(|cx| self.poll_accept(cx)).await
+    }
+
+    pub fn poll_accept(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<ServerStream, ServerError<T::Error>>> {
+        loop {
+            if let Some(stream) = self.accept_queue.pop_front() {
+                return Poll::Ready(Ok(stream));
+            }
+            if let Some(response) = self.response.as_ref() {
+                ready!(self.transport.poll_send(cx, response.clone()))
+                    .map_err(ServerError::Transport)?;
+                self.response = None;
+            }
+            let request = ready!(self.transport.poll_recv(cx)).map_err(ServerError::Transport)?;
+            self.response = self.handle_request(request);
+        }
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+
+    fn handle_request(&mut self, request: LazyPacket) -> Option<LazyPacket> {
+        self.evict_sessions();
+        if request.kind() == PacketKind::PING {
+            debug!("echoing ping");
+            return Some(request);
+        }
+        let packet = match request.into_session() {
+            Some(packet) => packet,
+            None => {
+                debug!("ignoring non-session packet");
+                return None;
+            }
+        };
+        let session_id = packet.head.session_id;
+        let request = packet.body.0.clone();
+        let is_new = match self.sessions.get(&session_id) {
+            Some(existing) => {
+                existing.session.is_closed()
+                    && matches!(packet.kind(), PacketKind::ENC | PacketKind::SYN)
+                    && existing.last_request.as_ref() != Some(&request)
+            }
+            None => true,
+        };
+        let packet = if is_new {
+            self.accept_session(packet)?
+        } else {
+            Some(packet)
+        };
+        let server_session = self.sessions.get_mut(&session_id)?;
+        server_session.last_seen = self.options.clock.now();
+        let response =
+            server_session.handle_packet(packet, &request, self.transport.max_send_size())?;
+        if !server_session.accepted && server_session.session.stage().is_established() {
+            let session = &server_session.session;
+            info!(
+                "accepted session (id: {}, name: {})",
+                session_id,
+                session.name().unwrap_or("<none>")
+            );
+            server_session.accepted = true;
+            self.accept_queue.push_back(ServerStream::new(
+                session_id,
+                session.name().map(ToOwned::to_owned),
+                session.is_command,
+                server_session.stream.clone(),
+            ));
+        }
+        Some(response.translate())
+    }
+
+    /// Removes the sessions idle past the session timeout, and the closed
+    /// sessions no longer expecting re-transmits.
+    fn evict_sessions(&mut self) {
+        let now = self.options.clock.now();
+        let session_timeout = self.options.session_timeout;
+        self.sessions.retain(|session_id, server_session| {
+            let idle = now.saturating_duration_since(server_session.last_seen);
+            let timeout = if server_session.session.is_closed() {
+                CLOSED_SESSION_LINGER
+            } else {
+                session_timeout
+            };
+            if idle < timeout {
+                return true;
+            }
+            debug!("evicting session {} idle for {:?}", session_id, idle);
+            let mut stream = server_session.stream.lock().expect("stream state poisoned");
+            if !stream.closed {
+                stream.set_closed();
+            }
+            false
+        });
+    }
+
+    fn accept_session(
+        &mut self,
+        packet: Packet<SessionBodyBytes>,
+    ) -> Option<Option<Packet<SessionBodyBytes>>> {
+        let session_id = packet.head.session_id;
+        let handshakes = self
+            .sessions
+            .values()
+            .filter(|existing| existing.is_handshaking())
+            .count();
+        if handshakes >= self.options.max_handshakes {
+            warn!(
+                "session {} rejected with {} sessions in handshake",
+                session_id, handshakes
+            );
+            return None;
+        }
+        let (encryption, packet) = match packet.kind() {
+            PacketKind::ENC => {
+                let body = match EncBody::decode_body(&packet.head, &mut packet.body.0.clone()) {
+                    Ok(body) => body,
+                    Err(err) => {
+                        warn!("session {} invalid encryption init: {}", session_id, err);
+                        return None;
+                    }
+                };
+                let public_key = match body.into_body() {
+                    EncBodyVariant::Init { public_key } => public_key,
+                    EncBodyVariant::Auth { .. } => {
+                        debug!("session {} not initialised for auth", session_id);
+                        return None;
+                    }
+                };
+                match self.acceptor.accept(public_key) {
+                    // The acceptor has already performed the handshake
+                    // given the client's public key.
+                    Ok(encryption) => (Some(encryption), None),
+                    Err(err) => {
+                        warn!("session {} encryption rejected: {}", session_id, err);
+                        return None;
+                    }
+                }
+            }
+            PacketKind::SYN if self.options.insecure => (None, Some(packet)),
+            PacketKind::SYN => {
+                warn!("session {} rejected as it is not encrypted", session_id);
+                return None;
+            }
+            kind => {
+                debug!("session {} unknown for {:?}", session_id, kind);
+                return None;
+            }
+        };
+        let init_seq = Sequence(self.random.gen());
+        let clock = self.options.clock.clone();
+        let mut session = Session::new(
+            SessionRole::Server,
+            session_id,
+            init_seq,
+            self.random.clone(),
+            clock,
+            encryption,
+        );
+        if session.is_encrypted() {
+            session.stage = SessionStage::EncryptInit;
+        }
+        session.windowed = self.options.window;
+        session.prefer_peer_name = self.options.prefer_client_name;
+        session.packet_trace = self.options.packet_trace;
+        let stream = StreamState::new(self.options.send_buf_size);
+        let last_seen = self.options.clock.now();
+        debug!("initialising session {}", session_id);
+        self.sessions.insert(
+            session_id,
+            ServerSession {
+                session,
+                stream: Arc::new(Mutex::new(stream)),
+                accepted: false,
+                last_request: None,
+                last_response: None,
+                unacked: Bytes::new(),
+                last_seen,
+            },
+        );
+        Some(packet)
+    }
+}
+
+impl<E, R> ServerSession<E, R>
+where
+    E: Encryption,
+    R: Rng,
+{
+    /// Returns `true` if the session is yet to be established.
+    fn is_handshaking(&self) -> bool {
+        !self.session.stage().is_established() && !self.session.is_closed()
+    }
+
+    fn handle_packet(
+        &mut self,
+        packet: Option<Packet<SessionBodyBytes>>,
+        request: &Bytes,
+        budget: usize,
+    ) -> Option<Packet<SessionBodyBytes>> {
+        let session_id = self.session.id();
+        let acked_from = self.session.self_seq;
+        let result = match packet {
+            Some(packet) => self.session.handle_inbound(packet),
+            None => Ok(None),
+        };
+        let mut stream = self.stream.lock().expect("stream state poisoned");
+        match result {
+            Ok(Some(data)) => stream.push_recv(data),
+            Ok(None) | Err(SessionError::Closed) if self.session.is_closed() => {
+                info!(
+                    "session {} closed (reason: {})",
+                    session_id,
+                    self.session.close_reason.as_deref().unwrap_or("<none>")
+                );
+            }
+            Ok(None) => (),
+            Err(err) => {
+                if self.last_request.as_ref() == Some(request) {
+                    debug!("re-transmitting last response to session {}", session_id);
+                    return self.last_response.clone();
+                }
+                warn!("session {} error: {}", session_id, err);
+                return None;
+            }
+        }
+        let result = match self.session.stage() {
+            SessionStage::EncryptInit => self.session.build_enc_init(),
+            SessionStage::EncryptAuth => self.session.build_enc_auth(),
+            SessionStage::SessionInit => self.session.build_syn(),
+            SessionStage::Send if stream.should_close() && self.unacked.is_empty() => {
+                self.session.build_fin("")
+            }
+            SessionStage::Send if self.session.is_windowed() => {
+                let acked = acked_from.steps_to(self.session.self_seq) as usize;
+                self.unacked.advance(acked.min(self.unacked.len()));
+                let max_len = self.session.max_data_chunk_size(budget) as usize;
+                if self.unacked.is_empty() {
+                    self.unacked = stream.take_send(max_len);
+                    self.session.build_msg(self.unacked.clone())
+                } else {
+                    // Responses may be lost, so keep sending the oldest
+                    // data until the client acknowledges it.
+                    let chunk = self.unacked.slice(..self.unacked.len().min(max_len));
+                    self.session.build_msg_at(self.session.self_seq, chunk)
+                }
+            }
+            SessionStage::Send => {
+                let max_len = self.session.max_data_chunk_size(budget) as usize;
+                self.session.build_msg(stream.take_send(max_len))
+            }
+            SessionStage::Closed => self.session.build_fin(""),
+            stage => {
+                warn!("session {} unable to respond in {:?}", session_id, stage);
+                return None;
+            }
+        };
+        if self.session.is_closed() && !stream.closed {
+            stream.set_closed();
+        }
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                warn!("session {} error: {}", session_id, err);
+                return None;
+            }
+        };
+        self.last_request = Some(request.clone());
+        self.last_response = Some(response.clone());
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::pin_mut;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg32;
+
+    use super::*;
+    use crate::client::ClientBuilder;
+    use crate::clock::{Simulation, SystemClock};
+    use crate::encryption::{StandardEncryption, StandardEncryptionAcceptor};
+    use crate::transport::channel::{channel_pair, ChannelTransport};
+    use crate::transport::{MemoryTransport, TransportMux};
+
+    const SECRET: &str = "dragons";
+
+    async fn serve_echo<T, A, R>(mut server: Server<T, A, R>)
+    where
+        T: Transport<LazyPacket>,
+        A: EncryptionAcceptor,
+        R: Rng + Clone,
+    {
+        let mut stream = server.accept().await.unwrap();
+        assert_eq!(stream.name(), Some("test"));
+        let echo = async move {
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf[..]).await.unwrap();
+            stream.write_all(&buf[..]).await.unwrap();
+            stream.flush().await.unwrap();
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            assert!(stream.is_closed());
+        };
+        let drive = server.accept();
+        pin_mut!(echo, drive);
+        future::select(echo, drive).await;
+    }
+
+    fn client_builder() -> ClientBuilder {
+        ClientBuilder::default()
+            .session_name("test")
+            .max_delay(Duration::from_millis(10))
+    }
+
+    #[tokio::test]
+    async fn test_server_insecure_echo() {
+        let (client_transport, server_transport) = channel_pair();
+        let server = ServerBuilder::default().build_insecure(server_transport);
+        let client = async {
+            let mut client = client_builder()
+                .connect_insecure(client_transport)
+                .await
+                .unwrap();
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            client.read_exact(&mut buf[..]).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            client.close().await.unwrap();
+        };
+        future::join(client, serve_echo(server)).await;
+    }
+
+    #[tokio::test]
+    async fn test_server_encrypted_echo() {
+        let (client_transport, server_transport) = channel_pair();
+        let acceptor = StandardEncryptionAcceptor::new(Some(SECRET.into()));
+        let server = ServerBuilder::default().build(server_transport, acceptor);
+        let client = async {
+            let encryption =
+                StandardEncryption::new_with_ephemeral(true, Some(SECRET.into())).unwrap();
+            let mut client = client_builder()
+                .connect(client_transport, encryption)
+                .await
+                .unwrap();
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            client.read_exact(&mut buf[..]).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            client.close().await.unwrap();
+        };
+        future::join(client, serve_echo(server)).await;
+    }
+
+    async fn windowed_echo<A>(
+        mut server: Server<ChannelTransport, A, rand::rngs::ThreadRng>,
+        client_transport: ChannelTransport,
+    ) -> bool
+    where
+        A: EncryptionAcceptor,
+    {
+        let data = (0..2048).map(|i| i as u8).collect::<Vec<_>>();
+        let client = async {
+            let mut client = client_builder()
+                .window_size(8)
+                .connect_insecure(client_transport)
+                .await
+                .unwrap();
+            client.write_all(&data[..]).await.unwrap();
+            let mut buf = vec![0u8; data.len()];
+            client.read_exact(&mut buf[..]).await.unwrap();
+            assert_eq!(buf, data);
+            client.close().await.unwrap();
+            client.session().is_windowed()
+        };
+        let serve = async {
+            let mut stream = server.accept().await.unwrap();
+            let echo = async move {
+                let mut buf = vec![0u8; 2048];
+                stream.read_exact(&mut buf[..]).await.unwrap();
+                stream.write_all(&buf[..]).await.unwrap();
+                stream.flush().await.unwrap();
+                let mut rest = Vec::new();
+                stream.read_to_end(&mut rest).await.unwrap();
+            };
+            let drive = server.accept();
+            pin_mut!(echo, drive);
+            future::select(echo, drive).await;
+        };
+        future::join(client, serve).await.0
+    }
+
+    #[tokio::test]
+    async fn test_server_windowed_echo() {
+        let (client_transport, server_transport) = channel_pair();
+        let server = ServerBuilder::default().build_insecure(server_transport);
+        assert!(windowed_echo(server, client_transport).await);
+    }
+
+    #[tokio::test]
+    async fn test_server_window_unsupported() {
+        let (client_transport, server_transport) = channel_pair();
+        let server = ServerBuilder::default()
+            .window(false)
+            .build_insecure(server_transport);
+        assert!(!windowed_echo(server, client_transport).await);
+    }
+
+    #[tokio::test]
+    async fn test_server_faulty_echo() {
+        type Fault = fn(MemoryTransport<Pcg32>) -> MemoryTransport<Pcg32>;
+        let faults: &[Fault] = &[
+            |transport| transport.loss(0.2),
+            |transport| transport.duplication(0.2),
+            |transport| transport.reordering(0.2),
+            |transport| transport.corruption(0.2),
+        ];
+        for fault in faults {
+            let (client_transport, server_transport) =
+                MemoryTransport::pair_with_random(Pcg32::seed_from_u64(1), Pcg32::seed_from_u64(2));
+            let client_transport = fault(client_transport)
+                .latency(Duration::from_millis(1))
+                .recv_timeout(Some(Duration::from_millis(50)));
+            let server_transport = fault(server_transport);
+            // Encryption rejects corrupted data that still decodes.
+            let acceptor = StandardEncryptionAcceptor::new(Some(SECRET.into()));
+            let server = ServerBuilder::default().build(server_transport, acceptor);
+            let client = async {
+                let encryption =
+                    StandardEncryption::new_with_ephemeral(true, Some(SECRET.into())).unwrap();
+                let mut client = client_builder()
+                    .retransmit_backoff(false)
+                    .max_retransmits(None)
+                    .connect(client_transport, encryption)
+                    .await
+                    .unwrap();
+                client.write_all(b"hello").await.unwrap();
+                let mut buf = [0u8; 5];
+                client.read_exact(&mut buf[..]).await.unwrap();
+                assert_eq!(&buf, b"hello");
+                client.close().await.unwrap();
+            };
+            future::join(client, serve_echo(server)).await;
+        }
+    }
+
+    fn simulate_lossy_echo(seed: u64) -> Duration {
+        let sim = Simulation::new();
+        let clock = sim.clock();
+        let (client_transport, server_transport) = MemoryTransport::pair_with_random(
+            Pcg32::seed_from_u64(seed),
+            Pcg32::seed_from_u64(seed + 1),
+        );
+        let client_transport = client_transport
+            .loss(0.2)
+            .latency(Duration::from_millis(50))
+            .recv_timeout(Some(Duration::from_secs(1)))
+            .clock(clock.clone());
+        let server_transport = server_transport
+            .loss(0.2)
+            .latency(Duration::from_millis(50))
+            .clock(clock.clone());
+        let server = ServerBuilder::default_with_random(Pcg32::seed_from_u64(seed + 2))
+            .clock(clock.clone())
+            .build_insecure(server_transport);
+        let client = async {
+            let mut client = ClientBuilder::default_with_random(Pcg32::seed_from_u64(seed + 3))
+                .session_name("test")
+                .max_retransmits(None)
+                .clock(clock.clone())
+                .connect_insecure(client_transport)
+                .await
+                .unwrap();
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            client.read_exact(&mut buf[..]).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            client.close().await.unwrap();
+        };
+        sim.run(future::join(client, serve_echo(server)));
+        clock.elapsed()
+    }
+
+    #[test]
+    fn test_simulated_lossy_echo() {
+        let elapsed = simulate_lossy_echo(1);
+        assert!(elapsed > Duration::from_secs(0));
+        assert_eq!(simulate_lossy_echo(1), elapsed);
+    }
+
+    #[test]
+    fn test_simulated_muxed_duplicated_echo() {
+        let sim = Simulation::new();
+        let clock = sim.clock();
+        let (client_transport, server_transport) =
+            MemoryTransport::pair_with_random(Pcg32::seed_from_u64(1), Pcg32::seed_from_u64(2));
+        let client_transport = client_transport
+            .recv_timeout(Some(Duration::from_secs(1)))
+            .clock(clock.clone());
+        // Every reply is sent twice, so each stale reply is rejected.
+        let server_transport = server_transport.duplication(1.0).clock(clock.clone());
+        let mux = TransportMux::new(client_transport);
+        let server = ServerBuilder::default_with_random(Pcg32::seed_from_u64(3))
+            .clock(clock.clone())
+            .build_insecure(server_transport);
+        let client = async {
+            let mut client = ClientBuilder::default_with_random(Pcg32::seed_from_u64(4))
+                .session_name("test")
+                .max_retransmits(None)
+                .retransmit_backoff(true)
+                .clock(clock.clone())
+                .connect_insecure(mux.handle())
+                .await
+                .unwrap();
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            client.read_exact(&mut buf[..]).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            client.close().await.unwrap();
+        };
+        sim.run(future::join(client, serve_echo(server)));
+    }
+
+    #[tokio::test]
+    async fn test_server_rejects_insecure() {
+        let (client_transport, server_transport) = channel_pair();
+        let mut server = ServerBuilder::default().build(
+            server_transport,
+            StandardEncryptionAcceptor::new(Some(SECRET.into())),
+        );
+        let mut client_transport = client_transport;
+        let mut session = Session::new(
+            SessionRole::Client,
+            1,
+            Sequence(0),
+            rand::thread_rng(),
+            Arc::new(SystemClock),
+            None::<StandardEncryption>,
+        );
+        let syn = session.build_syn().unwrap();
+        future::poll_fn(|cx| client_transport.poll_send(cx, syn.clone().translate()))
+            .await
+            .unwrap();
+        let is_pending = future::poll_fn(|cx| Poll::Ready(server.poll_accept(cx).is_pending()));
+        assert!(is_pending.await);
+        assert!(server.sessions.is_empty());
+    }
+
+    #[test]
+    fn test_server_evicts_closed_session() {
+        let sim = Simulation::new();
+        let clock = sim.clock();
+        let (client_transport, server_transport) = MemoryTransport::pair();
+        let mux = TransportMux::new(client_transport.clock(clock.clone()));
+        let mut server = ServerBuilder::default()
+            .clock(clock.clone())
+            .build_insecure(server_transport.clock(clock.clone()));
+        let client = async {
+            let mut client = ClientBuilder::default()
+                .clock(clock.clone())
+                .connect_insecure(mux.handle())
+                .await
+                .unwrap();
+            client.close().await.unwrap();
+            clock.delay(CLOSED_SESSION_LINGER).await;
+            // Any request drives the eviction.
+            ClientBuilder::default()
+                .clock(clock.clone())
+                .ping(mux.handle(), "hello")
+                .await
+                .unwrap();
+        };
+        {
+            let drive = async {
+                let mut streams = Vec::new();
+                loop {
+                    streams.push(server.accept().await.unwrap());
+                }
+            };
+            pin_mut!(client, drive);
+            sim.run(future::select(client, drive));
+        }
+        assert!(server.sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_server_caps_handshakes() {
+        let (mut client_transport, server_transport) = channel_pair();
+        let mut server = ServerBuilder::default().max_handshakes(1).build(
+            server_transport,
+            StandardEncryptionAcceptor::new(Some(SECRET.into())),
+        );
+        for session_id in 1..=2 {
+            let encryption =
+                StandardEncryption::new_with_ephemeral(true, Some(SECRET.into())).unwrap();
+            let mut session = Session::new(
+                SessionRole::Client,
+                session_id,
+                Sequence(0),
+                rand::thread_rng(),
+                Arc::new(SystemClock),
+                Some(encryption),
+            );
+            let enc_init = session.build_enc_init().unwrap();
+            future::poll_fn(|cx| client_transport.poll_send(cx, enc_init.clone().translate()))
+                .await
+                .unwrap();
+            let is_pending = future::poll_fn(|cx| Poll::Ready(server.poll_accept(cx).is_pending()));
+            assert!(is_pending.await);
+        }
+        assert_eq!(server.sessions.len(), 1);
+        assert!(server.sessions.contains_key(&1));
+    }
+}
@@ -0,0 +1,235 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use failure::Fail;
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::command::CommandStatus;
+
+use super::{CommandError, CommandHandle};
+
+const VERSION: u8 = 0x05;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_GENERAL_FAILURE: u8 = 0x01;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+#[derive(Debug, Fail)]
+pub enum SocksError {
+    #[fail(display = "IO error: {}", _0)]
+    Io(io::Error),
+    #[fail(display = "Unsupported SOCKS version {}", _0)]
+    Version(u8),
+    #[fail(display = "No acceptable authentication method")]
+    NoAcceptableMethod,
+    #[fail(display = "Unsupported SOCKS command {:#04x}", _0)]
+    UnsupportedCommand(u8),
+    #[fail(display = "Unsupported SOCKS address type {:#04x}", _0)]
+    UnsupportedAddress(u8),
+    #[fail(display = "Command error: {}", _0)]
+    Command(CommandError),
+}
+
+impl From<io::Error> for SocksError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// Serves a SOCKS5 `CONNECT` request on a stream, relaying the connection
+/// through a tunnel opened by the client of a command session.
+///
+/// Only the no authentication method is supported.
+pub async fn socks5_tunnel<S>(mut stream: S, handle: &CommandHandle) -> Result<(), SocksError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (host, port) = handshake(&mut stream).await?;
+    debug!("SOCKS5 connect to {}:{}", host, port);
+    match handle.tunnel_connect(&host, port).await {
+        Ok(tunnel) => {
+            reply(&mut stream, REP_SUCCEEDED).await?;
+            tunnel.relay(stream).await?;
+            Ok(())
+        }
+        Err(err) => {
+            let rep = match err {
+                CommandError::Status(CommandStatus::FAILURE, _) => REP_HOST_UNREACHABLE,
+                _ => REP_GENERAL_FAILURE,
+            };
+            reply(&mut stream, rep).await?;
+            Err(SocksError::Command(err))
+        }
+    }
+}
+
+/// Negotiates the authentication method and reads the `CONNECT` request,
+/// returning the requested host and port.
+async fn handshake<S>(stream: &mut S) -> Result<(String, u16), SocksError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting[..]).await?;
+    if greeting[0] != VERSION {
+        return Err(SocksError::Version(greeting[0]));
+    }
+    let mut methods = vec![0u8; greeting[1] as usize];
+    stream.read_exact(&mut methods[..]).await?;
+    if !methods.contains(&METHOD_NO_AUTH) {
+        stream.write_all(&[VERSION, METHOD_NONE_ACCEPTABLE]).await?;
+        return Err(SocksError::NoAcceptableMethod);
+    }
+    stream.write_all(&[VERSION, METHOD_NO_AUTH]).await?;
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request[..]).await?;
+    let [version, command, _, addr_type] = request;
+    if version != VERSION {
+        return Err(SocksError::Version(version));
+    }
+    if command != CMD_CONNECT {
+        reply(stream, REP_COMMAND_NOT_SUPPORTED).await?;
+        return Err(SocksError::UnsupportedCommand(command));
+    }
+    let host = match addr_type {
+        ATYP_IPV4 => {
+            let mut addr = [0u8; 4];
+            stream.read_exact(&mut addr[..]).await?;
+            Ipv4Addr::from(addr).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut addr = [0u8; 16];
+            stream.read_exact(&mut addr[..]).await?;
+            Ipv6Addr::from(addr).to_string()
+        }
+        ATYP_DOMAIN => {
+            let mut domain = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut domain[..]).await?;
+            String::from_utf8_lossy(&domain[..]).into_owned()
+        }
+        other => {
+            reply(stream, REP_ADDRESS_NOT_SUPPORTED).await?;
+            return Err(SocksError::UnsupportedAddress(other));
+        }
+    };
+    let port = stream.read_u16().await?;
+    Ok((host, port))
+}
+
+async fn reply<S>(stream: &mut S, rep: u8) -> Result<(), io::Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    #[rustfmt::skip]
+    let reply = [
+        VERSION, rep, 0x00,
+        ATYP_IPV4, 0x00, 0x00, 0x00, 0x00, // Bound address
+        0x00, 0x00, // Bound port
+    ];
+    stream.write_all(&reply[..]).await?;
+    stream.flush().await
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::server::stream::StreamState;
+    use crate::server::{CommandController, ServerStream};
+
+    async fn assert_handshake(request: &[u8], host: &str, port: u16) {
+        let (mut local, mut remote) = tokio::io::duplex(64);
+        local.write_all(&[0x05, 0x02, 0x02, 0x00]).await.unwrap();
+        local.write_all(request).await.unwrap();
+        assert_eq!(
+            handshake(&mut remote).await.unwrap(),
+            (host.to_string(), port)
+        );
+        let mut method = [0u8; 2];
+        local.read_exact(&mut method[..]).await.unwrap();
+        assert_eq!(method, [0x05, 0x00]);
+    }
+
+    #[tokio::test]
+    #[rustfmt::skip]
+    async fn test_socks_handshake_domain() {
+        assert_handshake(
+            &[
+                0x05, 0x01, 0x00, 0x03, // Connect to domain
+                0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', // Domain
+                0x00, 0x50, // Port
+            ],
+            "example",
+            80,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    #[rustfmt::skip]
+    async fn test_socks_handshake_ipv4() {
+        assert_handshake(
+            &[
+                0x05, 0x01, 0x00, 0x01, // Connect to IPv4
+                0x0A, 0x00, 0x00, 0x01, // Address
+                0x1F, 0x90, // Port
+            ],
+            "10.0.0.1",
+            8080,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_socks_unsupported_command() {
+        let (mut local, mut remote) = tokio::io::duplex(64);
+        local
+            .write_all(&[0x05, 0x01, 0x00, 0x05, 0x02, 0x00, 0x01])
+            .await
+            .unwrap();
+        assert!(matches!(
+            handshake(&mut remote).await,
+            Err(SocksError::UnsupportedCommand(0x02))
+        ));
+        let mut response = [0u8; 12];
+        local.read_exact(&mut response[..]).await.unwrap();
+        assert_eq!(response[..4], [0x05, 0x00, 0x05, 0x07]);
+    }
+
+    #[tokio::test]
+    async fn test_socks_no_command_session() {
+        let (mut local, remote) = tokio::io::duplex(64);
+        #[rustfmt::skip]
+        local.write_all(&[
+            0x05, 0x01, 0x00, // Greeting
+            0x05, 0x01, 0x00, 0x01, 0x7F, 0x00, 0x00, 0x01, 0x00, 0x50, // Request
+        ]).await.unwrap();
+        let state = StreamState::new(64);
+        let stream = ServerStream::new(1, None, true, Arc::new(Mutex::new(state)));
+        let (controller, handle) = CommandController::new(stream);
+        drop(controller);
+        assert!(matches!(
+            socks5_tunnel(remote, &handle).await,
+            Err(SocksError::Command(CommandError::Closed))
+        ));
+        let mut response = [0u8; 12];
+        local.read_exact(&mut response[..]).await.unwrap();
+        assert_eq!(response[..4], [0x05, 0x00, 0x05, 0x01]);
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::{cmp, io};
+
+use bytes::{Bytes, BytesMut};
+use futures::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead as TokioAsyncRead, AsyncWrite as TokioAsyncWrite, ReadBuf};
+
+use crate::packet::SessionId;
+
+/// State shared between a [`Server`](super::Server) and the
+/// [`ServerStream`] of an accepted session.
+#[derive(Debug)]
+pub(super) struct StreamState {
+    /// Data written by the stream waiting to be sent to the client.
+    pub(super) send_buf: BytesMut,
+    /// The max length of the send buffer before writes are blocked.
+    pub(super) send_buf_size: usize,
+    /// Data received from the client waiting to be read.
+    pub(super) recv_queue: VecDeque<Bytes>,
+    /// Set when the stream has requested the session be closed.
+    pub(super) closing: bool,
+    /// Set when the session has closed.
+    pub(super) closed: bool,
+    read_task: Option<Waker>,
+    write_task: Option<Waker>,
+    close_task: Option<Waker>,
+}
+
+impl StreamState {
+    pub(super) fn new(send_buf_size: usize) -> Self {
+        Self {
+            send_buf: BytesMut::new(),
+            send_buf_size,
+            recv_queue: VecDeque::new(),
+            closing: false,
+            closed: false,
+            read_task: None,
+            write_task: None,
+            close_task: None,
+        }
+    }
+
+    /// Push a chunk received from the client.
+    pub(super) fn push_recv(&mut self, chunk: Bytes) {
+        self.recv_queue.push_back(chunk);
+        if let Some(waker) = self.read_task.take() {
+            waker.wake();
+        }
+    }
+
+    /// Take at most `max_len` bytes of data to send to the client.
+    pub(super) fn take_send(&mut self, max_len: usize) -> Bytes {
+        let len = cmp::min(self.send_buf.len(), max_len);
+        let chunk = self.send_buf.split_to(len).freeze();
+        if let Some(waker) = self.write_task.take() {
+            waker.wake();
+        }
+        chunk
+    }
+
+    /// Returns `true` if the stream wants to close and all data has been sent.
+    pub(super) fn should_close(&self) -> bool {
+        self.closing && self.send_buf.is_empty()
+    }
+
+    /// Mark the session as closed, waking any waiting tasks.
+    pub(super) fn set_closed(&mut self) {
+        self.closed = true;
+        let tasks = [
+            self.read_task.take(),
+            self.write_task.take(),
+            self.close_task.take(),
+        ];
+        for waker in tasks.iter().flatten() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// A handle to a session accepted by a [`Server`](super::Server).
+///
+/// Data is only exchanged with the client while the server is being polled.
+#[derive(Debug)]
+pub struct ServerStream {
+    id: SessionId,
+    name: Option<String>,
+    is_command: bool,
+    state: Arc<Mutex<StreamState>>,
+    recv_buf: Bytes,
+}
+
+impl ServerStream {
+    pub(super) fn new(
+        id: SessionId,
+        name: Option<String>,
+        is_command: bool,
+        state: Arc<Mutex<StreamState>>,
+    ) -> Self {
+        Self {
+            id,
+            name,
+            is_command,
+            state,
+            recv_buf: Bytes::new(),
+        }
+    }
+
+    /// Returns session ID.
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// Returns session name.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns `true` if the client indicated this is a command session.
+    pub fn is_command(&self) -> bool {
+        self.is_command
+    }
+
+    /// Returns `true` if the session is closed.
+    pub fn is_closed(&self) -> bool {
+        self.state().closed
+    }
+
+    fn state(&self) -> MutexGuard<'_, StreamState> {
+        self.state.lock().expect("stream state poisoned")
+    }
+
+    fn do_poll_read(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<usize> {
+        if self.recv_buf.is_empty() {
+            let mut state = self.state();
+            match state.recv_queue.pop_front() {
+                Some(chunk) => {
+                    drop(state);
+                    self.recv_buf = chunk;
+                }
+                // End of stream.
+                None if state.closed => return Poll::Ready(0),
+                None => {
+                    state.read_task = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+            }
+        }
+        let len = cmp::min(buf.remaining(), self.recv_buf.len());
+        buf.put_slice(&self.recv_buf.split_to(len)[..]);
+        Poll::Ready(len)
+    }
+
+    fn do_poll_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let mut state = self.state();
+        if state.closing || state.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let len = cmp::min(
+            state.send_buf_size.saturating_sub(state.send_buf.len()),
+            buf.len(),
+        );
+        if len == 0 && !buf.is_empty() {
+            state.write_task = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        state.send_buf.extend_from_slice(&buf[..len]);
+        Poll::Ready(Ok(len))
+    }
+
+    fn do_poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let mut state = self.state();
+        if state.send_buf.is_empty() {
+            Poll::Ready(Ok(()))
+        } else if state.closed {
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        } else {
+            state.write_task = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn do_poll_close(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        let mut state = self.state();
+        if state.closed {
+            return Poll::Ready(Ok(()));
+        }
+        state.closing = true;
+        state.close_task = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for ServerStream {
+    fn drop(&mut self) {
+        // Close the session once any remaining data is sent.
+        if let Ok(mut state) = self.state.lock() {
+            state.closing = true;
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+impl AsyncRead for ServerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        self.get_mut()
+            .do_poll_read(cx, &mut ReadBuf::new(buf))
+            .map(Ok)
+    }
+}
+
+impl AsyncWrite for ServerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        self.get_mut().do_poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.get_mut().do_poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.get_mut().do_poll_close(cx)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+impl TokioAsyncRead for ServerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        self.get_mut().do_poll_read(cx, buf).map(|_| Ok(()))
+    }
+}
+
+impl TokioAsyncWrite for ServerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        self.get_mut().do_poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.get_mut().do_poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.get_mut().do_poll_close(cx)
+    }
+}
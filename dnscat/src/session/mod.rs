@@ -1,5 +1,11 @@
+#[cfg(feature = "persist")]
+mod state;
+
 use std::borrow::Cow;
+use std::sync::Arc;
 use std::time::Instant;
+#[cfg(feature = "persist")]
+use std::time::SystemTime;
 use std::{cmp, fmt};
 
 use bytes::Bytes;
@@ -7,10 +13,14 @@ use failure::Fail;
 use log::{debug, trace};
 use rand::Rng;
 
+use crate::clock::Clock;
 use crate::encryption::*;
 use crate::packet::*;
 use crate::transport::*;
 
+#[cfg(feature = "persist")]
+pub use self::state::{SessionState, SessionStateError};
+
 #[derive(Debug, Fail)]
 pub enum SessionError {
     #[fail(display = "Session is closed")]
@@ -62,6 +72,9 @@ pub enum SessionError {
     },
     #[fail(display = "Session packet decode error: {}", _0)]
     SessionBodyDecode(PacketDecodeError),
+    #[cfg(feature = "persist")]
+    #[fail(display = "Session state error: {}", _0)]
+    State(SessionStateError),
 }
 
 impl From<PacketDecodeError> for SessionError {
@@ -76,6 +89,13 @@ impl From<EncryptionError> for SessionError {
     }
 }
 
+#[cfg(feature = "persist")]
+impl From<SessionStateError> for SessionError {
+    fn from(err: SessionStateError) -> Self {
+        Self::State(err)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SessionStage {
     /// Session is uninitialized.
@@ -120,6 +140,8 @@ pub struct Session<T, R> {
     pub(crate) name: Option<Cow<'static, str>>,
     /// Random source.
     pub(crate) random: R,
+    /// Time source.
+    pub(crate) clock: Arc<dyn Clock>,
     /// The peer sequence for receiving data.
     pub(crate) peer_seq: Sequence,
     /// This session's sequence for sending data.
@@ -130,6 +152,9 @@ pub struct Session<T, R> {
     pub(crate) is_command: bool,
     /// Whether or not this is a client to server session.
     pub(crate) role: SessionRole,
+    /// Whether or not several `MSG` packets may be in flight, requested
+    /// until the peer's `SYN` is handled.
+    pub(crate) windowed: bool,
     /// Session stage.
     pub(crate) stage: SessionStage,
     /// The reason the session was closing/closed.
@@ -147,6 +172,9 @@ pub struct Session<T, R> {
     pub(crate) exchange_attempt: Option<usize>,
     /// The max number of retransmissions before closing.
     pub(crate) max_exchange_attempts: Option<usize>,
+    /// The max size of datagrams if limited, such as by probing the
+    /// largest that survives the transport.
+    pub(crate) max_datagram_size: Option<usize>,
 }
 
 impl<T, R> Session<T, R>
@@ -154,6 +182,39 @@ where
     T: Encryption,
     R: Rng,
 {
+    /// Constructs an uninitialised session, with the remaining options
+    /// left to their defaults.
+    pub(crate) fn new(
+        role: SessionRole,
+        id: SessionId,
+        init_seq: Sequence,
+        random: R,
+        clock: Arc<dyn Clock>,
+        encryption: Option<T>,
+    ) -> Self {
+        Self {
+            id,
+            name: None,
+            random,
+            clock,
+            peer_seq: Sequence(0),
+            self_seq: init_seq,
+            self_seq_pending: init_seq,
+            is_command: false,
+            role,
+            windowed: false,
+            stage: SessionStage::Uninit,
+            close_reason: None,
+            encryption,
+            prefer_peer_name: false,
+            packet_trace: false,
+            last_exchange: None,
+            exchange_attempt: None,
+            max_exchange_attempts: None,
+            max_datagram_size: None,
+        }
+    }
+
     /// Returns session ID.
     pub fn id(&self) -> SessionId {
         self.id
@@ -164,6 +225,11 @@ where
         self.name.as_ref().map(AsRef::as_ref)
     }
 
+    /// Returns the max size of datagrams if limited.
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        self.max_datagram_size
+    }
+
     /// Returns `true` if this is a command session.
     pub fn is_command(&mut self) -> bool {
         self.is_command
@@ -174,6 +240,11 @@ where
         self.encryption.is_some()
     }
 
+    /// Returns `true` if several `MSG` packets may be in flight.
+    pub fn is_windowed(&self) -> bool {
+        self.windowed
+    }
+
     /// Returns the current session stage.
     pub fn stage(&self) -> SessionStage {
         self.stage
@@ -224,7 +295,7 @@ where
             },
             // We are a server and this is the client's `SYN` request.
             // This could be from a uninitialized session, or we just established encryption.
-            (Server, Uninit, SYN) | (Server, EncryptAuth, SYN) => match self.handle_syn(packet) {
+            (Server, Uninit, SYN) | (Server, SessionInit, SYN) => match self.handle_syn(packet) {
                 Ok(()) => Ok((None, SessionInit)),
                 Err(err) => Err(err),
             },
@@ -234,12 +305,15 @@ where
                 Err(err) => Err(err),
             },
             // We are either a server or client and this is a `MSG` from our peer.
-            (_, Recv, MSG) => match self.handle_msg(packet) {
-                Ok(data) => Ok((data, Send)),
-                Err(err) => Err(err),
-            },
-            // We received a FIN from the server.
-            (Client, _, FIN) => match self.handle_fin(packet) {
+            // If windowed, we may still be sending.
+            (_, Recv, MSG) | (_, Send, MSG) if self.windowed || self.stage == Recv => {
+                match self.handle_msg(packet) {
+                    Ok(data) => Ok((data, Send)),
+                    Err(err) => Err(err),
+                }
+            }
+            // We received a FIN from our peer.
+            (_, _, FIN) => match self.handle_fin(packet) {
                 Ok(()) => Ok((None, Closed)),
                 Err(err) => Err(err),
             },
@@ -319,7 +393,13 @@ where
     ) -> Result<Option<Bytes>, SessionError> {
         let body: MsgBody =
             Self::parse_packet(packet, self.encryption.as_mut(), self.packet_trace)?;
-        self.validate_exchange(body.seq(), body.ack(), body.data_len())?;
+        if self.windowed {
+            if !self.validate_window_exchange(body.seq(), body.ack(), body.data_len())? {
+                return Ok(None);
+            }
+        } else {
+            self.validate_exchange(body.seq(), body.ack(), body.data_len())?;
+        }
         let data = body.into_data();
         if data.is_empty() {
             Ok(None)
@@ -329,8 +409,12 @@ where
     }
 
     fn handle_fin(&mut self, packet: Packet<SessionBodyBytes>) -> Result<(), SessionError> {
-        let body: FinBody =
-            Self::parse_packet(packet, self.encryption.as_mut(), self.packet_trace)?;
+        let encryption = if self.is_encryption_active() {
+            self.encryption.as_mut()
+        } else {
+            None
+        };
+        let body: FinBody = Self::parse_packet(packet, encryption, self.packet_trace)?;
         self.close_reason = Some(body.reason().to_owned().into());
         Ok(())
     }
@@ -373,15 +457,20 @@ where
     }
 
     pub fn build_syn(&mut self) -> Result<Packet<SessionBodyBytes>, SessionError> {
-        if self.is_encrypted() {
-            self.assert_stage(SessionStage::SessionInit);
-        } else {
+        // A server always responds to the client's `SYN`, so will
+        // already be initialising the session.
+        if self.role == SessionRole::Client && !self.is_encrypted() {
             self.assert_stage(SessionStage::Uninit);
+        } else {
+            self.assert_stage(SessionStage::SessionInit);
         }
         let mut body = SynBody::new(self.self_seq, self.is_command);
         if let Some(ref name) = self.name {
             body.set_session_name(name.clone());
         }
+        if self.windowed {
+            body.set_windowed();
+        }
         match self.role {
             SessionRole::Client => self.set_stage(SessionStage::SessionInit),
             SessionRole::Server => self.set_stage(SessionStage::Recv),
@@ -398,10 +487,12 @@ where
 
     pub fn build_msg(&mut self, chunk: Bytes) -> Result<Packet<SessionBodyBytes>, SessionError> {
         self.assert_stage(SessionStage::Send);
-        let mut body = MsgBody::new(self.self_seq, self.peer_seq);
+        let mut body = MsgBody::new(self.self_seq_pending, self.peer_seq);
         body.set_data(chunk);
         self.set_pending_ack(body.data_len());
-        self.set_stage(SessionStage::Recv);
+        if !self.windowed {
+            self.set_stage(SessionStage::Recv);
+        }
         self.mark_exchange_start();
         Self::build_packet(
             body,
@@ -412,6 +503,28 @@ where
         )
     }
 
+    /// Builds a `MSG` to send data again from `seq`, which must not yet be
+    /// acknowledged by the peer.
+    ///
+    /// Only supported if the session is windowed.
+    pub fn build_msg_at(
+        &mut self,
+        seq: Sequence,
+        chunk: Bytes,
+    ) -> Result<Packet<SessionBodyBytes>, SessionError> {
+        assert!(self.windowed, "session is not windowed");
+        self.assert_stage(SessionStage::Send);
+        let mut body = MsgBody::new(seq, self.peer_seq);
+        body.set_data(chunk);
+        Self::build_packet(
+            body,
+            self.id,
+            &mut self.random,
+            self.encryption.as_mut(),
+            self.packet_trace,
+        )
+    }
+
     pub fn build_fin<S>(&mut self, reason: S) -> Result<Packet<SessionBodyBytes>, SessionError>
     where
         S: Into<Cow<'static, str>>,
@@ -422,9 +535,10 @@ where
             body.set_reason(reason.to_string());
             self.close_reason = Some(reason);
         }
+        let encrypt = self.is_encryption_active();
         self.set_stage(SessionStage::Closed);
         self.mark_exchange_start();
-        let encryption = if self.stage.is_established() {
+        let encryption = if encrypt {
             self.encryption.as_mut()
         } else {
             None
@@ -447,6 +561,16 @@ where
         }
     }
 
+    /// Returns `true` if a shared secret has been agreed on and session
+    /// bodies should be encrypted.
+    fn is_encryption_active(&self) -> bool {
+        use SessionStage::*;
+        match self.stage {
+            Uninit | EncryptInit => false,
+            EncryptAuth | SessionInit | Send | Recv | Closed => self.encryption.is_some(),
+        }
+    }
+
     fn assert_stage(&self, expect: SessionStage) {
         if expect != self.stage {
             panic!("expected stage {:?}, got stage: {:?}", expect, self.stage);
@@ -456,7 +580,7 @@ where
     ///////////////////////////////////////////////////////////////////////////
 
     fn set_pending_ack(&mut self, sent: u8) {
-        self.self_seq_pending = self.self_seq.add_data(sent);
+        self.self_seq_pending = self.self_seq_pending.add_data(sent);
     }
 
     fn validate_exchange(
@@ -491,6 +615,41 @@ where
         Ok(())
     }
 
+    /// Validates an exchange when windowed, where the peer acknowledges
+    /// data cumulatively and packets may arrive out of order.
+    ///
+    /// Returns `true` if the peer's data is next in sequence and should be
+    /// accepted, otherwise it is a duplicate or arrived early and dropped.
+    fn validate_window_exchange(
+        &mut self,
+        peer_seq: Sequence,
+        peer_ack: Sequence,
+        recv_len: u8,
+    ) -> Result<bool, SessionError> {
+        let in_flight = self.self_seq.steps_to(self.self_seq_pending);
+        let acked = self.self_seq.steps_to(peer_ack);
+        if acked <= in_flight {
+            self.self_seq = peer_ack;
+        } else if peer_ack.steps_to(self.self_seq) > u16::MAX / 2 {
+            // The peer acknowledged more than we have sent, rather than
+            // this being an acknowledgement older than what we have.
+            return Err(SessionError::UnexpectedPeerAck {
+                expected: self.self_seq_pending,
+                actual: peer_ack,
+            });
+        }
+        if peer_seq != self.peer_seq {
+            debug!(
+                "dropping out of order data (expected: {}, got: {})",
+                self.peer_seq, peer_seq
+            );
+            return Ok(false);
+        }
+        debug!("data-ack: [rx: {}, tx: {}]", recv_len, acked.min(in_flight));
+        self.peer_seq = self.peer_seq.add_data(recv_len);
+        Ok(true)
+    }
+
     fn init_from_peer_syn(
         &mut self,
         syn: SynBody,
@@ -503,6 +662,11 @@ where
         }
         // Extract if the peer indicates this is a command session
         self.is_command = syn.is_command();
+        // Only use a window if both we and the peer support it
+        if self.windowed && !syn.is_windowed() {
+            debug!("peer does not support windowing");
+            self.windowed = false;
+        }
         // Extract the peer initial sequence
         self.peer_seq = syn.initial_sequence();
         // Woo!
@@ -591,13 +755,18 @@ where
         self.last_exchange
     }
 
+    /// Returns the clock the session takes its time from.
+    pub(crate) fn clock(&self) -> &dyn Clock {
+        &*self.clock
+    }
+
     fn mark_exchange_start(&mut self) {
-        self.last_exchange = Some(Instant::now());
+        self.last_exchange = Some(self.clock.now());
         self.exchange_attempt = Some(1);
     }
 
     fn mark_exchange_end(&mut self) {
-        self.last_exchange = Some(Instant::now());
+        self.last_exchange = Some(self.clock.now());
         self.exchange_attempt = None;
     }
 
@@ -605,16 +774,19 @@ where
         &mut self,
         packet: &mut Packet<SessionBodyBytes>,
     ) -> Result<(), SessionError> {
-        self.assert_stage(SessionStage::Recv);
+        // The attempt counts the transmissions made so far.
+        let attempt = self.exchange_attempt.expect("should be exchanging");
         if let Some(max_exchange_attempts) = self.max_exchange_attempts {
-            let attempt = self.exchange_attempt.unwrap_or(1);
-            if max_exchange_attempts >= attempt {
+            if attempt > max_exchange_attempts {
                 return Err(SessionError::MaxTransmitAttempts);
             }
-            self.exchange_attempt = Some(attempt);
         }
-        self.last_exchange = Some(Instant::now());
-        packet.head.set_packet_id(self.random.gen());
+        self.exchange_attempt = Some(attempt + 1);
+        self.last_exchange = Some(self.clock.now());
+        // The signature of an encrypted packet covers its header.
+        if !self.is_encryption_active() {
+            packet.head.set_packet_id(self.random.gen());
+        }
         Ok(())
     }
 
@@ -622,9 +794,14 @@ where
 
     /// Returns the max data chunk size that can be sent in one datagram.
     ///
-    /// This is calculated based on a budget, minus the cost of the framing
+    /// This is calculated based on the transport's send budget, which may
+    /// differ from what it can receive, minus the cost of the framing
     /// and/or encryption framing if enabled.
     pub fn max_data_chunk_size(&self, budget: usize) -> u8 {
+        let budget = match self.max_datagram_size {
+            Some(max) => cmp::min(budget, max),
+            None => budget,
+        };
         // Subtract the total size required from what the transport
         // can provide to get the budget we can use.
         let budget = budget - self.msg_packet_min_size() as usize;
@@ -643,7 +820,7 @@ where
         val
     }
 
-    fn msg_packet_min_size(&self) -> u8 {
+    pub(crate) fn msg_packet_min_size(&self) -> u8 {
         match self.encryption {
             Some(ref enc) => MsgBody::packet_size_no_data() + enc.args_size(),
             _ => MsgBody::packet_size_no_data(),
@@ -654,3 +831,218 @@ where
         &mut self.random
     }
 }
+
+#[cfg(feature = "persist")]
+impl<T, R> Session<T, R>
+where
+    T: EncryptionState,
+    R: Rng,
+{
+    /// Saves the state of the session so it can be resumed later.
+    ///
+    /// The session must be established with no exchange in progress. If
+    /// encrypted, the session can only send a limited number of packets
+    /// after saving before it needs to be saved again, as the encryption
+    /// nonces after that are reserved for resuming.
+    pub fn save_state(&mut self) -> Result<SessionState, SessionError> {
+        if self.stage != SessionStage::Send || self.self_seq != self.self_seq_pending {
+            return Err(SessionStateError::Stage(self.stage).into());
+        }
+        let encryption = match self.encryption {
+            Some(ref mut encryption) => Some(
+                encryption
+                    .save_state()?
+                    .ok_or(SessionStateError::Stage(self.stage))?,
+            ),
+            None => None,
+        };
+        Ok(SessionState {
+            id: self.id,
+            name: self.name.as_ref().map(ToString::to_string),
+            role: self.role,
+            is_command: self.is_command,
+            peer_seq: self.peer_seq,
+            self_seq: self.self_seq,
+            saved_at: SystemTime::now(),
+            encryption,
+        })
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::rngs::ThreadRng;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg32;
+
+    use super::*;
+    use crate::client::{ClientBuilder, ClientError};
+    use crate::clock::{Simulation, SystemClock};
+
+    fn session(role: SessionRole, windowed: bool) -> Session<NoEncryption, ThreadRng> {
+        let clock = Arc::new(SystemClock);
+        let mut session = Session::new(role, 1, Sequence(100), rand::thread_rng(), clock, None);
+        session.windowed = windowed;
+        session
+    }
+
+    fn established(
+        client_windowed: bool,
+        server_windowed: bool,
+    ) -> (
+        Session<NoEncryption, ThreadRng>,
+        Session<NoEncryption, ThreadRng>,
+    ) {
+        let mut client = session(SessionRole::Client, client_windowed);
+        let mut server = session(SessionRole::Server, server_windowed);
+        server.self_seq = Sequence(200);
+        server.self_seq_pending = Sequence(200);
+        let syn = client.build_syn().unwrap();
+        server.handle_inbound(syn).unwrap();
+        let syn = server.build_syn().unwrap();
+        client.handle_inbound(syn).unwrap();
+        (client, server)
+    }
+
+    fn msg_ack(packet: Packet<SessionBodyBytes>) -> Sequence {
+        let (head, mut body) = packet.split();
+        MsgBody::decode_body(&head, &mut body.0).unwrap().ack()
+    }
+
+    #[test]
+    fn test_window_negotiation() {
+        let (client, server) = established(true, true);
+        assert!(client.is_windowed() && server.is_windowed());
+        let (client, server) = established(true, false);
+        assert!(!client.is_windowed() && !server.is_windowed());
+        let (client, server) = established(false, true);
+        assert!(!client.is_windowed() && !server.is_windowed());
+    }
+
+    #[test]
+    fn test_window_out_of_order() {
+        let (mut client, mut server) = established(true, true);
+        let first = client.build_msg(Bytes::from_static(b"hello ")).unwrap();
+        let second = client.build_msg(Bytes::from_static(b"world")).unwrap();
+        assert_eq!(client.stage(), SessionStage::Send);
+
+        // The second arrives first and is dropped as it is early.
+        assert_eq!(server.handle_inbound(second.clone()).unwrap(), None);
+        let response = server.build_msg(Bytes::new()).unwrap();
+        assert_eq!(msg_ack(response.clone()), Sequence(100));
+        assert_eq!(client.handle_inbound(response).unwrap(), None);
+
+        assert_eq!(
+            server.handle_inbound(first.clone()).unwrap(),
+            Some(Bytes::from_static(b"hello "))
+        );
+        // Duplicates are dropped.
+        assert_eq!(server.handle_inbound(first).unwrap(), None);
+        assert_eq!(
+            server.handle_inbound(second).unwrap(),
+            Some(Bytes::from_static(b"world"))
+        );
+
+        // Cumulatively acknowledges both.
+        let response = server.build_msg(Bytes::new()).unwrap();
+        assert_eq!(msg_ack(response.clone()), Sequence(111));
+        client.handle_inbound(response).unwrap();
+        assert_eq!(client.self_seq, Sequence(111));
+        assert_eq!(client.self_seq_pending, Sequence(111));
+    }
+
+    #[test]
+    fn test_window_rejects_future_ack() {
+        let (mut client, mut server) = established(true, true);
+        let request = client.build_msg(Bytes::new()).unwrap();
+        server.handle_inbound(request).unwrap();
+        server.peer_seq = Sequence(150);
+        let response = server.build_msg(Bytes::new()).unwrap();
+        assert!(matches!(
+            client.handle_inbound(response),
+            Err(SessionError::UnexpectedPeerAck { .. })
+        ));
+    }
+
+    #[test]
+    fn test_retransmit_max_attempts() {
+        let (mut client, _) = established(false, false);
+        client.max_exchange_attempts = Some(2);
+        let mut packet = client.build_msg(Bytes::new()).unwrap();
+        // Two retransmits after the first transmit.
+        client.prepare_retransmit(&mut packet).unwrap();
+        client.prepare_retransmit(&mut packet).unwrap();
+        assert!(matches!(
+            client.prepare_retransmit(&mut packet),
+            Err(SessionError::MaxTransmitAttempts)
+        ));
+    }
+
+    #[test]
+    fn test_simulated_retransmit_give_up() {
+        let sim = Simulation::new();
+        let clock = sim.clock();
+        let (client_transport, _server_transport) =
+            MemoryTransport::pair_with_random(Pcg32::seed_from_u64(1), Pcg32::seed_from_u64(2));
+        let client_transport = client_transport
+            .loss(1.0)
+            .recv_timeout(Some(Duration::from_secs(1)))
+            .clock(clock.clone());
+        let client = ClientBuilder::default_with_random(Pcg32::seed_from_u64(3))
+            .max_retransmits(Some(4))
+            .retransmit_backoff(true)
+            .clock(clock.clone())
+            .connect_insecure(client_transport);
+        match sim.run(client) {
+            Err(ClientError::Session(SessionError::MaxTransmitAttempts)) => (),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        // Five sends timing out, with backoffs of 2, 4, 8, 16 and 32 secs.
+        assert_eq!(clock.elapsed(), Duration::from_secs(5 + 62));
+    }
+
+    #[test]
+    fn test_retransmit_handshake() {
+        let mut client = session(SessionRole::Client, false);
+        let mut syn = client.build_syn().unwrap();
+        assert_eq!(client.stage(), SessionStage::SessionInit);
+        client.prepare_retransmit(&mut syn).unwrap();
+        assert_eq!(client.exchange_attempt(), Some(2));
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn test_retransmit_encrypted() {
+        use crate::encryption::StandardEncryption;
+
+        let mut client_enc = StandardEncryption::new_with_ephemeral(true, None).unwrap();
+        let mut server_enc = StandardEncryption::new_with_ephemeral(false, None).unwrap();
+        client_enc.handshake(server_enc.public_key()).unwrap();
+        server_enc.handshake(client_enc.public_key()).unwrap();
+        let encrypted = |role, encryption| {
+            let clock = Arc::new(SystemClock);
+            let random = rand::thread_rng();
+            let mut session = Session::new(role, 1, Sequence(100), random, clock, encryption);
+            session.peer_seq = Sequence(100);
+            session
+        };
+        let mut client = encrypted(SessionRole::Client, Some(client_enc));
+        let mut server = encrypted(SessionRole::Server, Some(server_enc));
+        client.stage = SessionStage::Send;
+        server.stage = SessionStage::Recv;
+
+        let mut packet = client.build_msg(Bytes::from_static(b"hello")).unwrap();
+        let sent = packet.clone();
+        client.prepare_retransmit(&mut packet).unwrap();
+        // The packet ID is covered by the signature, so is kept.
+        assert_eq!(packet, sent);
+        assert_eq!(
+            server.handle_inbound(packet).unwrap(),
+            Some(Bytes::from_static(b"hello"))
+        );
+    }
+}
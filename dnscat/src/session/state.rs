@@ -0,0 +1,286 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Buf, BufMut};
+use failure::Fail;
+use secstr::SecStr;
+
+use crate::encryption::{EncryptionError, EncryptionState};
+use crate::packet::{Sequence, SessionId};
+
+use super::{SessionRole, SessionStage};
+
+const STATE_VERSION: u8 = 1;
+
+// version + saved at + id + role + is command + peer seq + self seq
+const STATE_HEAD_SIZE: usize = 1 + 8 + 2 + 1 + 1 + 2 + 2;
+
+#[derive(Debug, Fail)]
+pub enum SessionStateError {
+    #[fail(display = "Session state cannot be saved in stage `{:?}`", _0)]
+    Stage(SessionStage),
+    #[fail(display = "Malformed session state")]
+    Malformed,
+    #[fail(display = "Unsupported session state version {}", _0)]
+    Version(u8),
+    #[fail(display = "Session state mismatch on {}", _0)]
+    Mismatch(&'static str),
+    #[fail(display = "Session state is stale (age: {:?})", _0)]
+    Stale(Duration),
+    #[fail(display = "Encryption error: {}", _0)]
+    Encryption(EncryptionError),
+}
+
+impl From<EncryptionError> for SessionStateError {
+    fn from(err: EncryptionError) -> Self {
+        Self::Encryption(err)
+    }
+}
+
+/// The saved state of an established session, used to resume it without
+/// a new `SYN`.
+///
+/// If the session is encrypted the state holds its stream keys, which are
+/// kept protected with `secstr` and should be stored with the same care.
+/// Resuming from anything other than the latest state saved will reuse
+/// sequence numbers already sent. An encrypted state is updated as its
+/// encryption is restored, and must be persisted again before resuming,
+/// or encryption nonces will be reused.
+#[derive(Debug)]
+pub struct SessionState {
+    pub(crate) id: SessionId,
+    pub(crate) name: Option<String>,
+    pub(crate) role: SessionRole,
+    pub(crate) is_command: bool,
+    pub(crate) peer_seq: Sequence,
+    pub(crate) self_seq: Sequence,
+    pub(crate) saved_at: SystemTime,
+    pub(crate) encryption: Option<SecStr>,
+}
+
+impl SessionState {
+    /// Returns the session ID.
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// Returns the session name.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns `true` if this is the state of a command session.
+    pub fn is_command(&self) -> bool {
+        self.is_command
+    }
+
+    /// Returns `true` if the session is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    /// Returns how long ago the state was saved.
+    ///
+    /// Returns `None` if the state was saved in the future, in which case
+    /// the clock can't be trusted to tell if it is stale.
+    pub fn age(&self) -> Option<Duration> {
+        SystemTime::now().duration_since(self.saved_at).ok()
+    }
+
+    /// Encodes the state into bytes, protected as they may contain keys.
+    pub fn to_bytes(&self) -> SecStr {
+        let name = self.name.as_deref().unwrap_or("").as_bytes();
+        let encryption = self
+            .encryption
+            .as_ref()
+            .map(SecStr::unsecure)
+            .unwrap_or(&[]);
+        let saved_at = self
+            .saved_at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        // Allocated up front so no copies of the keys are left behind.
+        let len = STATE_HEAD_SIZE + 2 + name.len() + 2 + encryption.len();
+        let mut bytes = Vec::with_capacity(len);
+        bytes.put_u8(STATE_VERSION);
+        bytes.put_u64(saved_at);
+        bytes.put_u16(self.id);
+        bytes.put_u8(match self.role {
+            SessionRole::Client => 0,
+            SessionRole::Server => 1,
+        });
+        bytes.put_u8(self.is_command as u8);
+        bytes.put_u16(self.peer_seq.0);
+        bytes.put_u16(self.self_seq.0);
+        bytes.put_u16(name.len() as u16);
+        bytes.put_slice(name);
+        bytes.put_u16(encryption.len() as u16);
+        bytes.put_slice(encryption);
+        SecStr::new(bytes)
+    }
+
+    /// Decodes a state previously encoded with [`SessionState::to_bytes`].
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, SessionStateError> {
+        if bytes.remaining() < STATE_HEAD_SIZE {
+            return Err(SessionStateError::Malformed);
+        }
+        let version = bytes.get_u8();
+        if version != STATE_VERSION {
+            return Err(SessionStateError::Version(version));
+        }
+        let saved_at = UNIX_EPOCH + Duration::from_secs(bytes.get_u64());
+        let id = bytes.get_u16();
+        let role = match bytes.get_u8() {
+            0 => SessionRole::Client,
+            1 => SessionRole::Server,
+            _ => return Err(SessionStateError::Malformed),
+        };
+        let is_command = match bytes.get_u8() {
+            0 => false,
+            1 => true,
+            _ => return Err(SessionStateError::Malformed),
+        };
+        let peer_seq = Sequence(bytes.get_u16());
+        let self_seq = Sequence(bytes.get_u16());
+        let name = split_prefixed(&mut bytes)?;
+        let name = if name.is_empty() {
+            None
+        } else {
+            let name = std::str::from_utf8(name).or(Err(SessionStateError::Malformed))?;
+            Some(name.to_owned())
+        };
+        let encryption = split_prefixed(&mut bytes)?;
+        let encryption = if encryption.is_empty() {
+            None
+        } else {
+            Some(SecStr::from(encryption))
+        };
+        if bytes.has_remaining() {
+            return Err(SessionStateError::Malformed);
+        }
+        Ok(Self {
+            id,
+            name,
+            role,
+            is_command,
+            peer_seq,
+            self_seq,
+            saved_at,
+            encryption,
+        })
+    }
+
+    /// Validates the state can be resumed in the given role, returning an
+    /// error if it is mismatched or older than the max age.
+    pub(crate) fn validate(
+        &self,
+        role: SessionRole,
+        max_age: Option<Duration>,
+    ) -> Result<(), SessionStateError> {
+        if self.role != role {
+            return Err(SessionStateError::Mismatch("role"));
+        }
+        if let Some(max_age) = max_age {
+            match self.age() {
+                Some(age) if age <= max_age => {}
+                Some(age) => return Err(SessionStateError::Stale(age)),
+                None => return Err(SessionStateError::Stale(Duration::from_secs(0))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores the session encryption, returning `None` if the session
+    /// is not encrypted.
+    ///
+    /// The state is updated to resume past the encryption nonces reserved
+    /// for the encryption returned, and must be persisted in place of the
+    /// state restored before the session is resumed with it.
+    pub fn restore_encryption<E>(&mut self) -> Result<Option<E>, SessionStateError>
+    where
+        E: EncryptionState,
+    {
+        match self.encryption {
+            Some(ref mut state) => Ok(Some(E::restore_state(state)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn split_prefixed<'a>(bytes: &mut &'a [u8]) -> Result<&'a [u8], SessionStateError> {
+    if bytes.remaining() < 2 {
+        return Err(SessionStateError::Malformed);
+    }
+    let len = bytes.get_u16() as usize;
+    if bytes.remaining() < len {
+        return Err(SessionStateError::Malformed);
+    }
+    let (prefixed, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(prefixed)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> SessionState {
+        SessionState {
+            id: 0x1234,
+            name: Some("resumed".to_owned()),
+            role: SessionRole::Client,
+            is_command: true,
+            peer_seq: Sequence(10),
+            self_seq: Sequence(20),
+            saved_at: SystemTime::now(),
+            encryption: Some(SecStr::from(&[1, 2, 3][..])),
+        }
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let state = state();
+        let decoded = SessionState::from_bytes(state.to_bytes().unsecure()).unwrap();
+        assert_eq!(decoded.id, state.id);
+        assert_eq!(decoded.name, state.name);
+        assert_eq!(decoded.role, state.role);
+        assert_eq!(decoded.is_command, state.is_command);
+        assert_eq!(decoded.peer_seq, state.peer_seq);
+        assert_eq!(decoded.self_seq, state.self_seq);
+        assert_eq!(decoded.encryption, state.encryption);
+        assert!(decoded.age().unwrap() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_state_malformed() {
+        let bytes = state().to_bytes();
+        let bytes = bytes.unsecure();
+        assert!(matches!(
+            SessionState::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(SessionStateError::Malformed)
+        ));
+        let mut bad_version = bytes.to_vec();
+        bad_version[0] = 0xFF;
+        assert!(matches!(
+            SessionState::from_bytes(&bad_version[..]),
+            Err(SessionStateError::Version(0xFF))
+        ));
+    }
+
+    #[test]
+    fn test_state_validate() {
+        let mut state = state();
+        assert!(state.validate(SessionRole::Client, None).is_ok());
+        assert!(matches!(
+            state.validate(SessionRole::Server, None),
+            Err(SessionStateError::Mismatch("role"))
+        ));
+        state.saved_at -= Duration::from_secs(60);
+        assert!(matches!(
+            state.validate(SessionRole::Client, Some(Duration::from_secs(30))),
+            Err(SessionStateError::Stale(_))
+        ));
+    }
+}
@@ -0,0 +1,51 @@
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc;
+use futures::Stream;
+
+use crate::packet::LazyPacket;
+use crate::transport::Transport;
+
+/// A transport connected to another over unbounded channels, for tests.
+#[derive(Debug)]
+pub(crate) struct ChannelTransport {
+    tx: mpsc::UnboundedSender<LazyPacket>,
+    rx: mpsc::UnboundedReceiver<LazyPacket>,
+}
+
+pub(crate) fn channel_pair() -> (ChannelTransport, ChannelTransport) {
+    let (a_tx, a_rx) = mpsc::unbounded();
+    let (b_tx, b_rx) = mpsc::unbounded();
+    let a = ChannelTransport { tx: a_tx, rx: b_rx };
+    let b = ChannelTransport { tx: b_tx, rx: a_rx };
+    (a, b)
+}
+
+impl Transport<LazyPacket> for ChannelTransport {
+    type Error = Infallible;
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<LazyPacket, Self::Error>> {
+        Pin::new(&mut self.rx)
+            .poll_next(cx)
+            .map(|packet| Ok(packet.expect("channel closed")))
+    }
+
+    fn poll_send(
+        &mut self,
+        _cx: &mut Context<'_>,
+        datagram: LazyPacket,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.tx.unbounded_send(datagram).expect("channel closed");
+        Poll::Ready(Ok(()))
+    }
+
+    fn max_send_size(&self) -> usize {
+        128
+    }
+
+    fn max_recv_size(&self) -> usize {
+        128
+    }
+}
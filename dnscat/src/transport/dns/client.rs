@@ -1,20 +1,25 @@
+use std::collections::VecDeque;
 use std::fmt;
 use std::future::Future;
+use std::mem;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll, Waker};
 
 use bytes::{Bytes, BytesMut};
+use data_encoding::BASE64;
 use futures::ready;
-use log::warn;
-use tokio::net::UdpSocket;
+use log::{debug, warn};
+use tokio::net::{TcpStream, UdpSocket};
 use tokio::runtime;
 use tokio::task::JoinHandle;
 use trust_dns_client::client::AsyncClient;
 use trust_dns_proto::{
     error::ProtoError,
+    iocompat::AsyncIoTokioAsStd,
     op::Query,
     rr::{Record, RecordType},
+    tcp::TcpClientStream,
     udp::UdpClientStream,
     xfer::{DnsHandle, DnsRequestOptions, DnsResponse},
 };
@@ -22,7 +27,7 @@ use trust_dns_proto::{
 use crate::transport::{Datagram, DatagramError, SplitDatagram, Transport};
 use crate::util::hex;
 
-use super::{DnsEndpoint, DnsTransportError};
+use super::{DnsEndpoint, DnsEndpointError, DnsTransportError, TxtEncoding};
 
 const DEFAULT_LOOKUP_OPTIONS: DnsRequestOptions = DnsRequestOptions {
     use_edns: true,
@@ -30,8 +35,6 @@ const DEFAULT_LOOKUP_OPTIONS: DnsRequestOptions = DnsRequestOptions {
     expects_multiple_responses: false,
 };
 
-const MULTI_NAME_RESPONSE: bool = false;
-
 pub struct DnsClient<H, E, D>
 where
     D: Datagram,
@@ -39,9 +42,11 @@ where
     dns_handle: H,
     endpoint: E,
     runtime_handle: runtime::Handle,
-    send_task: Option<Waker>,
+    tcp_fallback: Option<SocketAddr>,
+    multi_name: bool,
+    in_order: bool,
     recv_task: Option<Waker>,
-    exchange: Option<ExchangeFuture<D>>,
+    exchanges: VecDeque<ExchangeFuture<D>>,
 }
 
 impl<E, D> DnsClient<AsyncClient, E, D>
@@ -49,10 +54,20 @@ where
     E: DnsEndpoint,
     D: Datagram,
 {
+    /// Connect to a DNS server over UDP.
+    ///
+    /// Truncated responses are retried over TCP to the same server.
     pub async fn connect(addr: SocketAddr, endpoint: E) -> Result<Self, ProtoError> {
         Self::connect_with_runtime(addr, endpoint, runtime::Handle::current()).await
     }
 
+    /// Connect to a DNS server over TCP only.
+    pub async fn connect_tcp(addr: SocketAddr, endpoint: E) -> Result<Self, ProtoError> {
+        let rt = runtime::Handle::current();
+        let client = connect_tcp_client(addr, &rt).await?;
+        Ok(Self::new(client, endpoint, rt))
+    }
+
     async fn connect_with_runtime(
         addr: SocketAddr,
         endpoint: E,
@@ -61,7 +76,7 @@ where
         let stream = UdpClientStream::<UdpSocket>::new(addr);
         let (client, bg) = AsyncClient::connect(stream).await?;
         rt.spawn(bg);
-        Ok(Self::new(client, endpoint, rt))
+        Ok(Self::new(client, endpoint, rt).tcp_fallback(Some(addr)))
     }
 }
 
@@ -74,99 +89,46 @@ where
     pub fn new(dns_handle: H, endpoint: E, runtime_handle: runtime::Handle) -> Self {
         Self {
             recv_task: None,
-            send_task: None,
-            exchange: None,
+            exchanges: VecDeque::new(),
+            tcp_fallback: None,
+            multi_name: false,
+            in_order: false,
             endpoint,
             dns_handle,
             runtime_handle,
         }
     }
 
-    fn parse_response(
-        &mut self,
-        answers: Vec<Record>,
-        record_type: RecordType,
-    ) -> Result<D, DnsTransportError<D::Error>> {
-        if answers.is_empty() {
-            return Err(DnsTransportError::NoAnswers);
-        }
-        // We will filter for the record type we requested, and
-        // drop record types we don't care about silently later.
-        let answers = answers.into_iter().map(|r| r.into_data());
-        // Parse the record data depending on the record type.
-        let mut bytes = match record_type {
-            RecordType::A => {
-                let mut buf = BytesMut::new();
-                let addrs = answers.filter_map(|d| d.into_a().ok());
-                SplitDatagram::write_iter_into(addrs, &mut buf).map_err(DatagramError::from)?;
-                buf.freeze()
-            }
-            RecordType::AAAA => {
-                let mut buf = BytesMut::new();
-                let addrs = answers.filter_map(|d| d.into_aaaa().ok());
-                SplitDatagram::write_iter_into(addrs, &mut buf).map_err(DatagramError::from)?;
-                buf.freeze()
-            }
-            RecordType::CNAME if MULTI_NAME_RESPONSE => {
-                let mut buf = BytesMut::new();
-                let mut blobs = Vec::with_capacity(answers.len());
-                let names = answers.filter_map(|d| d.into_cname().ok());
-                for name in names {
-                    blobs.push(self.endpoint.parse_cname_response(name)?);
-                }
-                SplitDatagram::write_iter_into(blobs, &mut buf).map_err(DatagramError::from)?;
-                buf.freeze()
-            }
-            RecordType::CNAME => {
-                let name = answers
-                    .filter_map(|d| d.into_cname().ok())
-                    .next()
-                    .expect("a CNAME answer");
-                self.endpoint.parse_cname_response(name)?
-            }
-            RecordType::MX if MULTI_NAME_RESPONSE => {
-                let mut buf = BytesMut::new();
-                let mut blobs = Vec::with_capacity(answers.len());
-                let names = answers.filter_map(|d| d.into_mx().ok());
-                for mx in names {
-                    blobs.push(self.endpoint.parse_mx_response(mx.exchange().clone())?);
-                }
-                SplitDatagram::write_iter_into(blobs, &mut buf).map_err(DatagramError::from)?;
-                buf.freeze()
-            }
-            RecordType::MX => {
-                let name = answers
-                    .filter_map(|d| d.into_mx().ok())
-                    .next()
-                    .expect("a MX answer");
-                self.endpoint.parse_mx_response(name.exchange().clone())?
-            }
-            RecordType::TXT => {
-                let mut buf = BytesMut::new();
-                let mut txts = answers.filter_map(|d| d.into_txt().ok());
-                if let Some(txt) = txts.next() {
-                    for blob in txt.txt_data() {
-                        hex::decode_into_buf(&mut buf, &blob[..], true)
-                            .map_err(DatagramError::from)?;
-                    }
+    /// Set the DNS server truncated responses are retried with over TCP.
+    ///
+    /// A new TCP connection is made for each retried exchange.
+    pub fn tcp_fallback(mut self, addr: Option<SocketAddr>) -> Self {
+        self.tcp_fallback = addr;
+        self
+    }
 
-                    if txts.next().is_some() {
-                        warn!("using the first of multiple txt answers received");
-                    }
-                }
-                buf.freeze()
-            }
-            other => panic!("unsupported record type: {:?}", other),
-        };
-        if bytes.is_empty() {
-            return Err(DnsTransportError::NoData);
-        }
-        let datagram = D::decode(&mut bytes).map_err(DatagramError::Decode)?;
-        if bytes.is_empty() {
-            Ok(datagram)
-        } else {
-            Err(DatagramError::Underflow.into())
-        }
+    /// Set whether CNAME and MX responses carry data split across
+    /// several answers, which the server must also be set to send.
+    pub fn multi_name(mut self, value: bool) -> Self {
+        self.multi_name = value;
+        self
+    }
+
+    /// Set whether replies are received in the order the requests were
+    /// sent, rather than as they are answered.
+    ///
+    /// Defaults to `false`. Required by a [`ResolverPool`], which matches
+    /// replies to requests in order.
+    ///
+    /// [`ResolverPool`]: crate::transport::ResolverPool
+    pub fn in_order(mut self, value: bool) -> Self {
+        self.in_order = value;
+        self
+    }
+
+    /// Returns the endpoint requests are built with.
+    pub fn endpoint(&self) -> &E {
+        &self.endpoint
     }
 }
 
@@ -179,32 +141,36 @@ where
 {
     type Error = DnsTransportError<D::Error>;
 
+    /// Receives the first answered of the exchanges in flight, or the
+    /// oldest if set to reply in order.
     fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<D, Self::Error>> {
-        match self.exchange.take() {
-            None => {
-                self.recv_task = Some(cx.waker().clone());
-                Poll::Pending
-            }
-            Some(mut exchange) => match exchange.poll(cx, self) {
-                Poll::Pending => {
-                    self.exchange = Some(exchange);
-                    Poll::Pending
-                }
-                Poll::Ready(result) => {
-                    if let Some(send_task) = self.send_task.take() {
-                        send_task.wake();
-                    }
-                    Poll::Ready(result)
-                }
-            },
+        if self.exchanges.is_empty() {
+            self.recv_task = Some(cx.waker().clone());
+            return Poll::Pending;
         }
+        // Taken while polling as the exchanges parse with the client.
+        let mut exchanges = mem::take(&mut self.exchanges);
+        let polled = if self.in_order { 1 } else { exchanges.len() };
+        let ready = exchanges
+            .iter_mut()
+            .take(polled)
+            .enumerate()
+            .find_map(|(i, exchange)| match exchange.poll(cx, self) {
+                Poll::Pending => None,
+                Poll::Ready(result) => Some((i, result)),
+            });
+        let poll = match ready {
+            None => Poll::Pending,
+            Some((i, result)) => {
+                exchanges.remove(i);
+                Poll::Ready(result)
+            }
+        };
+        self.exchanges = exchanges;
+        poll
     }
 
     fn poll_send(&mut self, cx: &mut Context<'_>, datagram: D) -> Poll<Result<(), Self::Error>> {
-        if self.exchange.is_some() {
-            self.send_task = Some(cx.waker().clone());
-            return Poll::Pending;
-        }
         let mut request_data = BytesMut::new();
         datagram.encode(&mut request_data);
         let mut future = ExchangeFuture::new(self, request_data.freeze());
@@ -212,16 +178,25 @@ where
             Poll::Pending => future,
             Poll::Ready(result) => ExchangeFuture::Ready(Some(result)),
         };
-        self.exchange = Some(future);
+        self.exchanges.push_back(future);
         if let Some(recv_task) = self.recv_task.take() {
             recv_task.wake();
         }
         Poll::Ready(Ok(()))
     }
 
-    fn max_datagram_size(&self) -> usize {
+    /// Returns the max size of a datagram sent in a query name.
+    fn max_send_size(&self) -> usize {
         self.endpoint.max_request_size()
     }
+
+    /// Returns the max size of a datagram received in answers.
+    ///
+    /// Answers too large for UDP are retried over TCP, so this is only
+    /// limited by what the server fits in them.
+    fn max_recv_size(&self) -> usize {
+        usize::MAX
+    }
 }
 
 impl<H, E, D> fmt::Debug for DnsClient<H, E, D>
@@ -236,6 +211,149 @@ where
 
 ///////////////////////////////////////////////////////////////////////////////
 
+/// Parses the datagram from the answers to a query of a record type.
+fn parse_response<E, D>(
+    endpoint: &mut E,
+    multi_name: bool,
+    answers: Vec<Record>,
+    record_type: RecordType,
+) -> Result<D, DnsTransportError<D::Error>>
+where
+    E: DnsEndpoint,
+    D: Datagram,
+{
+    if answers.is_empty() {
+        return Err(DnsTransportError::NoAnswers);
+    }
+    // We will filter for the record type we requested, and
+    // drop record types we don't care about silently later.
+    let answers = answers.into_iter().map(|r| r.into_data());
+    // Parse the record data depending on the record type.
+    let mut bytes = match record_type {
+        RecordType::A => {
+            let mut buf = BytesMut::new();
+            let addrs = answers.filter_map(|d| d.into_a().ok());
+            SplitDatagram::write_iter_into(addrs, &mut buf).map_err(DatagramError::from)?;
+            buf.freeze()
+        }
+        RecordType::AAAA => {
+            let mut buf = BytesMut::new();
+            let addrs = answers.filter_map(|d| d.into_aaaa().ok());
+            SplitDatagram::write_iter_into(addrs, &mut buf).map_err(DatagramError::from)?;
+            buf.freeze()
+        }
+        RecordType::CNAME if multi_name => {
+            let mut buf = BytesMut::new();
+            let mut blobs = Vec::with_capacity(answers.len());
+            let names = answers.filter_map(|d| d.into_cname().ok());
+            for name in names {
+                blobs.push(endpoint.parse_cname_response(name)?);
+            }
+            SplitDatagram::write_iter_into(blobs, &mut buf).map_err(DatagramError::from)?;
+            buf.freeze()
+        }
+        RecordType::CNAME => {
+            let name = answers
+                .filter_map(|d| d.into_cname().ok())
+                .next()
+                .ok_or(DnsTransportError::NoAnswers)?;
+            endpoint.parse_cname_response(name)?
+        }
+        RecordType::MX if multi_name => {
+            let mut buf = BytesMut::new();
+            let mut blobs = Vec::with_capacity(answers.len());
+            let names = answers.filter_map(|d| d.into_mx().ok());
+            for mx in names {
+                blobs.push(endpoint.parse_mx_response(mx.exchange().clone())?);
+            }
+            SplitDatagram::write_iter_into(blobs, &mut buf).map_err(DatagramError::from)?;
+            buf.freeze()
+        }
+        RecordType::MX => {
+            let name = answers
+                .filter_map(|d| d.into_mx().ok())
+                .next()
+                .ok_or(DnsTransportError::NoAnswers)?;
+            endpoint.parse_mx_response(name.exchange().clone())?
+        }
+        RecordType::SRV => {
+            let srv = answers
+                .filter_map(|d| d.into_srv().ok())
+                .next()
+                .ok_or(DnsTransportError::NoAnswers)?;
+            endpoint.parse_srv_response(srv.target().clone())?
+        }
+        RecordType::PTR => {
+            let name = answers
+                .filter_map(|d| d.into_ptr().ok())
+                .next()
+                .ok_or(DnsTransportError::NoAnswers)?;
+            endpoint.parse_ptr_response(name)?
+        }
+        RecordType::TXT => {
+            let mut buf = BytesMut::new();
+            let mut txts = answers.filter_map(|d| d.into_txt().ok());
+            if let Some(txt) = txts.next() {
+                let mut blobs = txt.txt_data().iter().peekable();
+                // Encodings other than hex are confirmed with a marker.
+                let encoding = blobs.peek().and_then(|blob| TxtEncoding::from_marker(blob));
+                if encoding.is_some() {
+                    blobs.next();
+                }
+                match encoding.unwrap_or_default() {
+                    TxtEncoding::Hex => {
+                        for blob in blobs {
+                            hex::decode_into_buf(&mut buf, &blob[..], true)
+                                .map_err(DatagramError::from)?;
+                        }
+                    }
+                    TxtEncoding::Base64 => {
+                        let encoded = blobs.flat_map(|blob| blob.iter()).copied();
+                        let decoded = BASE64
+                            .decode(&encoded.collect::<Vec<_>>()[..])
+                            .map_err(DatagramError::from)?;
+                        buf.extend_from_slice(&decoded[..]);
+                    }
+                    TxtEncoding::Raw => blobs.for_each(|blob| buf.extend_from_slice(blob)),
+                }
+
+                if txts.next().is_some() {
+                    warn!("using the first of multiple txt answers received");
+                }
+            }
+            buf.freeze()
+        }
+        RecordType::NULL => answers
+            .filter_map(|d| d.into_null().ok())
+            .find_map(|null| null.anything().map(Bytes::copy_from_slice))
+            .unwrap_or_default(),
+        other => return Err(DnsEndpointError::UnsupportedQuery(other).into()),
+    };
+    if bytes.is_empty() {
+        return Err(DnsTransportError::NoData);
+    }
+    let datagram = D::decode(&mut bytes).map_err(DatagramError::Decode)?;
+    if bytes.is_empty() {
+        Ok(datagram)
+    } else {
+        Err(DatagramError::Underflow.into())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+async fn connect_tcp_client(
+    addr: SocketAddr,
+    rt: &runtime::Handle,
+) -> Result<AsyncClient, ProtoError> {
+    let (stream, sender) = TcpClientStream::<AsyncIoTokioAsStd<TcpStream>>::new(addr);
+    let (client, bg) = AsyncClient::new(stream, sender, None).await?;
+    rt.spawn(bg);
+    Ok(client)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
 #[derive(Debug)]
 enum ExchangeFuture<D>
 where
@@ -260,8 +378,24 @@ where
         match client.endpoint.build_request(request_data) {
             Ok((name, record_type)) => {
                 let query = Query::query(name, record_type);
-                let request_fut = client.dns_handle.lookup(query, DEFAULT_LOOKUP_OPTIONS);
-                let request_fut = client.runtime_handle.spawn(request_fut);
+                let request_fut = client
+                    .dns_handle
+                    .lookup(query.clone(), DEFAULT_LOOKUP_OPTIONS);
+                let request_fut = match client.tcp_fallback {
+                    None => client.runtime_handle.spawn(request_fut),
+                    Some(addr) => {
+                        let rt = client.runtime_handle.clone();
+                        client.runtime_handle.spawn(async move {
+                            let response = request_fut.await?;
+                            if !response.truncated() {
+                                return Ok(response);
+                            }
+                            debug!("retrying truncated exchange over TCP");
+                            let mut tcp_client = connect_tcp_client(addr, &rt).await?;
+                            tcp_client.lookup(query, DEFAULT_LOOKUP_OPTIONS).await
+                        })
+                    }
+                };
                 ExchangeFuture::Pending {
                     record_type,
                     request_fut,
@@ -290,8 +424,16 @@ where
                     .map_err(DnsTransportError::Proto)
                     .and_then(|mut response| {
                         let answers = response.take_answers();
-                        client.parse_response(answers, *record_type)
+                        parse_response(
+                            &mut client.endpoint,
+                            client.multi_name,
+                            answers,
+                            *record_type,
+                        )
                     });
+                client
+                    .endpoint
+                    .record_exchange(*record_type, result.is_ok());
                 Poll::Ready(result)
             }
             Self::Ready(result_opt) => {
@@ -301,3 +443,79 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::TcpListener;
+    use trust_dns_proto::op::{Message, MessageType};
+    use trust_dns_proto::rr::{Name, RData};
+
+    use super::*;
+    use crate::packet::LazyPacket;
+    use crate::transport::dns::testing::{endpoint, exchange};
+    use crate::transport::dns::{BasicDnsEndpoint, DnsServer};
+
+    /// Binds a DNS server answering only over TCP, returning its address.
+    async fn bind_tcp_server() -> (DnsServer<BasicDnsEndpoint, LazyPacket>, SocketAddr) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server = DnsServer::new(socket, endpoint()).with_tcp(listener);
+        (server, addr)
+    }
+
+    #[tokio::test]
+    async fn test_dns_client_tcp() {
+        let (mut server, addr) = bind_tcp_server().await;
+        let mut client = DnsClient::connect_tcp(addr, endpoint()).await.unwrap();
+        exchange(&mut client, &mut server).await;
+    }
+
+    #[tokio::test]
+    async fn test_dns_client_tcp_fallback() {
+        let (mut server, addr) = bind_tcp_server().await;
+        // Truncates every UDP response on the same port as the server.
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf[..]).await {
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let mut response = request.truncate();
+                response.set_message_type(MessageType::Response);
+                response.add_queries(request.queries().to_vec());
+                let response = response.to_vec().unwrap();
+                socket.send_to(&response[..], peer).await.unwrap();
+            }
+        });
+        let mut client = DnsClient::connect(addr, endpoint()).await.unwrap();
+        exchange(&mut client, &mut server).await;
+    }
+
+    #[test]
+    fn test_dns_client_unsupported_answer() {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let answers = vec![Record::from_rdata(name.clone(), 1, RData::NS(name))];
+        let result =
+            parse_response::<_, LazyPacket>(&mut endpoint(), false, answers, RecordType::NS);
+        assert!(matches!(
+            result,
+            Err(DnsTransportError::Endpoint(
+                DnsEndpointError::UnsupportedQuery(RecordType::NS)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_dns_client_mismatched_answer() {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let answer = Record::from_rdata(name, 1, RData::A(Ipv4Addr::LOCALHOST));
+        for record_type in [RecordType::CNAME, RecordType::MX] {
+            let answers = vec![answer.clone()];
+            let result =
+                parse_response::<_, LazyPacket>(&mut endpoint(), false, answers, record_type);
+            assert!(matches!(result, Err(DnsTransportError::NoAnswers)));
+        }
+    }
+}
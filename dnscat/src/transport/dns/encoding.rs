@@ -0,0 +1,303 @@
+use std::fmt;
+use std::str::FromStr;
+
+use bytes::{BufMut, BytesMut};
+use failure::Fail;
+
+use crate::util::hex;
+
+const BASE32_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+const BASE36_ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+/// The number of bytes in a base36 block.
+const BASE36_BLOCK_SIZE: usize = 8;
+
+/// The number of base36 characters needed to encode a number of bytes,
+/// indexed by the number of bytes in a block.
+const BASE36_BLOCK_LEN: [usize; BASE36_BLOCK_SIZE + 1] = [0, 2, 4, 5, 7, 8, 10, 11, 13];
+
+#[derive(Debug, Clone, PartialEq, Fail)]
+pub enum NameEncodingError {
+    #[fail(display = "Hex decode error: {}", _0)]
+    Hex(hex::DecodeError),
+    #[fail(display = "Invalid character: {}", _0)]
+    InvalidChar(u8),
+    #[fail(display = "Invalid encoded length")]
+    InvalidLength,
+    #[fail(display = "Encoded value out of range")]
+    OutOfRange,
+}
+
+/// An encoding of data into characters valid within a DNS label.
+///
+/// Encodings must only produce lowercase ASCII, and decode regardless of
+/// case, as resolvers are free to change the case of a name.
+pub trait NameEncoding: fmt::Debug + Send + Sync {
+    /// Returns the max number of bytes that can be encoded within a number
+    /// of characters.
+    fn max_decoded_len(&self, encoded_len: usize) -> usize;
+
+    /// Encode bytes into a buffer.
+    fn encode(&self, buf: &mut BytesMut, bytes: &[u8]);
+
+    /// Decode encoded bytes into a buffer.
+    fn decode(&self, buf: &mut BytesMut, encoded: &[u8]) -> Result<(), NameEncodingError>;
+}
+
+/// Hex encoding, as used by the upstream dnscat2 implementation.
+///
+/// Encodes one byte into two characters.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Hex;
+
+impl NameEncoding for Hex {
+    fn max_decoded_len(&self, encoded_len: usize) -> usize {
+        encoded_len / 2
+    }
+
+    fn encode(&self, buf: &mut BytesMut, bytes: &[u8]) {
+        buf.reserve(bytes.len() * 2);
+        hex::encode_into_buf(buf, bytes);
+    }
+
+    fn decode(&self, buf: &mut BytesMut, encoded: &[u8]) -> Result<(), NameEncodingError> {
+        buf.reserve(encoded.len() / 2);
+        hex::decode_into_buf(buf, encoded, true).map_err(NameEncodingError::Hex)
+    }
+}
+
+/// Base32 encoding (RFC 4648) with a lowercase alphabet and no padding.
+///
+/// Encodes five bytes into eight characters.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Base32;
+
+impl NameEncoding for Base32 {
+    fn max_decoded_len(&self, encoded_len: usize) -> usize {
+        encoded_len * 5 / 8
+    }
+
+    fn encode(&self, buf: &mut BytesMut, bytes: &[u8]) {
+        buf.reserve((bytes.len() * 8).div_ceil(5));
+        let mut bits = 0u16;
+        let mut bits_len = 0;
+        for byte in bytes {
+            bits = (bits << 8) | *byte as u16;
+            bits_len += 8;
+            while bits_len >= 5 {
+                bits_len -= 5;
+                buf.put_u8(BASE32_ALPHABET[(bits >> bits_len) as usize & 0x1F]);
+            }
+        }
+        if bits_len > 0 {
+            buf.put_u8(BASE32_ALPHABET[(bits << (5 - bits_len)) as usize & 0x1F]);
+        }
+    }
+
+    fn decode(&self, buf: &mut BytesMut, encoded: &[u8]) -> Result<(), NameEncodingError> {
+        // Trailing characters must carry at least a byte of data.
+        if matches!(encoded.len() % 8, 1 | 3 | 6) {
+            return Err(NameEncodingError::InvalidLength);
+        }
+        buf.reserve(self.max_decoded_len(encoded.len()));
+        let mut bits = 0u16;
+        let mut bits_len = 0;
+        for c in encoded {
+            let value = decode_char(BASE32_ALPHABET, *c)?;
+            bits = (bits << 5) | value as u16;
+            bits_len += 5;
+            if bits_len >= 8 {
+                bits_len -= 8;
+                buf.put_u8((bits >> bits_len) as u8);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Base36 encoding, using digits and a lowercase alphabet.
+///
+/// Encodes blocks of eight bytes into thirteen characters, with a final
+/// partial block using as few characters as its bytes need.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Base36;
+
+impl NameEncoding for Base36 {
+    fn max_decoded_len(&self, encoded_len: usize) -> usize {
+        let full_len = BASE36_BLOCK_LEN[BASE36_BLOCK_SIZE];
+        let partial_len = encoded_len % full_len;
+        let partial = BASE36_BLOCK_LEN
+            .iter()
+            .rposition(|len| *len <= partial_len)
+            .unwrap_or(0);
+        encoded_len / full_len * BASE36_BLOCK_SIZE + partial
+    }
+
+    fn encode(&self, buf: &mut BytesMut, bytes: &[u8]) {
+        for block in bytes.chunks(BASE36_BLOCK_SIZE) {
+            let mut value = block
+                .iter()
+                .fold(0u128, |value, byte| (value << 8) | *byte as u128);
+            let mut chars = [0u8; 13];
+            let chars = &mut chars[..BASE36_BLOCK_LEN[block.len()]];
+            for c in chars.iter_mut().rev() {
+                *c = BASE36_ALPHABET[(value % 36) as usize];
+                value /= 36;
+            }
+            buf.extend_from_slice(chars);
+        }
+    }
+
+    fn decode(&self, buf: &mut BytesMut, encoded: &[u8]) -> Result<(), NameEncodingError> {
+        buf.reserve(self.max_decoded_len(encoded.len()));
+        for block in encoded.chunks(BASE36_BLOCK_LEN[BASE36_BLOCK_SIZE]) {
+            let block_size = BASE36_BLOCK_LEN
+                .iter()
+                .position(|len| *len == block.len())
+                .ok_or(NameEncodingError::InvalidLength)?;
+            let mut value = 0u128;
+            for c in block {
+                value = value * 36 + decode_char(BASE36_ALPHABET, *c)? as u128;
+            }
+            if value >> (block_size * 8) != 0 {
+                return Err(NameEncodingError::OutOfRange);
+            }
+            let value_bytes = value.to_be_bytes();
+            buf.extend_from_slice(&value_bytes[value_bytes.len() - block_size..]);
+        }
+        Ok(())
+    }
+}
+
+/// The encoding of data in TXT answers.
+///
+/// Encodings other than hex are requested by prepending their marker as a
+/// label to the query name, and confirmed by the server answering with the
+/// marker as the first character-string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TxtEncoding {
+    /// Hex encoding, as used by the upstream dnscat2 implementation.
+    #[default]
+    Hex,
+    /// Base64 encoding, for resolvers that only pass printable strings.
+    Base64,
+    /// Data is carried as is.
+    Raw,
+}
+
+impl TxtEncoding {
+    /// Returns the marker requesting and confirming the encoding.
+    pub fn marker(self) -> Option<&'static [u8]> {
+        match self {
+            Self::Hex => None,
+            Self::Base64 => Some(b"_b64"),
+            Self::Raw => Some(b"_raw"),
+        }
+    }
+
+    /// Returns the encoding a marker is for.
+    pub fn from_marker(marker: &[u8]) -> Option<Self> {
+        [Self::Base64, Self::Raw].iter().copied().find(|encoding| {
+            encoding
+                .marker()
+                .is_some_and(|m| m.eq_ignore_ascii_case(marker))
+        })
+    }
+}
+
+impl FromStr for TxtEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hex" => Ok(Self::Hex),
+            "base64" => Ok(Self::Base64),
+            "raw" => Ok(Self::Raw),
+            other => Err(format!("unknown TXT encoding `{}`", other)),
+        }
+    }
+}
+
+fn decode_char(alphabet: &[u8], c: u8) -> Result<u8, NameEncodingError> {
+    let lower = c.to_ascii_lowercase();
+    alphabet
+        .iter()
+        .position(|a| *a == lower)
+        .map(|value| value as u8)
+        .ok_or(NameEncodingError::InvalidChar(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(encoding: &dyn NameEncoding, bytes: &[u8]) -> BytesMut {
+        let mut encoded = BytesMut::new();
+        encoding.encode(&mut encoded, bytes);
+        assert!(encoded.len() <= bytes.len() * 2);
+        assert!(encoding.max_decoded_len(encoded.len()) >= bytes.len());
+        let mut decoded = BytesMut::new();
+        encoding.decode(&mut decoded, &encoded[..]).unwrap();
+        assert_eq!(&decoded[..], bytes, "{:?}", encoding);
+        encoded
+    }
+
+    #[test]
+    fn test_encodings_roundtrip() {
+        let bytes = (0..=255).rev().collect::<Vec<u8>>();
+        for encoding in &[&Hex as &dyn NameEncoding, &Base32, &Base36] {
+            for len in 0..=24 {
+                roundtrip(*encoding, &bytes[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_base32() {
+        let encoded = roundtrip(&Base32, b"foobar");
+        assert_eq!(&encoded[..], b"mzxw6ytboi");
+        let mut decoded = BytesMut::new();
+        Base32.decode(&mut decoded, b"MZXW6YTBOI").unwrap();
+        assert_eq!(&decoded[..], b"foobar");
+        assert_eq!(
+            Base32.decode(&mut decoded, b"mzx"),
+            Err(NameEncodingError::InvalidLength)
+        );
+        assert_eq!(
+            Base32.decode(&mut decoded, b"m1"),
+            Err(NameEncodingError::InvalidChar(b'1'))
+        );
+    }
+
+    #[test]
+    fn test_base36() {
+        let encoded = roundtrip(&Base36, &[0xFF; 9]);
+        assert_eq!(&encoded[..], b"3w5e11264sgsf73");
+        let mut decoded = BytesMut::new();
+        assert_eq!(
+            Base36.decode(&mut decoded, b"zzz"),
+            Err(NameEncodingError::InvalidLength)
+        );
+        assert_eq!(
+            Base36.decode(&mut decoded, b"zz"),
+            Err(NameEncodingError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn test_txt_encoding_marker() {
+        for encoding in &[TxtEncoding::Base64, TxtEncoding::Raw] {
+            let marker = encoding.marker().unwrap();
+            assert_eq!(TxtEncoding::from_marker(marker), Some(*encoding));
+        }
+        assert_eq!(TxtEncoding::from_marker(b"_B64"), Some(TxtEncoding::Base64));
+        assert_eq!(TxtEncoding::from_marker(b"dead"), None);
+    }
+
+    #[test]
+    fn test_max_decoded_len() {
+        assert_eq!(Hex.max_decoded_len(238), 119);
+        assert_eq!(Base32.max_decoded_len(238), 148);
+        assert_eq!(Base36.max_decoded_len(238), 146);
+    }
+}
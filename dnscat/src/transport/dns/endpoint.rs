@@ -1,12 +1,17 @@
+use std::iter;
+
 use bytes::Bytes;
 use failure::Fail;
-use rand::{rngs::OsRng, seq::SliceRandom, Rng};
+use rand::{rngs::OsRng, Rng};
 use trust_dns_proto::{
     error::ProtoError,
     rr::{Name, RecordType},
 };
 
-use super::{Labeller, NameEncoder, NameEncoderError};
+use super::{
+    Labeller, NameEncoder, NameEncoderError, NameEncoding, QueryTypeSelector, QueryTypeStats,
+    TxtEncoding,
+};
 
 pub type DnsEndpointRequest = (Name, RecordType);
 
@@ -33,6 +38,22 @@ pub trait DnsEndpoint {
 
     /// Parse a CNAME response into data.
     fn parse_cname_response(&mut self, name: Name) -> Result<Bytes, DnsEndpointError>;
+
+    /// Build a SRV response target given data.
+    fn build_srv_response(&mut self, data: Bytes) -> Result<Name, DnsEndpointError>;
+
+    /// Parse a SRV response target into data.
+    fn parse_srv_response(&mut self, name: Name) -> Result<Bytes, DnsEndpointError>;
+
+    /// Build a PTR response given data.
+    fn build_ptr_response(&mut self, data: Bytes) -> Result<Name, DnsEndpointError>;
+
+    /// Parse a PTR response into data.
+    fn parse_ptr_response(&mut self, name: Name) -> Result<Bytes, DnsEndpointError>;
+
+    /// Record whether an exchange made with a query type was answered,
+    /// such as to prefer the query types that are.
+    fn record_exchange(&mut self, _query_type: RecordType, _answered: bool) {}
 }
 
 #[derive(Debug, Fail)]
@@ -57,7 +78,8 @@ impl From<NameEncoderError> for DnsEndpointError {
 pub struct BasicDnsEndpoint<R: Rng = OsRng> {
     random: R,
     name_encoder: NameEncoder,
-    query_types: Vec<RecordType>,
+    query_types: QueryTypeSelector,
+    txt_encoding: TxtEncoding,
     max_request_size: usize,
 }
 
@@ -69,6 +91,22 @@ impl BasicDnsEndpoint {
         let name_encoder = NameEncoder::new(constant, Labeller::random())?;
         Self::new(query_types, name_encoder, OsRng)
     }
+
+    /// Construct an endpoint with default settings, encoding data with
+    /// a given encoding rather than hex.
+    ///
+    /// Both the client and server must be configured with the same encoding.
+    pub fn new_with_encoding<E>(
+        query_types: Vec<RecordType>,
+        constant: Name,
+        encoding: E,
+    ) -> Result<Self, DnsEndpointError>
+    where
+        E: NameEncoding + 'static,
+    {
+        let name_encoder = NameEncoder::new(constant, Labeller::random())?.encoding(encoding);
+        Self::new(query_types, name_encoder, OsRng)
+    }
 }
 
 impl<R> BasicDnsEndpoint<R>
@@ -87,14 +125,35 @@ where
         if let Some(query) = unsupported_query {
             return Err(DnsEndpointError::UnsupportedQuery(*query));
         }
-        let max_request_size = name_encoder.max_hex_data() as usize;
+        let max_request_size = name_encoder.max_encoded_data() as usize;
         Ok(Self {
             random,
-            query_types,
+            query_types: QueryTypeSelector::new(query_types),
             name_encoder,
+            txt_encoding: TxtEncoding::Hex,
             max_request_size,
         })
     }
+
+    /// Set the encoding requested for data in TXT answers, which
+    /// defaults to hex.
+    ///
+    /// Upstream dnscat2 servers do not support encodings other than hex,
+    /// and will not answer queries requesting them.
+    pub fn txt_encoding(mut self, encoding: TxtEncoding) -> Self {
+        // Room is kept in the query name for the marker label.
+        let reserved = encoding.marker().map_or(0, |marker| marker.len() + 1);
+        self.name_encoder = self.name_encoder.reserve(reserved as u8);
+        self.max_request_size = self.name_encoder.max_encoded_data() as usize;
+        self.txt_encoding = encoding;
+        self
+    }
+
+    /// Returns the query types requests are made with, and the observed
+    /// results of their exchanges.
+    pub fn query_stats(&self) -> &[(RecordType, QueryTypeStats)] {
+        self.query_types.stats()
+    }
 }
 
 impl<R> DnsEndpoint for BasicDnsEndpoint<R>
@@ -104,8 +163,11 @@ where
     fn supported_queries() -> &'static [RecordType] {
         &[
             RecordType::TXT,
+            RecordType::NULL,
             RecordType::MX,
             RecordType::CNAME,
+            RecordType::SRV,
+            RecordType::PTR,
             RecordType::A,
             RecordType::AAAA,
         ]
@@ -116,31 +178,99 @@ where
     }
 
     fn build_request(&mut self, data: Bytes) -> Result<DnsEndpointRequest, DnsEndpointError> {
-        let name_data = self.name_encoder.encode_hex(&data[..])?;
-        let query_type = self
-            .query_types
-            .choose(&mut self.random)
-            .expect("random query type");
-        Ok((name_data, *query_type))
+        let mut name_data = self.name_encoder.encode_data(&data[..])?;
+        let query_type = self.query_types.select(&mut self.random);
+        if let (RecordType::TXT, Some(marker)) = (query_type, self.txt_encoding.marker()) {
+            let is_fqdn = name_data.is_fqdn();
+            name_data = Name::from_labels(iter::once(marker).chain(name_data.iter()))
+                .map_err(DnsEndpointError::Proto)?;
+            name_data.set_fqdn(is_fqdn);
+        }
+        Ok((name_data, query_type))
     }
 
     fn parse_request(&mut self, req: DnsEndpointRequest) -> Result<Bytes, DnsEndpointError> {
-        Ok(self.name_encoder.decode_hex(&req.0)?)
+        let (mut name, _) = req;
+        if txt_encoding_requested(&name).is_some() {
+            name = name.base_name();
+        }
+        Ok(self.name_encoder.decode_data(&name)?)
     }
 
     fn build_mx_response(&mut self, data: Bytes) -> Result<Name, DnsEndpointError> {
-        Ok(self.name_encoder.encode_hex(&data[..])?)
+        Ok(self.name_encoder.encode_data(&data[..])?)
     }
 
     fn parse_mx_response(&mut self, name: Name) -> Result<Bytes, DnsEndpointError> {
-        Ok(self.name_encoder.decode_hex(&name)?)
+        Ok(self.name_encoder.decode_data(&name)?)
     }
 
     fn build_cname_response(&mut self, data: Bytes) -> Result<Name, DnsEndpointError> {
-        Ok(self.name_encoder.encode_hex(&data[..])?)
+        Ok(self.name_encoder.encode_data(&data[..])?)
     }
 
     fn parse_cname_response(&mut self, name: Name) -> Result<Bytes, DnsEndpointError> {
-        Ok(self.name_encoder.decode_hex(&name)?)
+        Ok(self.name_encoder.decode_data(&name)?)
+    }
+
+    fn build_srv_response(&mut self, data: Bytes) -> Result<Name, DnsEndpointError> {
+        Ok(self.name_encoder.encode_data(&data[..])?)
+    }
+
+    fn parse_srv_response(&mut self, name: Name) -> Result<Bytes, DnsEndpointError> {
+        Ok(self.name_encoder.decode_data(&name)?)
+    }
+
+    fn build_ptr_response(&mut self, data: Bytes) -> Result<Name, DnsEndpointError> {
+        Ok(self.name_encoder.encode_data(&data[..])?)
+    }
+
+    fn parse_ptr_response(&mut self, name: Name) -> Result<Bytes, DnsEndpointError> {
+        Ok(self.name_encoder.decode_data(&name)?)
+    }
+
+    fn record_exchange(&mut self, query_type: RecordType, answered: bool) {
+        self.query_types.record(query_type, answered);
+    }
+}
+
+/// Returns the TXT encoding requested by a marker label prefixing a name.
+pub fn txt_encoding_requested(name: &Name) -> Option<TxtEncoding> {
+    name.iter().next().and_then(TxtEncoding::from_marker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint_query_stats() {
+        let constant = Name::from_ascii("example.com.").unwrap();
+        let query_types = vec![RecordType::TXT, RecordType::A];
+        let mut endpoint = BasicDnsEndpoint::new_with_defaults(query_types, constant).unwrap();
+        endpoint.record_exchange(RecordType::TXT, true);
+        for _ in 0..3 {
+            endpoint.record_exchange(RecordType::A, false);
+        }
+        // Not a query type requests are made with, so not recorded.
+        endpoint.record_exchange(RecordType::MX, true);
+        let stats = endpoint.query_stats();
+        assert_eq!(
+            stats[0],
+            (
+                RecordType::TXT,
+                QueryTypeStats {
+                    successes: 1,
+                    failures: 0,
+                    consecutive_failures: 0,
+                    demoted: false,
+                }
+            )
+        );
+        assert_eq!(stats[1].0, RecordType::A);
+        assert_eq!(stats[1].1.failures, 3);
+        assert!(stats[1].1.demoted);
+        assert_eq!(stats[1].1.to_string(), "0 answered, 3 failed, demoted");
+        assert_eq!(stats.len(), 2);
     }
 }
@@ -0,0 +1,195 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use data_encoding::BASE64URL_NOPAD;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use reqwest::{Client as HttpClient, RequestBuilder, Url};
+use tokio::runtime;
+use trust_dns_proto::error::ProtoError;
+use trust_dns_proto::op::Message;
+use trust_dns_proto::xfer::{DnsHandle, DnsRequest, DnsResponse};
+
+use crate::transport::Datagram;
+
+use super::{DnsClient, DnsEndpoint};
+
+/// The media type of DNS messages sent and received over HTTPS.
+const DNS_MESSAGE_TYPE: &str = "application/dns-message";
+
+/// The HTTP method used to send DNS-over-HTTPS requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpsMethod {
+    /// The DNS message is encoded in the `dns` URL parameter.
+    Get,
+    /// The DNS message is sent as the request body.
+    Post,
+}
+
+/// A DNS handle sending queries as DNS-over-HTTPS (RFC 8484) requests.
+#[derive(Debug, Clone)]
+pub struct HttpsHandle {
+    http: HttpClient,
+    url: Url,
+    method: HttpsMethod,
+}
+
+impl HttpsHandle {
+    pub fn new(url: Url, method: HttpsMethod) -> Self {
+        Self::with_client(HttpClient::new(), url, method)
+    }
+
+    /// Construct a handle with a configured HTTP client, such as one
+    /// trusting a custom root certificate.
+    pub fn with_client(http: HttpClient, url: Url, method: HttpsMethod) -> Self {
+        Self { http, url, method }
+    }
+
+    fn build_request(&self, message: Vec<u8>) -> RequestBuilder {
+        let request = match self.method {
+            HttpsMethod::Get => {
+                let mut url = self.url.clone();
+                url.query_pairs_mut()
+                    .append_pair("dns", &BASE64URL_NOPAD.encode(&message[..]));
+                self.http.get(url)
+            }
+            HttpsMethod::Post => self
+                .http
+                .post(self.url.clone())
+                .header(CONTENT_TYPE, DNS_MESSAGE_TYPE)
+                .body(message),
+        };
+        request.header(ACCEPT, DNS_MESSAGE_TYPE)
+    }
+}
+
+impl DnsHandle for HttpsHandle {
+    type Response = Pin<Box<dyn Future<Output = Result<DnsResponse, ProtoError>> + Send>>;
+    type Error = ProtoError;
+
+    fn send<R: Into<DnsRequest> + Unpin + Send + 'static>(&mut self, request: R) -> Self::Response {
+        let (mut message, _) = request.into().into_parts();
+        // An ID of zero keeps GET requests cache friendly.
+        message.set_id(0);
+        let request = message.to_vec().map(|message| self.build_request(message));
+        Box::pin(async move {
+            let response = request?
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|err| ProtoError::from(format!("HTTPS request failed: {}", err)))?;
+            let body = response
+                .bytes()
+                .await
+                .map_err(|err| ProtoError::from(format!("HTTPS response failed: {}", err)))?;
+            Ok(Message::from_vec(&body[..])?.into())
+        })
+    }
+}
+
+impl<E, D> DnsClient<HttpsHandle, E, D>
+where
+    E: DnsEndpoint,
+    D: Datagram,
+{
+    /// Connect to a DNS-over-HTTPS server at a URL.
+    pub fn connect_https(url: Url, method: HttpsMethod, endpoint: E) -> Self {
+        let handle = HttpsHandle::new(url, method);
+        Self::new(handle, endpoint, runtime::Handle::current())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::transport::dns::testing::{endpoint, exchange, tls_acceptor};
+    use crate::transport::dns::DnsServer;
+
+    /// Serves DNS-over-HTTPS with a self-signed certificate for `localhost`,
+    /// relaying each DNS message to a DNS server over UDP.
+    async fn bind_https_relay(dns_addr: SocketAddr) -> (SocketAddr, reqwest::Certificate) {
+        let (acceptor, cert_pem) = tls_acceptor();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(relay_https(stream, acceptor.clone(), dns_addr));
+            }
+        });
+        let cert = reqwest::Certificate::from_pem(cert_pem.as_bytes()).unwrap();
+        (addr, cert)
+    }
+
+    async fn relay_https(stream: TcpStream, acceptor: TlsAcceptor, dns_addr: SocketAddr) {
+        let mut stream = BufReader::new(acceptor.accept(stream).await.unwrap());
+        let mut line = String::new();
+        while stream.read_line(&mut line).await.unwrap() != 0 {
+            let mut request_line = line.split(' ');
+            let method = request_line.next().unwrap().to_string();
+            let target = request_line.next().unwrap().to_string();
+            let mut content_len = 0;
+            loop {
+                line.clear();
+                stream.read_line(&mut line).await.unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                let (name, value) = line.split_once(':').unwrap();
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_len = value.trim().parse().unwrap();
+                }
+            }
+            let query = match method.as_str() {
+                "GET" => {
+                    let (_, dns) = target.split_once("dns=").unwrap();
+                    BASE64URL_NOPAD.decode(dns.as_bytes()).unwrap()
+                }
+                "POST" => {
+                    let mut body = vec![0; content_len];
+                    stream.read_exact(&mut body[..]).await.unwrap();
+                    body
+                }
+                other => panic!("unexpected method {}", other),
+            };
+            let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            socket.send_to(&query[..], dns_addr).await.unwrap();
+            let mut response = vec![0; 4096];
+            let len = socket.recv(&mut response[..]).await.unwrap();
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                DNS_MESSAGE_TYPE, len
+            );
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(&response[..len]).await.unwrap();
+            stream.flush().await.unwrap();
+            line.clear();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dns_client_https() {
+        let addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let mut server = DnsServer::bind(addr, endpoint()).await.unwrap();
+        let (https_addr, cert) = bind_https_relay(server.local_addr().unwrap()).await;
+        let http = HttpClient::builder()
+            .add_root_certificate(cert)
+            .resolve("localhost", https_addr)
+            .build()
+            .unwrap();
+        let url = Url::parse(&format!(
+            "https://localhost:{}/dns-query",
+            https_addr.port()
+        ))
+        .unwrap();
+        for method in &[HttpsMethod::Get, HttpsMethod::Post] {
+            let handle = HttpsHandle::with_client(http.clone(), url.clone(), *method);
+            let mut client = DnsClient::new(handle, endpoint(), runtime::Handle::current());
+            exchange(&mut client, &mut server).await;
+        }
+    }
+}
@@ -1,14 +1,34 @@
 #[cfg(feature = "client")]
 mod client;
+mod encoding;
 mod endpoint;
+#[cfg(feature = "https")]
+mod https;
 mod name;
+mod query;
 #[cfg(feature = "trust-dns-resolver")]
 mod resolver;
+#[cfg(feature = "server")]
+mod server;
+#[cfg(all(test, feature = "server"))]
+mod testing;
+#[cfg(feature = "tls")]
+mod tls;
 
 #[cfg(feature = "client")]
 pub use self::client::*;
+pub use self::encoding::*;
 pub use self::endpoint::*;
+#[cfg(feature = "https")]
+pub use self::https::*;
 pub use self::name::*;
+pub use self::query::*;
+#[cfg(feature = "server")]
+pub use self::server::*;
+#[cfg(feature = "tls")]
+pub use self::tls::*;
+
+use std::io;
 
 use failure::Fail;
 use trust_dns_proto::error::ProtoError;
@@ -16,7 +36,7 @@ use trust_dns_proto::error::ProtoError;
 pub use trust_dns_proto::rr::{Name, RecordType};
 
 #[cfg(feature = "trust-dns-resolver")]
-pub use self::resolver::get_system_dns_server;
+pub use self::resolver::{get_system_dns_server, get_system_dns_servers};
 
 use crate::transport::DatagramError;
 
@@ -32,6 +52,10 @@ pub enum DnsTransportError<D: Fail> {
     NoAnswers,
     #[fail(display = "No data was returned")]
     NoData,
+    #[fail(display = "No request to respond to")]
+    NoRequest,
+    #[fail(display = "I/O error: {}", _0)]
+    Io(io::Error),
 }
 
 impl<D: Fail> From<ProtoError> for DnsTransportError<D> {
@@ -51,3 +75,9 @@ impl<D: Fail> From<DnsEndpointError> for DnsTransportError<D> {
         Self::Endpoint(err)
     }
 }
+
+impl<D: Fail> From<io::Error> for DnsTransportError<D> {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
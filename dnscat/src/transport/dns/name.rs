@@ -1,3 +1,4 @@
+use std::sync::Arc;
 use std::{cmp, iter};
 
 use bytes::{Bytes, BytesMut};
@@ -5,7 +6,7 @@ use failure::Fail;
 use rand::{rngs::OsRng, Rng};
 use trust_dns_proto::{error::ProtoError, rr::Name};
 
-use crate::util::hex;
+use super::{Hex, NameEncoding, NameEncodingError};
 
 const NAME_MAX_SIZE: usize = 255;
 const LABEL_MAX_SIZE: usize = 63;
@@ -64,15 +65,17 @@ pub enum NameEncoderError {
     ConstantTooLarge,
     #[fail(display = "DNS Protocol error: {}", _0)]
     Proto(ProtoError),
-    #[fail(display = "Hex decode error: {}", _0)]
-    Hex(hex::DecodeError),
+    #[fail(display = "Name decode error: {}", _0)]
+    Encoding(NameEncodingError),
 }
 
 #[derive(Debug, Clone)]
 pub struct NameEncoder {
     budget: u8,
+    reserved: u8,
     labeller: Labeller,
     constant: LowerAsciiName,
+    encoding: Arc<dyn NameEncoding>,
 }
 
 impl NameEncoder {
@@ -95,23 +98,46 @@ impl NameEncoder {
             constant,
             labeller,
             budget,
+            reserved: 0,
+            encoding: Arc::new(Hex),
         };
         Ok(this)
     }
 
+    /// Set the encoding used for data, which defaults to hex.
+    pub fn encoding<E>(mut self, encoding: E) -> Self
+    where
+        E: NameEncoding + 'static,
+    {
+        self.encoding = Arc::new(encoding);
+        self
+    }
+
+    /// Reserve part of the budget for labels added to an encoded name.
+    pub fn reserve(mut self, len: u8) -> Self {
+        self.reserved = len;
+        self
+    }
+
     /// Returns the max length of data that can be encoded.
     pub fn max_data(&self) -> u8 {
-        self.labeller.max_data_for_budget(self.budget)
+        self.labeller.max_data_for_budget(self.budget())
     }
 
     /// Returns the max length of data that can be encoded in hex.
     pub fn max_hex_data(&self) -> u8 {
-        self.max_data() / 2
+        Hex.max_decoded_len(self.max_data() as usize) as u8
+    }
+
+    /// Returns the max length of data that can be encoded with the
+    /// configured encoding.
+    pub fn max_encoded_data(&self) -> u8 {
+        self.encoding.max_decoded_len(self.max_data() as usize) as u8
     }
 
     /// Returns the budget available to encode data.
     pub fn budget(&self) -> u8 {
-        self.budget
+        self.budget.saturating_sub(self.reserved)
     }
 
     /// Returns a reference to the constant name.
@@ -126,14 +152,29 @@ impl NameEncoder {
 
     /// Encode data as hex into into a FQDN.
     pub fn encode_hex(&mut self, bytes: &[u8]) -> Result<Name, NameEncoderError> {
-        let mut hex_bytes = BytesMut::with_capacity(bytes.len() * 2);
-        hex::encode_into_buf(&mut hex_bytes, bytes);
-        self.encode(hex_bytes.as_ref())
+        self.encode_with(&Hex, bytes)
+    }
+
+    /// Encode data with the configured encoding into a FQDN.
+    pub fn encode_data(&mut self, bytes: &[u8]) -> Result<Name, NameEncoderError> {
+        let encoding = self.encoding.clone();
+        self.encode_with(encoding.as_ref(), bytes)
+    }
+
+    fn encode_with(
+        &mut self,
+        encoding: &dyn NameEncoding,
+        bytes: &[u8],
+    ) -> Result<Name, NameEncoderError> {
+        let mut encoded = BytesMut::new();
+        encoding.encode(&mut encoded, bytes);
+        self.encode(encoded.as_ref())
     }
 
     /// Encodes data into a FQDN.
     pub fn encode(&mut self, bytes: &[u8]) -> Result<Name, NameEncoderError> {
-        let labels = match self.labeller.label(bytes, self.budget) {
+        let budget = self.budget();
+        let labels = match self.labeller.label(bytes, budget) {
             Some(labels) => labels,
             None => return Err(NameEncoderError::DataTooLarge),
         };
@@ -149,11 +190,25 @@ impl NameEncoder {
 
     /// Decode hex data from a FQDN.
     pub fn decode_hex(&self, encoded_name: &Name) -> Result<Bytes, NameEncoderError> {
-        let bytes = self.decode(encoded_name)?;
-        let mut hex_bytes = BytesMut::with_capacity(bytes.len());
-        hex::decode_into_buf(&mut hex_bytes, bytes.as_ref(), true)
-            .map_err(NameEncoderError::Hex)?;
-        Ok(hex_bytes.freeze())
+        self.decode_with(&Hex, encoded_name)
+    }
+
+    /// Decode data with the configured encoding from a FQDN.
+    pub fn decode_data(&self, encoded_name: &Name) -> Result<Bytes, NameEncoderError> {
+        self.decode_with(self.encoding.as_ref(), encoded_name)
+    }
+
+    fn decode_with(
+        &self,
+        encoding: &dyn NameEncoding,
+        encoded_name: &Name,
+    ) -> Result<Bytes, NameEncoderError> {
+        let encoded = self.decode(encoded_name)?;
+        let mut bytes = BytesMut::new();
+        encoding
+            .decode(&mut bytes, encoded.as_ref())
+            .map_err(NameEncoderError::Encoding)?;
+        Ok(bytes.freeze())
     }
 
     /// Decode data from a FQDN.
@@ -315,6 +370,7 @@ impl Default for Labeller {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::transport::dns::Base32;
     use itertools::Itertools;
 
     #[test]
@@ -452,11 +508,29 @@ mod tests {
         assert_eq!(data, &[0xDE, 0xAD, 0xBE, 0xEF][..]);
     }
 
+    #[test]
+    fn test_name_encoder_base32() {
+        let data = &[1, 2, 3, 4, 5];
+        let domain_name = Name::from_ascii("example.com.").unwrap();
+        let encoded_name_valid = Name::from_ascii("aebag.baf.example.com.").unwrap();
+        let mut name_encoder = NameEncoder::new(domain_name, Labeller::exact(5))
+            .unwrap()
+            .encoding(Base32);
+        let encoded_name = name_encoder.encode_data(data).unwrap();
+        assert_eq!(encoded_name, encoded_name_valid);
+        let decoded = name_encoder.decode_data(&encoded_name).unwrap();
+        assert_eq!(decoded, &data[..]);
+        assert_eq!(name_encoder.max_encoded_data(), 125);
+    }
+
     #[test]
     fn test_name_default_max_data_calc() {
         let domain_name = Name::from_ascii("example.com.").unwrap();
         let name_encoder = NameEncoder::new(domain_name, Labeller::default()).unwrap();
         assert_eq!(name_encoder.budget(), 242);
         assert_eq!(name_encoder.max_data(), 238);
+        let name_encoder = name_encoder.reserve(5);
+        assert_eq!(name_encoder.budget(), 237);
+        assert_eq!(name_encoder.max_data(), 233);
     }
 }
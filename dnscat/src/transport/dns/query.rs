@@ -0,0 +1,181 @@
+use std::fmt;
+
+use log::info;
+use rand::{seq::SliceRandom, Rng};
+use trust_dns_proto::rr::RecordType;
+
+/// The number of consecutive failures after which a query type is demoted.
+const DEMOTE_AFTER_FAILURES: u32 = 3;
+
+/// The number of queries between re-tests of a demoted query type.
+const RETEST_INTERVAL: usize = 20;
+
+/// The number of exchanges between logs of the query type stats.
+const STATS_LOG_INTERVAL: u64 = 1000;
+
+/// The observed results of exchanges made with a query type.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryTypeStats {
+    /// The number of exchanges answered.
+    pub successes: u64,
+    /// The number of exchanges that failed.
+    pub failures: u64,
+    /// The number of exchanges that failed since the last answered.
+    pub consecutive_failures: u32,
+    /// Whether the query type is demoted, only being used to re-test it.
+    pub demoted: bool,
+}
+
+impl fmt::Display for QueryTypeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} answered, {} failed", self.successes, self.failures)?;
+        if self.demoted {
+            write!(f, ", demoted")?;
+        }
+        Ok(())
+    }
+}
+
+/// Selects the query type of each request, preferring the types that
+/// are observed to succeed.
+///
+/// Types that fail several times in a row are demoted, and only used
+/// once every so often to re-test them. If every type is demoted, they
+/// are selected from uniformly again.
+#[derive(Debug, Clone)]
+pub struct QueryTypeSelector {
+    types: Vec<(RecordType, QueryTypeStats)>,
+    since_retest: usize,
+    exchanges: u64,
+}
+
+impl QueryTypeSelector {
+    pub fn new(query_types: Vec<RecordType>) -> Self {
+        assert_ne!(query_types.len(), 0);
+        let types = query_types
+            .into_iter()
+            .map(|query_type| (query_type, QueryTypeStats::default()))
+            .collect();
+        Self {
+            types,
+            since_retest: 0,
+            exchanges: 0,
+        }
+    }
+
+    /// Returns the query types with their observed stats.
+    pub fn stats(&self) -> &[(RecordType, QueryTypeStats)] {
+        &self.types[..]
+    }
+
+    /// Select the query type for a request.
+    pub fn select<R: Rng>(&mut self, random: &mut R) -> RecordType {
+        let (demoted, promoted): (Vec<&(RecordType, QueryTypeStats)>, Vec<_>) =
+            self.types.iter().partition(|(_, stats)| stats.demoted);
+        self.since_retest += 1;
+        let candidates = if promoted.is_empty() {
+            demoted
+        } else if !demoted.is_empty() && self.since_retest >= RETEST_INTERVAL {
+            self.since_retest = 0;
+            demoted
+        } else {
+            promoted
+        };
+        candidates.choose(random).expect("random query type").0
+    }
+
+    /// Record the result of an exchange made with a query type.
+    pub fn record(&mut self, query_type: RecordType, success: bool) {
+        let stats = match self.types.iter_mut().find(|(t, _)| *t == query_type) {
+            Some((_, stats)) => stats,
+            None => return,
+        };
+        if success {
+            stats.successes += 1;
+            stats.consecutive_failures = 0;
+            if stats.demoted {
+                info!("promoting {} queries after being answered", query_type);
+                stats.demoted = false;
+            }
+        } else {
+            stats.failures += 1;
+            stats.consecutive_failures += 1;
+            if !stats.demoted && stats.consecutive_failures >= DEMOTE_AFTER_FAILURES {
+                info!(
+                    "demoting {} queries after {} failures",
+                    query_type, stats.consecutive_failures
+                );
+                stats.demoted = true;
+            }
+        }
+        self.exchanges += 1;
+        if self.exchanges.is_multiple_of(STATS_LOG_INTERVAL) {
+            self.log_stats();
+        }
+    }
+
+    fn log_stats(&self) {
+        let stats = self
+            .types
+            .iter()
+            .map(|(query_type, stats)| format!("{} ({})", query_type, stats))
+            .collect::<Vec<_>>();
+        info!(
+            "query stats after {} exchanges: {}",
+            self.exchanges,
+            stats.join(", ")
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_pcg::Pcg32;
+
+    use super::*;
+
+    fn selections(selector: &mut QueryTypeSelector, n: usize) -> Vec<RecordType> {
+        let mut random = Pcg32::seed_from_u64(0);
+        (0..n).map(|_| selector.select(&mut random)).collect()
+    }
+
+    #[test]
+    fn test_query_type_demoted() {
+        let mut selector = QueryTypeSelector::new(vec![RecordType::TXT, RecordType::A]);
+        for _ in 0..DEMOTE_AFTER_FAILURES {
+            selector.record(RecordType::TXT, false);
+        }
+        selector.record(RecordType::A, true);
+        let selected = selections(&mut selector, RETEST_INTERVAL);
+        let retests = selected.iter().filter(|t| **t == RecordType::TXT).count();
+        assert_eq!(retests, 1);
+        assert_eq!(selected[RETEST_INTERVAL - 1], RecordType::TXT);
+        assert_eq!(
+            selector.stats()[0],
+            (
+                RecordType::TXT,
+                QueryTypeStats {
+                    successes: 0,
+                    failures: 3,
+                    consecutive_failures: 3,
+                    demoted: true,
+                }
+            )
+        );
+        selector.record(RecordType::TXT, true);
+        assert!(!selector.stats()[0].1.demoted);
+    }
+
+    #[test]
+    fn test_query_type_all_demoted() {
+        let mut selector = QueryTypeSelector::new(vec![RecordType::TXT, RecordType::A]);
+        for _ in 0..DEMOTE_AFTER_FAILURES {
+            selector.record(RecordType::TXT, false);
+            selector.record(RecordType::A, false);
+        }
+        let selected = selections(&mut selector, 10);
+        assert!(selected.contains(&RecordType::TXT));
+        assert!(selected.contains(&RecordType::A));
+    }
+}
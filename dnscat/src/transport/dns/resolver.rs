@@ -5,12 +5,17 @@ use trust_dns_resolver::config::Protocol;
 use trust_dns_resolver::system_conf::read_system_conf;
 
 pub fn get_system_dns_server() -> Result<Option<SocketAddr>, io::Error> {
+    get_system_dns_servers().map(|servers| servers.into_iter().next())
+}
+
+/// Returns the UDP name servers of the system, in order of preference.
+pub fn get_system_dns_servers() -> Result<Vec<SocketAddr>, io::Error> {
     read_system_conf().map(|(config, _)| {
         config
             .name_servers()
             .iter()
             .filter(|server| server.protocol == Protocol::Udp)
             .map(|server| server.socket_addr)
-            .next()
+            .collect()
     })
 }
@@ -0,0 +1,678 @@
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use data_encoding::BASE64;
+use futures::channel::{mpsc, oneshot};
+use futures::{ready, StreamExt};
+use log::{debug, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use trust_dns_proto::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_proto::rr::rdata::{MX, NULL, SRV, TXT};
+use trust_dns_proto::rr::{RData, Record, RecordType};
+
+use crate::transport::{Datagram, SplitDatagram, Transport};
+use crate::util::hex;
+
+use super::{
+    txt_encoding_requested, DnsEndpoint, DnsEndpointError, DnsTransportError, NameEncoderError,
+    TxtEncoding,
+};
+
+/// The max length of a DNS message we will receive.
+const MAX_MESSAGE_LEN: usize = 4096;
+/// The max length of a UDP DNS response without EDNS.
+const UDP_DEFAULT_PAYLOAD_LEN: u16 = 512;
+/// The max length of a TXT character-string.
+const TXT_MAX_STRING_LEN: usize = 255;
+/// The max length of a name.
+const MAX_NAME_LEN: usize = 255;
+/// The length of an answer excluding its data, with the name
+/// compressed to a pointer to the query name.
+const ANSWER_HEADER_LEN: usize = 12;
+/// The TTL set on answers, which is kept low to avoid caching.
+const ANSWER_TTL: u32 = 1;
+/// The preference set on MX answers.
+const MX_PREFERENCE: u16 = 10;
+/// The priority set on SRV answers.
+const SRV_PRIORITY: u16 = 10;
+/// The weight set on SRV answers.
+const SRV_WEIGHT: u16 = 0;
+/// The port set on SRV answers.
+const SRV_PORT: u16 = 443;
+
+type TcpRequest = (Vec<u8>, oneshot::Sender<Vec<u8>>);
+
+/// A DNS server transport, receiving datagrams encoded in queries
+/// and sending datagrams encoded in the answers to them.
+///
+/// Each datagram received must be answered by the next datagram sent.
+/// If a query is not answered before the next is received, it is
+/// answered without any records.
+pub struct DnsServer<E, D>
+where
+    D: Datagram,
+{
+    socket: UdpSocket,
+    endpoint: E,
+    tcp_requests: Option<mpsc::UnboundedReceiver<TcpRequest>>,
+    multi_name: bool,
+    pending: Option<PendingQuery>,
+    recv_buf: Vec<u8>,
+    datagram: PhantomData<fn() -> D>,
+}
+
+impl<E, D> DnsServer<E, D>
+where
+    E: DnsEndpoint,
+    D: Datagram,
+{
+    pub async fn bind(addr: SocketAddr, endpoint: E) -> Result<Self, io::Error> {
+        let socket = UdpSocket::bind(addr).await?;
+        Ok(Self::new(socket, endpoint))
+    }
+
+    pub fn new(socket: UdpSocket, endpoint: E) -> Self {
+        Self {
+            socket,
+            endpoint,
+            tcp_requests: None,
+            multi_name: false,
+            pending: None,
+            recv_buf: vec![0; MAX_MESSAGE_LEN],
+            datagram: PhantomData,
+        }
+    }
+
+    /// Also accept queries over TCP from the given listener.
+    ///
+    /// This must be called within the context of a tokio runtime.
+    pub fn with_tcp(mut self, listener: TcpListener) -> Self {
+        let (tx, rx) = mpsc::unbounded();
+        tokio::spawn(accept_tcp(listener, tx));
+        self.tcp_requests = Some(rx);
+        self
+    }
+
+    /// Set whether to split data in CNAME and MX responses across several
+    /// answers, which the client must also be set to receive.
+    ///
+    /// This raises the max size of datagrams sent in these responses, though
+    /// some resolvers refuse several CNAME answers for a name.
+    pub fn multi_name(mut self, value: bool) -> Self {
+        self.multi_name = value;
+        self
+    }
+
+    /// Returns the local address of the UDP socket.
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.socket.local_addr()
+    }
+
+    fn poll_recv_message(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(Vec<u8>, Responder), io::Error>> {
+        if let Some(ref mut tcp_requests) = self.tcp_requests {
+            if let Poll::Ready(Some((request, tx))) = tcp_requests.poll_next_unpin(cx) {
+                return Poll::Ready(Ok((request, Responder::Tcp(tx))));
+            }
+        }
+        let mut buf = ReadBuf::new(&mut self.recv_buf[..]);
+        let addr = ready!(self.socket.poll_recv_from(cx, &mut buf))?;
+        Poll::Ready(Ok((buf.filled().to_vec(), Responder::Udp(addr))))
+    }
+
+    fn parse_request(&mut self, request: &Message) -> Result<(D, Query), ResponseCode> {
+        if request.message_type() != MessageType::Query || request.op_code() != OpCode::Query {
+            return Err(ResponseCode::NotImp);
+        }
+        let query = match request.queries() {
+            [query] => query.clone(),
+            _ => return Err(ResponseCode::FormErr),
+        };
+        let mut data = self
+            .endpoint
+            .parse_request((query.name().clone(), query.query_type()))
+            .map_err(|err| {
+                debug!("ignoring query for `{}`: {}", query.name(), err);
+                match err {
+                    DnsEndpointError::Name(NameEncoderError::ConstantNotFound) => {
+                        ResponseCode::Refused
+                    }
+                    _ => ResponseCode::NXDomain,
+                }
+            })?;
+        if !E::supported_queries().contains(&query.query_type()) {
+            debug!("ignoring unsupported query type {}", query.query_type());
+            return Err(ResponseCode::NXDomain);
+        }
+        let datagram = match D::decode(&mut data) {
+            Ok(datagram) if data.is_empty() => datagram,
+            Ok(_) => {
+                debug!("ignoring query for `{}`: datagram underflow", query.name());
+                return Err(ResponseCode::NXDomain);
+            }
+            Err(err) => {
+                debug!("ignoring query for `{}`: {}", query.name(), err);
+                return Err(ResponseCode::NXDomain);
+            }
+        };
+        Ok((datagram, query))
+    }
+
+    fn build_answers(
+        &mut self,
+        query: &Query,
+        data: Bytes,
+    ) -> Result<Vec<Record>, DnsTransportError<D::Error>> {
+        let rdatas = match query.query_type() {
+            RecordType::TXT => {
+                // Answer with the encoding requested, confirming it with its marker.
+                let encoding = txt_encoding_requested(query.name()).unwrap_or_default();
+                let encoded = match encoding {
+                    TxtEncoding::Hex => {
+                        let mut hex_data = BytesMut::with_capacity(data.len() * 2);
+                        hex::encode_into_buf(&mut hex_data, &data[..]);
+                        hex_data.freeze()
+                    }
+                    TxtEncoding::Base64 => BASE64.encode(&data[..]).into(),
+                    TxtEncoding::Raw => data,
+                };
+                let strings = encoding
+                    .marker()
+                    .into_iter()
+                    .chain(encoded.chunks(TXT_MAX_STRING_LEN))
+                    .collect();
+                vec![RData::TXT(TXT::from_bytes(strings))]
+            }
+            RecordType::NULL => vec![RData::NULL(NULL::with(data.to_vec()))],
+            RecordType::CNAME | RecordType::MX if self.multi_name => {
+                let block_size = self.endpoint.max_request_size();
+                let blocks = SplitDatagram::<Bytes>::from_data(&data[..], block_size, 0);
+                let mut rdatas = Vec::with_capacity(blocks.block_count());
+                for block in blocks.into_blocks() {
+                    let rdata = if query.query_type() == RecordType::MX {
+                        let exchange = self.endpoint.build_mx_response(block)?;
+                        RData::MX(MX::new(MX_PREFERENCE, exchange))
+                    } else {
+                        RData::CNAME(self.endpoint.build_cname_response(block)?)
+                    };
+                    rdatas.push(rdata);
+                }
+                rdatas
+            }
+            RecordType::MX => {
+                let exchange = self.endpoint.build_mx_response(data)?;
+                vec![RData::MX(MX::new(MX_PREFERENCE, exchange))]
+            }
+            RecordType::CNAME => vec![RData::CNAME(self.endpoint.build_cname_response(data)?)],
+            RecordType::SRV => {
+                let target = self.endpoint.build_srv_response(data)?;
+                vec![RData::SRV(SRV::new(
+                    SRV_PRIORITY,
+                    SRV_WEIGHT,
+                    SRV_PORT,
+                    target,
+                ))]
+            }
+            RecordType::PTR => vec![RData::PTR(self.endpoint.build_ptr_response(data)?)],
+            RecordType::A => SplitDatagram::<Ipv4Addr>::from_data(&data[..], 4, 0)
+                .into_blocks()
+                .into_iter()
+                .map(RData::A)
+                .collect(),
+            RecordType::AAAA => SplitDatagram::<Ipv6Addr>::from_data(&data[..], 16, 0)
+                .into_blocks()
+                .into_iter()
+                .map(RData::AAAA)
+                .collect(),
+            other => return Err(DnsEndpointError::UnsupportedQuery(other).into()),
+        };
+        let answers = rdatas
+            .into_iter()
+            .map(|rdata| Record::from_rdata(query.name().clone(), ANSWER_TTL, rdata))
+            .collect();
+        Ok(answers)
+    }
+
+    /// Returns the max length of data the answers to a query can carry
+    /// within a number of bytes.
+    fn max_answer_data(&self, query: &Query, len: usize) -> usize {
+        let rdata_len = |extra_len| len.saturating_sub(ANSWER_HEADER_LEN + extra_len);
+        let answer_count = |rdata_len| cmp::max(len / (ANSWER_HEADER_LEN + rdata_len), 1);
+        // The head block of a split datagram also holds the data length.
+        let split_data_len = |count: usize, block_size: usize| count * (block_size - 1) - 1;
+        match query.query_type() {
+            RecordType::TXT => {
+                let encoding = txt_encoding_requested(query.name()).unwrap_or_default();
+                let marker_len = encoding.marker().map_or(0, |marker| marker.len() + 1);
+                // Each character-string is prefixed with its length.
+                let strings_len = rdata_len(marker_len);
+                let encoded_len =
+                    strings_len - (strings_len + TXT_MAX_STRING_LEN) / (TXT_MAX_STRING_LEN + 1);
+                match encoding {
+                    TxtEncoding::Hex => encoded_len / 2,
+                    TxtEncoding::Base64 => encoded_len / 4 * 3,
+                    TxtEncoding::Raw => encoded_len,
+                }
+            }
+            RecordType::NULL => rdata_len(0),
+            RecordType::CNAME | RecordType::MX if self.multi_name => {
+                // Names are assumed to be uncompressed, and the MX preference
+                // is included for both.
+                let count = answer_count(MAX_NAME_LEN + 2);
+                split_data_len(count, self.endpoint.max_request_size())
+            }
+            RecordType::A => split_data_len(answer_count(4), 4),
+            RecordType::AAAA => split_data_len(answer_count(16), 16),
+            _ => self.endpoint.max_request_size(),
+        }
+    }
+}
+
+impl<E, D> Transport<D> for DnsServer<E, D>
+where
+    E: DnsEndpoint,
+    D: Datagram,
+{
+    type Error = DnsTransportError<D::Error>;
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<D, Self::Error>> {
+        loop {
+            // Answer any query the datagram consumer chose to ignore.
+            if let Some(pending) = self.pending.take() {
+                let response = build_response(&pending.request, ResponseCode::NoError);
+                pending
+                    .responder
+                    .respond(&self.socket, &pending.request, response);
+            }
+            let (request, responder) = ready!(self.poll_recv_message(cx))?;
+            let request = match Message::from_vec(&request[..]) {
+                Ok(request) => request,
+                Err(err) => {
+                    debug!("ignoring invalid DNS request: {}", err);
+                    continue;
+                }
+            };
+            match self.parse_request(&request) {
+                Ok((datagram, query)) => {
+                    self.pending = Some(PendingQuery {
+                        request,
+                        query,
+                        responder,
+                    });
+                    return Poll::Ready(Ok(datagram));
+                }
+                Err(code) => {
+                    let response = build_response(&request, code);
+                    responder.respond(&self.socket, &request, response);
+                }
+            }
+        }
+    }
+
+    fn poll_send(&mut self, cx: &mut Context<'_>, datagram: D) -> Poll<Result<(), Self::Error>> {
+        let pending = match self.pending.as_ref() {
+            Some(pending) => pending,
+            None => return Poll::Ready(Err(DnsTransportError::NoRequest)),
+        };
+        let mut data = BytesMut::new();
+        datagram.encode(&mut data);
+        let query = pending.query.clone();
+        let answers = self.build_answers(&query, data.freeze())?;
+        let pending = self.pending.as_ref().expect("pending query");
+        let mut response = build_response(&pending.request, ResponseCode::NoError);
+        response.add_answers(answers);
+        if let Responder::Udp(addr) = pending.responder {
+            let response = encode_udp_response(&pending.request, response)?;
+            ready!(self.socket.poll_send_to(cx, &response[..], addr))?;
+            self.pending = None;
+        } else {
+            let pending = self.pending.take().expect("pending query");
+            pending
+                .responder
+                .respond(&self.socket, &pending.request, response);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Returns the max size of a datagram sent in answer to the pending
+    /// query, which depends on its record type and the response size the
+    /// requester supports.
+    fn max_send_size(&self) -> usize {
+        let pending = match self.pending.as_ref() {
+            Some(pending) => pending,
+            None => return self.endpoint.max_request_size(),
+        };
+        let max_len = match pending.responder {
+            Responder::Udp(_) => max_udp_response_len(&pending.request),
+            Responder::Tcp(_) => u16::MAX as usize,
+        };
+        let response = build_response(&pending.request, ResponseCode::NoError);
+        let response_len = response.to_vec().map_or(max_len, |bytes| bytes.len());
+        let answers_len = max_len.saturating_sub(response_len);
+        cmp::min(
+            self.max_answer_data(&pending.query, answers_len),
+            SplitDatagram::<Bytes>::max_data_len(),
+        )
+    }
+
+    /// Returns the max size of a datagram received in a query name.
+    fn max_recv_size(&self) -> usize {
+        self.endpoint.max_request_size()
+    }
+}
+
+impl<E, D> fmt::Debug for DnsServer<E, D>
+where
+    E: fmt::Debug,
+    D: Datagram,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DnsServer").field(&self.endpoint).finish()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+struct PendingQuery {
+    request: Message,
+    query: Query,
+    responder: Responder,
+}
+
+#[derive(Debug)]
+enum Responder {
+    Udp(SocketAddr),
+    Tcp(oneshot::Sender<Vec<u8>>),
+}
+
+impl Responder {
+    /// Respond without waiting, dropping the response on failure.
+    fn respond(self, socket: &UdpSocket, request: &Message, response: Message) {
+        match self {
+            Self::Udp(addr) => match encode_udp_response(request, response) {
+                Ok(response) => {
+                    if let Err(err) = socket.try_send_to(&response[..], addr) {
+                        warn!("failed to send DNS response to {}: {}", addr, err);
+                    }
+                }
+                Err(err) => warn!("failed to encode DNS response: {}", err),
+            },
+            Self::Tcp(tx) => match response.to_vec() {
+                Ok(response) => drop(tx.send(response)),
+                Err(err) => warn!("failed to encode DNS response: {}", err),
+            },
+        }
+    }
+}
+
+fn build_response(request: &Message, code: ResponseCode) -> Message {
+    let mut response = Message::new();
+    response
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_authoritative(true)
+        .set_recursion_desired(request.recursion_desired())
+        .set_response_code(code)
+        .add_queries(request.queries().to_vec());
+    if request.edns().is_some() {
+        let mut edns = Edns::new();
+        edns.set_max_payload(MAX_MESSAGE_LEN as u16);
+        response.set_edns(edns);
+    }
+    response
+}
+
+/// Returns the max length of a UDP response the requester supports.
+fn max_udp_response_len(request: &Message) -> usize {
+    request
+        .edns()
+        .map(|edns| edns.max_payload())
+        .unwrap_or(UDP_DEFAULT_PAYLOAD_LEN)
+        .max(UDP_DEFAULT_PAYLOAD_LEN) as usize
+}
+
+/// Encodes a UDP response, truncating it if larger than the
+/// requester supports.
+fn encode_udp_response(
+    request: &Message,
+    mut response: Message,
+) -> Result<Vec<u8>, trust_dns_proto::error::ProtoError> {
+    let bytes = response.to_vec()?;
+    if bytes.len() <= max_udp_response_len(request) {
+        return Ok(bytes);
+    }
+    debug!("truncating DNS response of {} bytes", bytes.len());
+    response.take_answers();
+    response.set_truncated(true);
+    response.to_vec()
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+async fn accept_tcp(listener: TcpListener, tx: mpsc::UnboundedSender<TcpRequest>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                debug!("accepted TCP connection from {}", addr);
+                tokio::spawn(serve_tcp(stream, tx.clone()));
+            }
+            Err(err) => warn!("failed to accept TCP connection: {}", err),
+        }
+        if tx.is_closed() {
+            break;
+        }
+    }
+}
+
+async fn serve_tcp(mut stream: TcpStream, tx: mpsc::UnboundedSender<TcpRequest>) {
+    while let Ok(len) = stream.read_u16().await {
+        let mut request = vec![0; len as usize];
+        if stream.read_exact(&mut request[..]).await.is_err() {
+            break;
+        }
+        let (response_tx, response_rx) = oneshot::channel();
+        if tx.unbounded_send((request, response_tx)).is_err() {
+            break;
+        }
+        let response = match response_rx.await {
+            Ok(response) => response,
+            Err(_) => break,
+        };
+        let result = match stream.write_u16(response.len() as u16).await {
+            Ok(()) => stream.write_all(&response[..]).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            debug!("failed to write TCP response: {}", err);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use trust_dns_proto::rr::Name;
+
+    use super::*;
+    use crate::packet::*;
+    use crate::transport::dns::{BasicDnsEndpoint, DnsClient};
+    use crate::transport::Encode;
+
+    fn endpoint(query_type: RecordType) -> BasicDnsEndpoint {
+        let constant = Name::from_ascii("example.com.").unwrap();
+        BasicDnsEndpoint::new_with_defaults(vec![query_type], constant).unwrap()
+    }
+
+    fn packet(session_id: SessionId, data: &'static [u8]) -> LazyPacket {
+        let head = SessionHeader::new(1, PacketKind::MSG, session_id);
+        Packet::new(head, SessionBodyBytes(Bytes::from_static(data))).translate()
+    }
+
+    async fn bind_server() -> DnsServer<BasicDnsEndpoint, LazyPacket> {
+        let addr = (Ipv4Addr::LOCALHOST, 0).into();
+        DnsServer::bind(addr, endpoint(RecordType::TXT))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_dns_server_exchange() {
+        let mut server = bind_server().await;
+        let addr = server.local_addr().unwrap();
+        for query_type in <BasicDnsEndpoint as DnsEndpoint>::supported_queries() {
+            let mut client = DnsClient::connect(addr, endpoint(*query_type))
+                .await
+                .unwrap();
+            let request = packet(1, b"request");
+            let response = packet(2, b"response");
+            future::poll_fn(|cx| client.poll_send(cx, request.clone()))
+                .await
+                .unwrap();
+            let received = future::poll_fn(|cx| server.poll_recv(cx)).await.unwrap();
+            assert_eq!(received, request);
+            future::poll_fn(|cx| server.poll_send(cx, response.clone()))
+                .await
+                .unwrap();
+            let received = future::poll_fn(|cx| client.poll_recv(cx)).await.unwrap();
+            assert_eq!(received, response, "{}", query_type);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dns_server_txt_encoding() {
+        let mut server = bind_server().await;
+        let addr = server.local_addr().unwrap();
+        for encoding in &[TxtEncoding::Hex, TxtEncoding::Base64, TxtEncoding::Raw] {
+            let endpoint = endpoint(RecordType::TXT).txt_encoding(*encoding);
+            let mut client = DnsClient::connect(addr, endpoint).await.unwrap();
+            let request = packet(1, b"request");
+            let response = packet(2, b"\x00\xFFresponse");
+            future::poll_fn(|cx| client.poll_send(cx, request.clone()))
+                .await
+                .unwrap();
+            let received = future::poll_fn(|cx| server.poll_recv(cx)).await.unwrap();
+            assert_eq!(received, request);
+            future::poll_fn(|cx| server.poll_send(cx, response.clone()))
+                .await
+                .unwrap();
+            let received = future::poll_fn(|cx| client.poll_recv(cx)).await.unwrap();
+            assert_eq!(received, response, "{:?}", encoding);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dns_server_multi_name() {
+        let addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let mut server = DnsServer::bind(addr, endpoint(RecordType::TXT))
+            .await
+            .unwrap()
+            .multi_name(true);
+        let addr = server.local_addr().unwrap();
+        for query_type in &[RecordType::CNAME, RecordType::MX] {
+            let mut client = DnsClient::connect(addr, endpoint(*query_type))
+                .await
+                .unwrap()
+                .multi_name(true);
+            let request = packet(1, b"request");
+            let response = packet(2, &[0x5A; 200]);
+            future::poll_fn(|cx| client.poll_send(cx, request.clone()))
+                .await
+                .unwrap();
+            let received = future::poll_fn(|cx| server.poll_recv(cx)).await.unwrap();
+            assert_eq!(received, request);
+            assert_eq!(server.max_send_size(), 255);
+            future::poll_fn(|cx| server.poll_send(cx, response.clone()))
+                .await
+                .unwrap();
+            let received = future::poll_fn(|cx| client.poll_recv(cx)).await.unwrap();
+            assert_eq!(received, response, "{}", query_type);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dns_server_non_tunnel_query() {
+        let mut server = bind_server().await;
+        let addr = server.local_addr().unwrap();
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let cases = &[
+            ("www.example.org.", ResponseCode::Refused),
+            ("not-hex.example.com.", ResponseCode::NXDomain),
+        ];
+        for (name, code) in cases {
+            let mut request = Message::new();
+            request.set_id(7).add_query(Query::query(
+                Name::from_ascii(name).unwrap(),
+                RecordType::TXT,
+            ));
+            socket
+                .send_to(&request.to_vec().unwrap()[..], addr)
+                .await
+                .unwrap();
+            let mut buf = vec![0; MAX_MESSAGE_LEN];
+            let len = {
+                let serve = future::poll_fn(|cx| server.poll_recv(cx));
+                let recv = socket.recv(&mut buf[..]);
+                futures::pin_mut!(serve, recv);
+                match future::select(serve, recv).await {
+                    future::Either::Right((len, _)) => len.unwrap(),
+                    future::Either::Left(_) => panic!("unexpected datagram"),
+                }
+            };
+            let response = Message::from_vec(&buf[..len]).unwrap();
+            assert_eq!(response.id(), 7);
+            assert_eq!(response.response_code(), *code, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dns_server_answer_budget() {
+        let mut server = bind_server().await;
+        let addr = server.local_addr().unwrap();
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let request = packet(1, b"request");
+        let mut request_data = BytesMut::new();
+        request.encode(&mut request_data);
+        let mut header = BytesMut::new();
+        packet(2, b"").encode(&mut header);
+        for query_type in <BasicDnsEndpoint as DnsEndpoint>::supported_queries() {
+            // Without EDNS, responses are limited to 512 bytes.
+            let (name, _) = endpoint(*query_type)
+                .build_request(request_data.clone().freeze())
+                .unwrap();
+            let mut message = Message::new();
+            message.set_id(7).add_query(Query::query(name, *query_type));
+            socket
+                .send_to(&message.to_vec().unwrap()[..], addr)
+                .await
+                .unwrap();
+            let received = future::poll_fn(|cx| server.poll_recv(cx)).await.unwrap();
+            assert_eq!(received, request);
+            let max = server.max_send_size();
+            if *query_type == RecordType::A {
+                assert!(max < server.max_recv_size());
+            }
+            let data = Bytes::from(vec![0x5A; max - header.len()]);
+            let head = SessionHeader::new(2, PacketKind::MSG, 2);
+            let response: LazyPacket = Packet::new(head, SessionBodyBytes(data)).translate();
+            future::poll_fn(|cx| server.poll_send(cx, response.clone()))
+                .await
+                .unwrap();
+            let mut buf = vec![0; MAX_MESSAGE_LEN];
+            let len = socket.recv(&mut buf[..]).await.unwrap();
+            let response = Message::from_vec(&buf[..len]).unwrap();
+            assert!(!response.truncated(), "{} truncated at {}", query_type, max);
+            assert_ne!(response.answer_count(), 0);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Fixtures shared by the DNS client tests.
+
+#[cfg(any(feature = "https", feature = "tls"))]
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::future;
+#[cfg(any(feature = "https", feature = "tls"))]
+use tokio_rustls::{
+    rustls::crypto::ring,
+    rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+    rustls::ServerConfig,
+    TlsAcceptor,
+};
+
+use crate::packet::*;
+use crate::transport::Transport;
+
+use super::{BasicDnsEndpoint, DnsServer, Name, RecordType};
+
+pub(super) fn endpoint() -> BasicDnsEndpoint {
+    let constant = Name::from_ascii("example.com.").unwrap();
+    BasicDnsEndpoint::new_with_defaults(vec![RecordType::TXT], constant).unwrap()
+}
+
+pub(super) fn packet(session_id: SessionId, data: &'static [u8]) -> LazyPacket {
+    let head = SessionHeader::new(1, PacketKind::MSG, session_id);
+    Packet::new(head, SessionBodyBytes(Bytes::from_static(data))).translate()
+}
+
+/// Builds a TLS acceptor with a self-signed certificate for `localhost`,
+/// returning it with the certificate PEM.
+#[cfg(any(feature = "https", feature = "tls"))]
+pub(super) fn tls_acceptor() -> (TlsAcceptor, String) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![certified.cert.der().clone()], PrivateKeyDer::from(key))
+        .unwrap();
+    (TlsAcceptor::from(Arc::new(config)), certified.cert.pem())
+}
+
+/// Sends a request from the client to the server, and a response back.
+pub(super) async fn exchange<T>(
+    client: &mut T,
+    server: &mut DnsServer<BasicDnsEndpoint, LazyPacket>,
+) where
+    T: Transport<LazyPacket>,
+{
+    let request = packet(1, b"request");
+    let response = packet(2, b"response");
+    future::poll_fn(|cx| client.poll_send(cx, request.clone()))
+        .await
+        .unwrap();
+    let received = future::poll_fn(|cx| server.poll_recv(cx)).await.unwrap();
+    assert_eq!(received, request);
+    future::poll_fn(|cx| server.poll_send(cx, response.clone()))
+        .await
+        .unwrap();
+    let received = future::poll_fn(|cx| client.poll_recv(cx)).await.unwrap();
+    assert_eq!(received, response);
+}
@@ -0,0 +1,311 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::future;
+use tokio::net::TcpStream;
+use tokio::runtime;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    self, CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use tokio_rustls::TlsConnector;
+use trust_dns_client::client::AsyncClient;
+use trust_dns_proto::error::ProtoError;
+use trust_dns_proto::iocompat::AsyncIoTokioAsStd;
+use trust_dns_proto::tcp::{TcpClientStream, TcpStream as DnsTcpStream};
+use trust_dns_proto::BufDnsStreamHandle;
+
+use crate::transport::Datagram;
+
+use super::{DnsClient, DnsEndpoint};
+
+/// How a DNS-over-TLS server's certificate is trusted.
+#[derive(Debug, Clone)]
+enum TlsTrust {
+    /// Certificates issued for the server name by the web PKI roots.
+    WebPki,
+    /// Certificates issued for the server name by one of these CAs.
+    Ca(Vec<CertificateDer<'static>>),
+    /// Only this certificate, whatever the server name.
+    Pinned(CertificateDer<'static>),
+}
+
+/// Verification options for DNS-over-TLS (RFC 7858) servers.
+///
+/// By default the server must present a certificate for its IP address
+/// issued by one of the web PKI roots.
+#[derive(Debug, Clone)]
+pub struct DnsTlsConfig {
+    server_name: Option<String>,
+    trust: TlsTrust,
+}
+
+impl DnsTlsConfig {
+    /// Set the name the server's certificate is verified against, which
+    /// is also sent to the server with SNI.
+    pub fn server_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    /// Only trust certificates issued by the CAs in a PEM file.
+    pub fn ca_pem(mut self, pem: &[u8]) -> Result<Self, io::Error> {
+        let certs = CertificateDer::pem_slice_iter(pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid_pem)?;
+        if certs.is_empty() {
+            return Err(invalid_pem("no certificates"));
+        }
+        self.trust = TlsTrust::Ca(certs);
+        Ok(self)
+    }
+
+    /// Only trust the certificate in a PEM file.
+    ///
+    /// The server name is not verified, as the certificate is trusted as is.
+    pub fn pin_pem(mut self, pem: &[u8]) -> Result<Self, io::Error> {
+        let cert = CertificateDer::from_pem_slice(pem).map_err(invalid_pem)?;
+        self.trust = TlsTrust::Pinned(cert);
+        Ok(self)
+    }
+
+    /// Build the rustls client config for these options.
+    pub fn client_config(&self) -> ClientConfig {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("default protocol versions supported");
+        let builder = match self.trust {
+            TlsTrust::WebPki => {
+                let roots = webpki_roots::TLS_SERVER_ROOTS.to_vec();
+                builder.with_root_certificates(RootCertStore { roots })
+            }
+            TlsTrust::Ca(ref certs) => {
+                let mut roots = RootCertStore::empty();
+                roots.add_parsable_certificates(certs.iter().cloned());
+                builder.with_root_certificates(roots)
+            }
+            TlsTrust::Pinned(ref cert) => {
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                        cert: cert.clone(),
+                        provider,
+                    }))
+            }
+        };
+        builder.with_no_client_auth()
+    }
+
+    fn server_name_for(&self, addr: SocketAddr) -> Result<ServerName<'static>, io::Error> {
+        match self.server_name {
+            Some(ref name) => ServerName::try_from(name.clone())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)),
+            None => Ok(ServerName::from(addr.ip())),
+        }
+    }
+}
+
+impl Default for DnsTlsConfig {
+    fn default() -> Self {
+        Self {
+            server_name: None,
+            trust: TlsTrust::WebPki,
+        }
+    }
+}
+
+impl<E, D> DnsClient<AsyncClient, E, D>
+where
+    E: DnsEndpoint,
+    D: Datagram,
+{
+    /// Connect to a DNS-over-TLS server.
+    pub async fn connect_tls(
+        addr: SocketAddr,
+        config: &DnsTlsConfig,
+        endpoint: E,
+    ) -> Result<Self, ProtoError> {
+        let server_name = config.server_name_for(addr)?;
+        let connector = TlsConnector::from(Arc::new(config.client_config()));
+        let stream = TcpStream::connect(addr).await?;
+        let stream = connector.connect(server_name, stream).await?;
+        let (stream, sender) = DnsTcpStream::from_stream(AsyncIoTokioAsStd(stream), addr);
+        let stream = future::ready(Ok(TcpClientStream::from_stream(stream)));
+        let sender = Box::new(BufDnsStreamHandle::new(addr, sender));
+        let (client, bg) = AsyncClient::new(stream, sender, None).await?;
+        let rt = runtime::Handle::current();
+        rt.spawn(bg);
+        Ok(Self::new(client, endpoint, rt))
+    }
+}
+
+fn invalid_pem<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Verifies the server presents exactly the pinned certificate.
+#[derive(Debug)]
+struct PinnedVerifier {
+    cert: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if *end_entity == self.cert {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(CertificateError::ApplicationVerificationFailure.into())
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algs = &self.provider.signature_verification_algorithms;
+        crypto::verify_tls12_signature(message, cert, dss, algs)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algs = &self.provider.signature_verification_algorithms;
+        crypto::verify_tls13_signature(message, cert, dss, algs)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use super::*;
+    use crate::packet::LazyPacket;
+    use crate::transport::dns::testing::{endpoint, exchange, tls_acceptor};
+    use crate::transport::dns::{BasicDnsEndpoint, DnsServer};
+
+    /// Serves DNS-over-TLS with a self-signed certificate for `localhost`,
+    /// relaying each DNS message to a DNS server over UDP.
+    async fn bind_tls_relay(dns_addr: SocketAddr) -> (SocketAddr, String) {
+        let (acceptor, cert_pem) = tls_acceptor();
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut stream = match acceptor.accept(stream).await {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+                    while let Ok(len) = stream.read_u16().await {
+                        let mut message = vec![0; len as usize];
+                        stream.read_exact(&mut message[..]).await.unwrap();
+                        socket.send_to(&message[..], dns_addr).await.unwrap();
+                        message.resize(4096, 0);
+                        let len = socket.recv(&mut message[..]).await.unwrap();
+                        stream.write_u16(len as u16).await.unwrap();
+                        stream.write_all(&message[..len]).await.unwrap();
+                    }
+                });
+            }
+        });
+        (addr, cert_pem)
+    }
+
+    async fn bind_server() -> (DnsServer<BasicDnsEndpoint, LazyPacket>, SocketAddr, String) {
+        let addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let server = DnsServer::bind(addr, endpoint()).await.unwrap();
+        let (tls_addr, cert_pem) = bind_tls_relay(server.local_addr().unwrap()).await;
+        (server, tls_addr, cert_pem)
+    }
+
+    #[tokio::test]
+    async fn test_dns_client_tls_ca() {
+        let (mut server, addr, cert_pem) = bind_server().await;
+        let config = DnsTlsConfig::default()
+            .server_name("localhost")
+            .ca_pem(cert_pem.as_bytes())
+            .unwrap();
+        let mut client = DnsClient::connect_tls(addr, &config, endpoint())
+            .await
+            .unwrap();
+        exchange(&mut client, &mut server).await;
+    }
+
+    #[tokio::test]
+    async fn test_dns_client_tls_pinned() {
+        let (mut server, addr, cert_pem) = bind_server().await;
+        // The certificate is not issued for the IP address verified against
+        // by default, but is trusted as pinned.
+        let config = DnsTlsConfig::default()
+            .pin_pem(cert_pem.as_bytes())
+            .unwrap();
+        let mut client = DnsClient::connect_tls(addr, &config, endpoint())
+            .await
+            .unwrap();
+        exchange(&mut client, &mut server).await;
+    }
+
+    #[tokio::test]
+    async fn test_dns_client_tls_rejects() {
+        let (_server, addr, cert_pem) = bind_server().await;
+        let other_pem = rcgen::generate_simple_self_signed(vec!["localhost".into()])
+            .unwrap()
+            .cert
+            .pem();
+        let configs = vec![
+            DnsTlsConfig::default()
+                .server_name("example.org")
+                .ca_pem(cert_pem.as_bytes())
+                .unwrap(),
+            DnsTlsConfig::default()
+                .server_name("localhost")
+                .ca_pem(other_pem.as_bytes())
+                .unwrap(),
+            DnsTlsConfig::default()
+                .pin_pem(other_pem.as_bytes())
+                .unwrap(),
+        ];
+        for config in configs {
+            let result =
+                DnsClient::<_, _, LazyPacket>::connect_tls(addr, &config, endpoint()).await;
+            assert!(result.is_err(), "{:?}", config);
+        }
+    }
+}
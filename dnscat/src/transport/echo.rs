@@ -6,7 +6,7 @@ use bytes::BytesMut;
 use crate::packet::*;
 use crate::transport::{Encode, Transport};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct PacketEchoTransport {
     datagram: Option<LazyPacket>,
     send_task: Option<Waker>,
@@ -74,7 +74,11 @@ impl Transport<LazyPacket> for PacketEchoTransport {
         }
     }
 
-    fn max_datagram_size(&self) -> usize {
+    fn max_send_size(&self) -> usize {
         usize::max_value()
     }
+
+    fn max_recv_size(&self) -> usize {
+        usize::MAX
+    }
 }
@@ -0,0 +1,365 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use failure::Fail;
+use futures::channel::mpsc;
+use futures::{ready, FutureExt, Stream};
+use log::debug;
+use rand::{rngs::OsRng, Rng};
+
+use crate::clock::{Clock, Delay, SystemClock};
+use crate::packet::LazyPacket;
+use crate::transport::{Decode, Encode, Transport};
+
+/// A datagram in flight, with the instant it is delivered.
+type Delivery = (Instant, LazyPacket);
+
+#[derive(Debug, Clone, PartialEq, Eq, Fail)]
+pub enum MemoryTransportError {
+    #[fail(display = "Peer transport closed")]
+    Closed,
+    #[fail(display = "Timed out waiting for a datagram")]
+    Timeout,
+}
+
+/// A transport connected in memory to another, injecting faults into the
+/// datagrams it sends.
+///
+/// Faults are driven by the transport's random number generator, which can
+/// be seeded to reproduce them. As with DNS, a lost datagram is only noticed
+/// by a receiver with a timeout set.
+#[derive(Debug)]
+pub struct MemoryTransport<R = OsRng> {
+    tx: mpsc::UnboundedSender<Delivery>,
+    rx: mpsc::UnboundedReceiver<Delivery>,
+    random: R,
+    loss: f64,
+    duplication: f64,
+    reordering: f64,
+    corruption: f64,
+    latency: Duration,
+    recv_timeout: Option<Duration>,
+    clock: Arc<dyn Clock>,
+    held: Option<LazyPacket>,
+    next: Option<(LazyPacket, Delay)>,
+    timeout: Option<Delay>,
+}
+
+impl MemoryTransport {
+    /// Construct a pair of transports connected to each other, without
+    /// any faults.
+    pub fn pair() -> (Self, Self) {
+        Self::pair_with_random(OsRng, OsRng)
+    }
+}
+
+impl<R> MemoryTransport<R>
+where
+    R: Rng,
+{
+    /// Construct a pair of transports connected to each other, each
+    /// injecting faults with the given random number generator.
+    pub fn pair_with_random(a_random: R, b_random: R) -> (Self, Self) {
+        let (a_tx, a_rx) = mpsc::unbounded();
+        let (b_tx, b_rx) = mpsc::unbounded();
+        let a = Self::new(a_tx, b_rx, a_random);
+        let b = Self::new(b_tx, a_rx, b_random);
+        (a, b)
+    }
+
+    fn new(
+        tx: mpsc::UnboundedSender<Delivery>,
+        rx: mpsc::UnboundedReceiver<Delivery>,
+        random: R,
+    ) -> Self {
+        Self {
+            tx,
+            rx,
+            random,
+            loss: 0.0,
+            duplication: 0.0,
+            reordering: 0.0,
+            corruption: 0.0,
+            latency: Duration::from_secs(0),
+            recv_timeout: None,
+            clock: Arc::new(SystemClock),
+            held: None,
+            next: None,
+            timeout: None,
+        }
+    }
+
+    /// Set the probability a datagram sent is lost.
+    ///
+    /// Panics if the probability is not between 0 and 1.
+    pub fn loss(mut self, probability: f64) -> Self {
+        assert_probability(probability);
+        self.loss = probability;
+        self
+    }
+
+    /// Set the probability a datagram sent is delivered twice.
+    ///
+    /// Panics if the probability is not between 0 and 1.
+    pub fn duplication(mut self, probability: f64) -> Self {
+        assert_probability(probability);
+        self.duplication = probability;
+        self
+    }
+
+    /// Set the probability a datagram sent is held back, and delivered
+    /// after the next datagram sent.
+    ///
+    /// Panics if the probability is not between 0 and 1.
+    pub fn reordering(mut self, probability: f64) -> Self {
+        assert_probability(probability);
+        self.reordering = probability;
+        self
+    }
+
+    /// Set the probability a datagram sent has a bit flipped.
+    ///
+    /// Corrupted datagrams that no longer decode are lost.
+    ///
+    /// Panics if the probability is not between 0 and 1.
+    pub fn corruption(mut self, probability: f64) -> Self {
+        assert_probability(probability);
+        self.corruption = probability;
+        self
+    }
+
+    /// Set the delay before a datagram sent is delivered.
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Set how long to wait for a datagram before failing with
+    /// [`MemoryTransportError::Timeout`], which by default is forever.
+    pub fn recv_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.recv_timeout = timeout;
+        self
+    }
+
+    /// Set the clock latency and timeouts are measured against, which
+    /// defaults to the system clock.
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    fn deliver(&mut self, datagram: LazyPacket) -> Result<(), MemoryTransportError> {
+        if self.random.gen_bool(self.loss) {
+            debug!("losing datagram");
+            return Ok(());
+        }
+        let datagram = if self.random.gen_bool(self.corruption) {
+            match self.corrupt(datagram) {
+                Some(datagram) => datagram,
+                None => return Ok(()),
+            }
+        } else {
+            datagram
+        };
+        let copies = if self.random.gen_bool(self.duplication) {
+            debug!("duplicating datagram");
+            2
+        } else {
+            1
+        };
+        let at = self.clock.now() + self.latency;
+        for _ in 0..copies {
+            self.tx
+                .unbounded_send((at, datagram.clone()))
+                .map_err(|_| MemoryTransportError::Closed)?;
+        }
+        Ok(())
+    }
+
+    /// Flips a random bit of a datagram, returning `None` if it no
+    /// longer decodes.
+    fn corrupt(&mut self, datagram: LazyPacket) -> Option<LazyPacket> {
+        let mut bytes = BytesMut::new();
+        datagram.encode(&mut bytes);
+        let i = self.random.gen_range(0..bytes.len());
+        bytes[i] ^= 1 << self.random.gen_range(0..8);
+        let mut bytes: Bytes = bytes.freeze();
+        match LazyPacket::decode(&mut bytes) {
+            Ok(datagram) if bytes.is_empty() => {
+                debug!("corrupting datagram");
+                Some(datagram)
+            }
+            _ => {
+                debug!("dropping datagram corrupted beyond decoding");
+                None
+            }
+        }
+    }
+
+    fn poll_next_delivery(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<LazyPacket, MemoryTransportError>> {
+        loop {
+            if let Some((_, ref mut delay)) = self.next {
+                ready!(delay.poll_unpin(cx));
+                let (datagram, _) = self.next.take().expect("next delivery");
+                return Poll::Ready(Ok(datagram));
+            }
+            match ready!(Pin::new(&mut self.rx).poll_next(cx)) {
+                None => return Poll::Ready(Err(MemoryTransportError::Closed)),
+                Some((at, datagram)) => {
+                    let delay = self
+                        .clock
+                        .delay(at.saturating_duration_since(self.clock.now()));
+                    self.next = Some((datagram, delay));
+                }
+            }
+        }
+    }
+}
+
+impl<R> Transport<LazyPacket> for MemoryTransport<R>
+where
+    R: Rng + Unpin,
+{
+    type Error = MemoryTransportError;
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<LazyPacket, Self::Error>> {
+        if let Poll::Ready(result) = self.poll_next_delivery(cx) {
+            self.timeout = None;
+            return Poll::Ready(result);
+        }
+        if let Some(recv_timeout) = self.recv_timeout {
+            let clock = &self.clock;
+            let timeout = self
+                .timeout
+                .get_or_insert_with(|| clock.delay(recv_timeout));
+            ready!(timeout.poll_unpin(cx));
+            self.timeout = None;
+            return Poll::Ready(Err(MemoryTransportError::Timeout));
+        }
+        Poll::Pending
+    }
+
+    fn poll_send(
+        &mut self,
+        _cx: &mut Context<'_>,
+        datagram: LazyPacket,
+    ) -> Poll<Result<(), Self::Error>> {
+        if self.held.is_none() && self.random.gen_bool(self.reordering) {
+            debug!("holding back datagram");
+            self.held = Some(datagram);
+            return Poll::Ready(Ok(()));
+        }
+        self.deliver(datagram)?;
+        if let Some(held) = self.held.take() {
+            self.deliver(held)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn max_send_size(&self) -> usize {
+        LazyPacket::max_size() as usize
+    }
+
+    fn max_recv_size(&self) -> usize {
+        LazyPacket::max_size() as usize
+    }
+}
+
+fn assert_probability(probability: f64) {
+    assert!(
+        (0.0..=1.0).contains(&probability),
+        "probability must be between 0 and 1, got {}",
+        probability
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg32;
+
+    use super::*;
+    use crate::packet::*;
+
+    fn packet(packet_id: u16) -> LazyPacket {
+        let head = SessionHeader::new(packet_id, PacketKind::MSG, 1);
+        Packet::new(head, SessionBodyBytes(Bytes::from_static(b"hello"))).translate()
+    }
+
+    fn pair() -> (MemoryTransport<Pcg32>, MemoryTransport<Pcg32>) {
+        MemoryTransport::pair_with_random(Pcg32::seed_from_u64(1), Pcg32::seed_from_u64(2))
+    }
+
+    async fn send(transport: &mut MemoryTransport<Pcg32>, datagram: LazyPacket) {
+        future::poll_fn(|cx| transport.poll_send(cx, datagram.clone()))
+            .await
+            .unwrap();
+    }
+
+    async fn recv(
+        transport: &mut MemoryTransport<Pcg32>,
+    ) -> Result<LazyPacket, MemoryTransportError> {
+        future::poll_fn(|cx| transport.poll_recv(cx)).await
+    }
+
+    #[tokio::test]
+    async fn test_memory_transport_exchange() {
+        let (mut a, mut b) = pair();
+        send(&mut a, packet(1)).await;
+        assert_eq!(recv(&mut b).await, Ok(packet(1)));
+        send(&mut b, packet(2)).await;
+        assert_eq!(recv(&mut a).await, Ok(packet(2)));
+        drop(a);
+        assert_eq!(recv(&mut b).await, Err(MemoryTransportError::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_memory_transport_faults() {
+        let timeout = Some(Duration::from_millis(10));
+        let (a, b) = pair();
+        let (mut a, mut b) = (a.loss(1.0), b.recv_timeout(timeout));
+        send(&mut a, packet(1)).await;
+        assert_eq!(recv(&mut b).await, Err(MemoryTransportError::Timeout));
+
+        let (a, mut b) = pair();
+        let mut a = a.duplication(1.0).latency(Duration::from_millis(10));
+        let sent = Instant::now();
+        send(&mut a, packet(1)).await;
+        assert_eq!(recv(&mut b).await, Ok(packet(1)));
+        assert!(sent.elapsed() >= Duration::from_millis(10));
+        assert_eq!(recv(&mut b).await, Ok(packet(1)));
+
+        let (a, mut b) = pair();
+        let mut a = a.reordering(1.0);
+        send(&mut a, packet(1)).await;
+        send(&mut a, packet(2)).await;
+        assert_eq!(recv(&mut b).await, Ok(packet(2)));
+        assert_eq!(recv(&mut b).await, Ok(packet(1)));
+
+        let (a, b) = pair();
+        let (mut a, mut b) = (a.corruption(1.0), b.recv_timeout(timeout));
+        for packet_id in 0..16 {
+            send(&mut a, packet(packet_id)).await;
+            match recv(&mut b).await {
+                Ok(received) => assert_ne!(received, packet(packet_id)),
+                Err(err) => assert_eq!(err, MemoryTransportError::Timeout),
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "probability must be between 0 and 1")]
+    fn test_memory_invalid_probability() {
+        let _ = MemoryTransport::pair().0.loss(1.5);
+    }
+}
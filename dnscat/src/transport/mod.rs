@@ -1,4 +1,9 @@
+#[cfg(test)]
+pub(crate) mod channel;
 mod echo;
+mod memory;
+mod mux;
+mod pool;
 mod split;
 
 pub mod dns;
@@ -8,6 +13,9 @@ use std::task::{Context, Poll};
 use failure::Fail;
 
 pub use self::echo::PacketEchoTransport;
+pub use self::memory::{MemoryTransport, MemoryTransportError};
+pub use self::mux::{MuxTransport, TransportMux};
+pub use self::pool::{ResolverPool, ResolverStats};
 pub use self::split::*;
 
 pub use crate::util::{hex, Decode, Encode};
@@ -24,6 +32,8 @@ pub enum DatagramError<D: Fail> {
     Underflow,
     #[fail(display = "Hex decode error: {}", _0)]
     Hex(hex::DecodeError),
+    #[fail(display = "Base64 decode error: {}", _0)]
+    Base64(data_encoding::DecodeError),
     #[fail(display = "Split datagram error: {}", _0)]
     Split(SplitDatagramError),
 }
@@ -34,6 +44,12 @@ impl<D: Fail> From<hex::DecodeError> for DatagramError<D> {
     }
 }
 
+impl<D: Fail> From<data_encoding::DecodeError> for DatagramError<D> {
+    fn from(err: data_encoding::DecodeError) -> Self {
+        Self::Base64(err)
+    }
+}
+
 impl<D: Fail> From<SplitDatagramError> for DatagramError<D> {
     fn from(err: SplitDatagramError) -> Self {
         Self::Split(err)
@@ -52,6 +68,12 @@ where
     /// Poll sending a datagram.
     fn poll_send(&mut self, cx: &mut Context<'_>, datagram: D) -> Poll<Result<(), Self::Error>>;
 
-    /// Returns the max datagram size this transport supports.
-    fn max_datagram_size(&self) -> usize;
+    /// Returns the max size of a datagram this transport can send.
+    fn max_send_size(&self) -> usize;
+
+    /// Returns the max size of a datagram this transport can receive.
+    ///
+    /// This may differ from the send size, such as with DNS where a query
+    /// name holds less than an answer.
+    fn max_recv_size(&self) -> usize;
 }
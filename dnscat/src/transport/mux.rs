@@ -0,0 +1,345 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+use futures::ready;
+use log::warn;
+
+use crate::transport::{Datagram, Transport};
+
+type HandleId = usize;
+
+struct MuxState<T> {
+    transport: T,
+    /// The handle currently exchanging with the transport.
+    owner: Option<HandleId>,
+    /// Handles waiting for their turn to exchange, in order.
+    waiting: VecDeque<(HandleId, Waker)>,
+    /// The number of datagrams the owner has sent without a reply yet.
+    awaiting_recv: usize,
+    /// The number of replies to receive and drop before the next exchange,
+    /// as the handle that sent the requests is gone.
+    discard_recv: usize,
+    next_id: HandleId,
+}
+
+impl<T> MuxState<T> {
+    /// Returns `true` if the handle may exchange with the transport,
+    /// otherwise queues the handle to be woken on its turn.
+    fn acquire(&mut self, id: HandleId, cx: &mut Context<'_>) -> bool {
+        // The owner may send more before its replies are received, unless
+        // others are waiting their turn.
+        if self.owner == Some(id) && self.waiting.is_empty() {
+            return true;
+        }
+        let is_next = match self.waiting.front() {
+            Some((next, _)) => *next == id,
+            None => true,
+        };
+        if self.owner.is_none() && is_next {
+            self.waiting.pop_front();
+            self.owner = Some(id);
+            return true;
+        }
+        match self.waiting.iter_mut().find(|(waiting, _)| *waiting == id) {
+            Some((_, waker)) => *waker = cx.waker().clone(),
+            None => self.waiting.push_back((id, cx.waker().clone())),
+        }
+        false
+    }
+
+    /// Releases the transport, waking the next handle waiting.
+    fn release(&mut self) {
+        self.owner = None;
+        self.awaiting_recv = 0;
+        if let Some((_, waker)) = self.waiting.front() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// Shares one request/response transport between several sessions.
+///
+/// Each session is given a [`MuxTransport`] handle. Only one handle may
+/// exchange with the transport at a time (sends followed by receiving each
+/// reply), with handles waiting their turn in the order they first polled
+/// to send.
+///
+/// The transport must deliver exactly one reply, or error, for each datagram
+/// sent, as a [`DnsClient`] does. Only as many replies are received as were
+/// sent for, so any extra would be taken as the reply to a later exchange.
+///
+/// [`DnsClient`]: crate::transport::dns::DnsClient
+pub struct TransportMux<T> {
+    state: Arc<Mutex<MuxState<T>>>,
+}
+
+impl<T> TransportMux<T> {
+    pub fn new(transport: T) -> Self {
+        let state = MuxState {
+            transport,
+            owner: None,
+            waiting: VecDeque::new(),
+            awaiting_recv: 0,
+            discard_recv: 0,
+            next_id: 0,
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Creates a new handle to the shared transport.
+    pub fn handle(&self) -> MuxTransport<T> {
+        let mut state = self.state.lock().expect("mux state poisoned");
+        let id = state.next_id;
+        state.next_id += 1;
+        MuxTransport {
+            id,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> Clone for TransportMux<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for TransportMux<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransportMux").finish()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// A handle to a transport shared with a [`TransportMux`].
+///
+/// A datagram must be sent before receiving its reply, which makes the
+/// handle suitable for clients only. Receiving with no reply owed to the
+/// handle never completes.
+pub struct MuxTransport<T> {
+    id: HandleId,
+    state: Arc<Mutex<MuxState<T>>>,
+}
+
+impl<T> MuxTransport<T> {
+    fn state(&self) -> MutexGuard<'_, MuxState<T>> {
+        self.state.lock().expect("mux state poisoned")
+    }
+}
+
+impl<T, D> Transport<D> for MuxTransport<T>
+where
+    T: Transport<D>,
+    D: Datagram,
+{
+    type Error = T::Error;
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<D, Self::Error>> {
+        let mut state = self.state();
+        if state.owner != Some(self.id) || state.awaiting_recv == 0 {
+            warn!("mux handle {} polled recv with no reply owed", self.id);
+            return Poll::Pending;
+        }
+        let result = ready!(state.transport.poll_recv(cx));
+        state.awaiting_recv -= 1;
+        if state.awaiting_recv == 0 {
+            state.release();
+        }
+        Poll::Ready(result)
+    }
+
+    fn poll_send(&mut self, cx: &mut Context<'_>, datagram: D) -> Poll<Result<(), Self::Error>> {
+        let mut state = self.state();
+        if !state.acquire(self.id, cx) {
+            return Poll::Pending;
+        }
+        while state.discard_recv > 0 {
+            // Any reply, or error, is for a handle that no longer exists.
+            let _ = ready!(state.transport.poll_recv(cx));
+            state.discard_recv -= 1;
+        }
+        let result = ready!(state.transport.poll_send(cx, datagram));
+        match result {
+            Ok(()) => state.awaiting_recv += 1,
+            Err(_) if state.awaiting_recv == 0 => state.release(),
+            Err(_) => (),
+        }
+        Poll::Ready(result)
+    }
+
+    fn max_send_size(&self) -> usize {
+        self.state().transport.max_send_size()
+    }
+
+    fn max_recv_size(&self) -> usize {
+        self.state().transport.max_recv_size()
+    }
+}
+
+impl<T> Drop for MuxTransport<T> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            let id = self.id;
+            state.waiting.retain(|(waiting, _)| *waiting != id);
+            if state.owner == Some(id) {
+                state.discard_recv = state.awaiting_recv;
+                state.release();
+            } else if state.owner.is_none() {
+                // We may have been next in line.
+                state.release();
+            }
+        }
+    }
+}
+
+impl<T> fmt::Debug for MuxTransport<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MuxTransport")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures::{future, pin_mut};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::client::ClientBuilder;
+    use crate::packet::LazyPacket;
+    use crate::server::ServerBuilder;
+    use crate::transport::channel::channel_pair;
+    use crate::transport::{Decode, PacketEchoTransport};
+
+    use super::*;
+
+    #[rustfmt::skip]
+    fn ping() -> LazyPacket {
+        LazyPacket::decode(&mut Bytes::from_static(&[
+            0x00, 0x01, // Packet ID
+            0xFF, // Packet kind
+            0x00, 0x02, // Ping ID
+            0x00, // Data
+        ]))
+        .unwrap()
+    }
+
+    async fn send<T: Transport<LazyPacket>>(transport: &mut T) -> bool {
+        future::poll_fn(|cx| Poll::Ready(transport.poll_send(cx, ping()).is_ready())).await
+    }
+
+    async fn recv<T: Transport<LazyPacket>>(transport: &mut T) -> bool {
+        future::poll_fn(|cx| Poll::Ready(transport.poll_recv(cx).is_ready())).await
+    }
+
+    #[tokio::test]
+    async fn test_mux_exchanges_in_turn() {
+        let mux = TransportMux::new(PacketEchoTransport::default());
+        let mut a = mux.handle();
+        let mut b = mux.handle();
+        let mut c = mux.handle();
+
+        assert!(send(&mut a).await);
+        // `c` queues before `b`, so must exchange before it.
+        assert!(!send(&mut c).await);
+        assert!(!send(&mut b).await);
+        assert!(recv(&mut a).await);
+        assert!(!send(&mut b).await);
+        assert!(send(&mut c).await);
+        // Dropping `c` mid-exchange discards its reply.
+        drop(c);
+        assert!(send(&mut b).await);
+        assert!(recv(&mut b).await);
+    }
+
+    #[tokio::test]
+    async fn test_mux_owner_yields_to_waiting() {
+        let mux = TransportMux::new(PacketEchoTransport::default());
+        let mut a = mux.handle();
+        let mut b = mux.handle();
+
+        assert!(send(&mut a).await);
+        assert!(!send(&mut b).await);
+        // `b` is waiting, so `a` can't send more until its turn comes again.
+        assert!(!send(&mut a).await);
+        assert!(recv(&mut a).await);
+        assert!(send(&mut b).await);
+        assert!(recv(&mut b).await);
+        assert!(send(&mut a).await);
+    }
+
+    #[tokio::test]
+    async fn test_mux_recv_without_reply_owed() {
+        let mux = TransportMux::new(PacketEchoTransport::default());
+        let mut a = mux.handle();
+        let mut b = mux.handle();
+
+        assert!(!recv(&mut a).await);
+        assert!(send(&mut a).await);
+        assert!(!recv(&mut b).await);
+        assert!(recv(&mut a).await);
+        // The reply was received, so `a` has given up its turn.
+        assert!(!recv(&mut a).await);
+        assert!(send(&mut b).await);
+        assert!(recv(&mut b).await);
+    }
+
+    #[tokio::test]
+    async fn test_mux_clients() {
+        let (client_transport, server_transport) = channel_pair();
+        let mut server = ServerBuilder::default()
+            .insecure(true)
+            .build_insecure(server_transport);
+        let serve = async {
+            loop {
+                let mut stream = server.accept().await.unwrap();
+                tokio::task::spawn_local(async move {
+                    let mut buf = [0u8; 5];
+                    stream.read_exact(&mut buf[..]).await.unwrap();
+                    stream.write_all(&buf[..]).await.unwrap();
+                    stream.flush().await.unwrap();
+                });
+            }
+        };
+
+        let mux = TransportMux::new(client_transport);
+        let echo = |id, data: &'static [u8; 5]| {
+            let transport = mux.handle();
+            async move {
+                let mut client = ClientBuilder::default()
+                    .session_id(id)
+                    .max_delay(Duration::from_millis(10))
+                    .connect_insecure(transport)
+                    .await
+                    .unwrap();
+                client.write_all(&data[..]).await.unwrap();
+                let mut buf = [0u8; 5];
+                client.read_exact(&mut buf[..]).await.unwrap();
+                assert_eq!(&buf, data);
+            }
+        };
+        let clients = future::join(echo(1, b"hello"), echo(2, b"world"));
+
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async {
+                pin_mut!(serve, clients);
+                future::select(serve, clients).await;
+            })
+            .await;
+    }
+}
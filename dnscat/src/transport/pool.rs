@@ -0,0 +1,537 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use crate::clock::{Clock, SystemClock};
+use crate::transport::{Datagram, Transport};
+
+type RequestId = u64;
+
+/// A request sent to one or more resolvers.
+struct Request {
+    /// The number of resolvers yet to reply or fail.
+    pending: usize,
+    answered: bool,
+}
+
+struct Resolver<T> {
+    name: String,
+    transport: T,
+    /// When each request awaiting a reply or failure was sent, in order.
+    in_flight: VecDeque<(Instant, RequestId)>,
+    /// The smoothed round trip time of replies.
+    rtt: Option<Duration>,
+    /// The number of failures since the last reply.
+    failures: usize,
+    exchanges: usize,
+    total_failures: usize,
+}
+
+impl<T> Resolver<T> {
+    /// Resolvers with fewer failures are preferred, followed by those with
+    /// a lower round trip time, untried resolvers being tried first.
+    fn score(&self) -> (usize, Duration) {
+        (self.failures, self.rtt.unwrap_or_default())
+    }
+
+    fn record_reply(&mut self, now: Instant) -> RequestId {
+        let (sent, id) = self.in_flight.pop_front().expect("reply to a request");
+        let sample = now.duration_since(sent);
+        // Weighted as the smoothed RTT of TCP (RFC 6298).
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+        self.failures = 0;
+        self.exchanges += 1;
+        id
+    }
+
+    fn record_failure<E: fmt::Display>(&mut self, err: &E) {
+        warn!("resolver `{}` failed: {}", self.name, err);
+        self.failures += 1;
+        self.total_failures += 1;
+    }
+}
+
+/// Statistics of a resolver in a [`ResolverPool`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolverStats<'a> {
+    pub name: &'a str,
+    /// The smoothed round trip time of replies, if any were received.
+    pub rtt: Option<Duration>,
+    /// The number of failures since the last reply.
+    pub failures: usize,
+    /// The number of replies received.
+    pub exchanges: usize,
+    pub total_failures: usize,
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+/// A transport exchanging through the healthiest of several resolvers.
+///
+/// Each request is sent to the resolver with the fewest failures since its
+/// last reply, then the lowest round trip time. A resolver failing is
+/// demoted, so retransmits switch to another without ending the session.
+///
+/// Requests may also be hedged, sent to several of the healthiest resolvers
+/// at once. The first reply received is returned, with the duplicates that
+/// follow dropped. A hedged request only fails if every resolver it was
+/// sent to fails.
+///
+/// Replies from a resolver are matched to the requests sent to it in order,
+/// so each resolver must reply in the order the requests were sent, as a
+/// [`DnsClient`] set to reply [`in_order`] does.
+///
+/// [`DnsClient`]: crate::transport::dns::DnsClient
+/// [`in_order`]: crate::transport::dns::DnsClient::in_order
+pub struct ResolverPool<T> {
+    resolvers: Vec<Resolver<T>>,
+    fan_out: usize,
+    requests: HashMap<RequestId, Request>,
+    next_request: RequestId,
+    current: Option<usize>,
+    recv_task: Option<Waker>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<T> ResolverPool<T> {
+    pub fn new() -> Self {
+        Self {
+            resolvers: Vec::new(),
+            fan_out: 1,
+            requests: HashMap::new(),
+            next_request: 0,
+            current: None,
+            recv_task: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Set the number of resolvers each request is sent to at once.
+    ///
+    /// Defaults to one, sending each request to the healthiest resolver.
+    pub fn fan_out(mut self, fan_out: usize) -> Self {
+        assert_ne!(fan_out, 0, "fan out must be greater than zero");
+        self.fan_out = fan_out;
+        self
+    }
+
+    /// Set the clock round trip times are measured against, which defaults
+    /// to the system clock.
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Add a resolver to the pool, named for logs and statistics.
+    pub fn add<S: Into<String>>(&mut self, name: S, transport: T) {
+        self.resolvers.push(Resolver {
+            name: name.into(),
+            transport,
+            in_flight: VecDeque::new(),
+            rtt: None,
+            failures: 0,
+            exchanges: 0,
+            total_failures: 0,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.resolvers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.resolvers.is_empty()
+    }
+
+    /// Returns the statistics of each resolver, in the order added.
+    pub fn stats(&self) -> impl Iterator<Item = ResolverStats<'_>> {
+        self.resolvers.iter().map(|resolver| ResolverStats {
+            name: &resolver.name,
+            rtt: resolver.rtt,
+            failures: resolver.failures,
+            exchanges: resolver.exchanges,
+            total_failures: resolver.total_failures,
+        })
+    }
+
+    /// Returns the resolvers to send the next request to, healthiest first.
+    fn select(&mut self) -> Vec<usize> {
+        assert!(!self.resolvers.is_empty(), "resolver pool is empty");
+        let mut selected = (0..self.resolvers.len()).collect::<Vec<_>>();
+        selected.sort_by_key(|i| self.resolvers[*i].score());
+        selected.truncate(self.fan_out);
+        let best = selected[0];
+        if self.current != Some(best) {
+            if self.current.is_some() {
+                info!("switching to resolver `{}`", self.resolvers[best].name);
+            }
+            self.current = Some(best);
+        }
+        selected
+    }
+
+    /// Returns `true` if a reply to a request is the first, and so should
+    /// be returned.
+    fn handle_reply(&mut self, id: RequestId) -> bool {
+        let request = self.requests.get_mut(&id).expect("request in flight");
+        let first = !request.answered;
+        request.answered = true;
+        request.pending -= 1;
+        if request.pending == 0 {
+            self.requests.remove(&id);
+        }
+        first
+    }
+
+    /// Returns `true` if a request failed on every resolver it was sent
+    /// to without a reply, and so the failure should be returned.
+    fn handle_failure(&mut self, id: RequestId) -> bool {
+        let request = self.requests.get_mut(&id).expect("request in flight");
+        request.pending -= 1;
+        if request.pending > 0 {
+            return false;
+        }
+        let answered = request.answered;
+        self.requests.remove(&id);
+        !answered
+    }
+}
+
+impl<T> Default for ResolverPool<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, D> Transport<D> for ResolverPool<T>
+where
+    T: Transport<D>,
+    D: Datagram + Clone,
+{
+    type Error = T::Error;
+
+    /// Receives the first reply from the resolvers with requests in flight.
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<D, Self::Error>> {
+        if self.requests.is_empty() {
+            self.recv_task = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let mut i = 0;
+        while i < self.resolvers.len() {
+            let resolver = &mut self.resolvers[i];
+            if resolver.in_flight.is_empty() {
+                i += 1;
+                continue;
+            }
+            match resolver.transport.poll_recv(cx) {
+                Poll::Pending => i += 1,
+                Poll::Ready(Ok(datagram)) => {
+                    let id = resolver.record_reply(self.clock.now());
+                    if self.handle_reply(id) {
+                        return Poll::Ready(Ok(datagram));
+                    }
+                    debug!("dropping duplicate reply from `{}`", self.resolvers[i].name);
+                }
+                Poll::Ready(Err(err)) => {
+                    let (_, id) = resolver.in_flight.pop_front().expect("request in flight");
+                    resolver.record_failure(&err);
+                    if self.handle_failure(id) {
+                        return Poll::Ready(Err(err));
+                    }
+                }
+            }
+        }
+        Poll::Pending
+    }
+
+    fn poll_send(&mut self, cx: &mut Context<'_>, datagram: D) -> Poll<Result<(), Self::Error>> {
+        let id = self.next_request;
+        let mut sent = 0;
+        let mut last_err = None;
+        for (n, i) in self.select().into_iter().enumerate() {
+            let resolver = &mut self.resolvers[i];
+            match resolver.transport.poll_send(cx, datagram.clone()) {
+                // Wait for the healthiest, hedging with the rest if ready.
+                Poll::Pending if n == 0 => return Poll::Pending,
+                Poll::Pending => continue,
+                Poll::Ready(Ok(())) => {
+                    resolver.in_flight.push_back((self.clock.now(), id));
+                    sent += 1;
+                }
+                Poll::Ready(Err(err)) => {
+                    resolver.record_failure(&err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        if sent == 0 {
+            let err = last_err.expect("send failed");
+            return Poll::Ready(Err(err));
+        }
+        self.next_request += 1;
+        self.requests.insert(
+            id,
+            Request {
+                pending: sent,
+                answered: false,
+            },
+        );
+        if let Some(recv_task) = self.recv_task.take() {
+            recv_task.wake();
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Returns the max datagram size all the resolvers can send.
+    fn max_send_size(&self) -> usize {
+        self.resolvers
+            .iter()
+            .map(|resolver| resolver.transport.max_send_size())
+            .min()
+            .expect("resolver pool is empty")
+    }
+
+    /// Returns the max datagram size all the resolvers can receive.
+    fn max_recv_size(&self) -> usize {
+        self.resolvers
+            .iter()
+            .map(|resolver| resolver.transport.max_recv_size())
+            .min()
+            .expect("resolver pool is empty")
+    }
+}
+
+impl<T> fmt::Debug for ResolverPool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.resolvers.iter().map(|resolver| &resolver.name))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use futures::future;
+
+    use super::*;
+    use crate::clock::Simulation;
+    use crate::packet::LazyPacket;
+    use crate::transport::Decode;
+
+    /// Echoes requests, or fails them if dead.
+    #[derive(Default)]
+    struct TestResolver {
+        replies: VecDeque<Option<LazyPacket>>,
+        dead: Arc<AtomicBool>,
+        paused: Arc<AtomicBool>,
+    }
+
+    impl Transport<LazyPacket> for TestResolver {
+        type Error = io::Error;
+
+        fn poll_recv(&mut self, _cx: &mut Context<'_>) -> Poll<Result<LazyPacket, Self::Error>> {
+            if self.paused.load(Ordering::SeqCst) {
+                return Poll::Pending;
+            }
+            match self.replies.pop_front() {
+                None => Poll::Pending,
+                Some(Some(datagram)) => Poll::Ready(Ok(datagram)),
+                Some(None) => Poll::Ready(Err(io::ErrorKind::TimedOut.into())),
+            }
+        }
+
+        fn poll_send(
+            &mut self,
+            _cx: &mut Context<'_>,
+            datagram: LazyPacket,
+        ) -> Poll<Result<(), Self::Error>> {
+            if self.dead.load(Ordering::SeqCst) {
+                self.replies.push_back(None);
+            } else {
+                self.replies.push_back(Some(datagram));
+            }
+            Poll::Ready(Ok(()))
+        }
+
+        fn max_send_size(&self) -> usize {
+            128
+        }
+
+        fn max_recv_size(&self) -> usize {
+            128
+        }
+    }
+
+    #[rustfmt::skip]
+    fn ping(ping_id: u8) -> LazyPacket {
+        LazyPacket::decode(&mut Bytes::from(vec![
+            0x00, 0x01, // Packet ID
+            0xFF, // Packet kind
+            0x00, ping_id, // Ping ID
+            0x00, // Data
+        ]))
+        .unwrap()
+    }
+
+    async fn send(pool: &mut ResolverPool<TestResolver>, packet: LazyPacket) {
+        future::poll_fn(|cx| pool.poll_send(cx, packet.clone()))
+            .await
+            .unwrap();
+    }
+
+    async fn recv(pool: &mut ResolverPool<TestResolver>) -> io::Result<LazyPacket> {
+        future::poll_fn(|cx| pool.poll_recv(cx)).await
+    }
+
+    async fn exchange(pool: &mut ResolverPool<TestResolver>) -> bool {
+        send(pool, ping(2)).await;
+        recv(pool).await.is_ok()
+    }
+
+    async fn is_pending(pool: &mut ResolverPool<TestResolver>) -> bool {
+        future::poll_fn(|cx| Poll::Ready(pool.poll_recv(cx).is_pending())).await
+    }
+
+    fn exchanges(pool: &ResolverPool<TestResolver>) -> Vec<(usize, usize)> {
+        pool.stats()
+            .map(|stats| (stats.exchanges, stats.total_failures))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_pool_failover() {
+        let a = TestResolver::default();
+        let a_dead = a.dead.clone();
+        let mut pool = ResolverPool::new();
+        pool.add("a", a);
+        pool.add("b", TestResolver::default());
+
+        // Untried resolvers are tried first.
+        assert!(exchange(&mut pool).await);
+        assert!(exchange(&mut pool).await);
+        assert_eq!(exchanges(&pool), vec![(1, 0), (1, 0)]);
+
+        a_dead.store(true, Ordering::SeqCst);
+        while exchanges(&pool)[0].1 == 0 {
+            exchange(&mut pool).await;
+        }
+        // Once `a` fails, `b` is used.
+        for _ in 0..3 {
+            assert!(exchange(&mut pool).await);
+        }
+        let stats = exchanges(&pool);
+        assert_eq!(stats[0].1, 1);
+        assert_eq!(stats[1].1, 0);
+        assert!(stats[1].0 >= 4);
+    }
+
+    #[tokio::test]
+    async fn test_pool_all_failed() {
+        let a = TestResolver::default();
+        a.dead.store(true, Ordering::SeqCst);
+        let b = TestResolver::default();
+        b.dead.store(true, Ordering::SeqCst);
+        let b_dead = b.dead.clone();
+        let mut pool = ResolverPool::new();
+        pool.add("a", a);
+        pool.add("b", b);
+
+        for _ in 0..4 {
+            assert!(!exchange(&mut pool).await);
+        }
+        // Failures alternate between the resolvers.
+        assert_eq!(exchanges(&pool), vec![(0, 2), (0, 2)]);
+        b_dead.store(false, Ordering::SeqCst);
+        assert!(!exchange(&mut pool).await);
+        assert!(exchange(&mut pool).await);
+        assert_eq!(pool.stats().nth(1).unwrap().failures, 0);
+    }
+
+    #[test]
+    fn test_pool_round_trip_time() {
+        let sim = Simulation::new();
+        let clock = sim.clock();
+        let mut pool = ResolverPool::new().clock(clock.clone());
+        pool.add("a", TestResolver::default());
+        pool.add("b", TestResolver::default());
+
+        sim.run(async {
+            for rtt in [100, 10, 0] {
+                send(&mut pool, ping(2)).await;
+                clock.delay(Duration::from_millis(rtt)).await;
+                recv(&mut pool).await.unwrap();
+            }
+        });
+        // The untried `b` is tried second, then preferred as it is faster.
+        let rtts = pool.stats().map(|stats| stats.rtt).collect::<Vec<_>>();
+        assert_eq!(
+            rtts,
+            vec![
+                Some(Duration::from_millis(100)),
+                Some(Duration::from_millis(10) * 7 / 8)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pool_hedged() {
+        let a = TestResolver::default();
+        let a_dead = a.dead.clone();
+        let mut pool = ResolverPool::new().fan_out(2);
+        pool.add("a", a);
+        pool.add("b", TestResolver::default());
+
+        assert!(exchange(&mut pool).await);
+        assert!(exchange(&mut pool).await);
+        // Both replied to each, with the duplicates dropped.
+        assert!(is_pending(&mut pool).await);
+        assert_eq!(exchanges(&pool), vec![(2, 0), (2, 0)]);
+
+        // A failure is hidden by the other reply.
+        a_dead.store(true, Ordering::SeqCst);
+        assert!(exchange(&mut pool).await);
+        assert!(exchange(&mut pool).await);
+        assert_eq!(exchanges(&pool), vec![(2, 2), (4, 0)]);
+    }
+
+    #[tokio::test]
+    async fn test_pool_slow_resolver() {
+        let a = TestResolver::default();
+        let a_paused = a.paused.clone();
+        let b = TestResolver::default();
+        let b_paused = b.paused.clone();
+        let mut pool = ResolverPool::new().fan_out(2);
+        pool.add("a", a);
+        pool.add("b", b);
+
+        // `b` answers the first request while `a` is slow.
+        a_paused.store(true, Ordering::SeqCst);
+        send(&mut pool, ping(1)).await;
+        assert_eq!(recv(&mut pool).await.unwrap(), ping(1));
+
+        // The late reply from `a` isn't taken as the second.
+        b_paused.store(true, Ordering::SeqCst);
+        a_paused.store(false, Ordering::SeqCst);
+        send(&mut pool, ping(2)).await;
+        assert_eq!(recv(&mut pool).await.unwrap(), ping(2));
+
+        b_paused.store(false, Ordering::SeqCst);
+        assert!(is_pending(&mut pool).await);
+        assert_eq!(exchanges(&pool), vec![(2, 0), (2, 0)]);
+    }
+}
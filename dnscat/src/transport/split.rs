@@ -390,11 +390,13 @@ pub trait SplitDatagramBlock {
 
 impl SplitDatagramBlock for Ipv4Addr {
     fn new_head(seq: u8, len: u8, data: &[u8]) -> Self {
-        Self::new(seq, len, data[0], data[1])
+        let mut next = block_data_iter(data);
+        Self::new(seq, len, next(), next())
     }
 
     fn new_tail(seq: u8, data: &[u8]) -> Self {
-        Self::new(seq, data[0], data[1], data[2])
+        let mut next = block_data_iter(data);
+        Self::new(seq, next(), next(), next())
     }
 
     fn len(&self) -> usize {
@@ -566,6 +568,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_split_datagram_from_data_ipv4_padded() {
+        let data = b"hi!";
+        let datagram: SplitDatagram<Ipv4Addr> = SplitDatagram::from_data(data, 4, 0);
+        assert_eq!(
+            datagram.into_blocks(),
+            vec![
+                Ipv4Addr::new(0, 3, b'h', b'i'),
+                Ipv4Addr::new(1, b'!', 0, 0)
+            ]
+        );
+    }
+
     #[test]
     fn test_split_datagram_from_data_ipv6() {
         let data = &[0b0000_0001, 0b0000_0010];
@@ -40,6 +40,12 @@ pub fn be_u16(bytes: &mut Bytes) -> Result<u16, Needed> {
     Ok(bytes.get_u16())
 }
 
+#[inline]
+pub fn be_u32(bytes: &mut Bytes) -> Result<u32, Needed> {
+    require_size_of::<u32>(bytes)?;
+    Ok(bytes.get_u32())
+}
+
 pub fn nt_string<E>(bytes: &mut Bytes) -> Result<StringBytes, E>
 where
     E: From<NoNullTermError>,